@@ -1,6 +1,27 @@
-use crate::files;
+use std::io;
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+use crate::{error::AppError, files};
+
+/// 预设的路由模式。
+/// the preset routing modes.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingMode {
+    /// 全部流量走代理，忽略路由规则
+    /// all traffic goes through the proxy, ignoring the routing rules
+    Global,
+    /// 按路由规则分流，未命中的流量走代理
+    /// traffic is split by the routing rules, traffic matching none goes through the proxy
+    Rule,
+    /// 全部流量直连
+    /// all traffic goes direct
+    Direct,
+    /// 局域网与中国大陆的流量直连，其余按路由规则分流
+    /// LAN and mainland China traffic goes direct, the rest is split by the routing rules
+    BypassChina,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct AppConfig {
     pub dns: Vec<String>,
     pub socks_port: i32,
@@ -10,15 +31,73 @@ pub struct AppConfig {
     pub tcp_fast_open: bool,
     pub http_status: bool,
     pub sock5_status: bool,
+    /// 真实延迟测试访问的地址
+    /// the url fetched by the real delay test
+    #[serde(default = "default_probe_url")]
+    pub probe_url: String,
+    /// 真实延迟测试同时运行的临时核心数量
+    /// the number of temporary cores running at the same time during the real delay test
+    #[serde(default = "default_probe_concurrency")]
+    pub probe_concurrency: usize,
+    /// 真实延迟测试中启动核心与访问测试地址各自的超时时间（秒）
+    /// the timeout in seconds of starting the core and of fetching the probe url, each, in the real delay test
+    #[serde(default = "default_timeout")]
+    pub probe_timeout: u64,
+    /// 测速下载的地址
+    /// the url downloaded by the speed test
+    #[serde(default = "default_speed_test_url")]
+    pub speed_test_url: String,
+    /// 同时测速的代理数量
+    /// the number of proxies whose speed is tested at the same time
+    #[serde(default = "default_speed_test_concurrency")]
+    pub speed_test_concurrency: usize,
+    /// 测速中启动核心与建立下载连接的超时时间（秒）
+    /// the timeout in seconds of starting the core and of opening the download in the speed test
+    #[serde(default = "default_timeout")]
+    pub speed_test_timeout: u64,
+    /// 核心 API 监听的本地端口，被占用时自动选择其他端口
+    /// the local port of the core API, another one is chosen automatically when it is in use
+    #[serde(default = "default_api_port")]
+    pub api_port: i32,
+    /// 核心可执行文件的路径，为空时自动查找
+    /// the path of the core executable, looked up automatically when empty
+    #[serde(default)]
+    pub core_path: String,
+    /// 路由模式
+    /// the routing mode
+    #[serde(default = "default_routing_mode")]
+    pub routing_mode: RoutingMode,
+}
+
+fn default_probe_url() -> String {
+    "https://www.gstatic.com/generate_204".to_string()
+}
+
+fn default_probe_concurrency() -> usize {
+    4
+}
+
+fn default_timeout() -> u64 {
+    5
 }
 
-pub fn init_config() {
-    let data = serde_json::to_string_pretty(&get_default_config()).unwrap();
+fn default_api_port() -> i32 {
+    10085
+}
+
+fn default_routing_mode() -> RoutingMode {
+    RoutingMode::Rule
+}
 
-    files::write("config.json", data.as_str()).unwrap();
+fn default_speed_test_url() -> String {
+    "https://speed.cloudflare.com/__down?bytes=104857600".to_string()
 }
 
-fn get_default_config() -> AppConfig {
+fn default_speed_test_concurrency() -> usize {
+    2
+}
+
+pub fn get_default_config() -> AppConfig {
     AppConfig {
         dns: vec![
             "1.1.1.1".to_string(),
@@ -32,14 +111,77 @@ fn get_default_config() -> AppConfig {
         tcp_fast_open: false,
         http_status: true,
         sock5_status: true,
+        probe_url: default_probe_url(),
+        probe_concurrency: default_probe_concurrency(),
+        probe_timeout: default_timeout(),
+        speed_test_url: default_speed_test_url(),
+        speed_test_concurrency: default_speed_test_concurrency(),
+        speed_test_timeout: default_timeout(),
+        api_port: default_api_port(),
+        core_path: String::new(),
+        routing_mode: default_routing_mode(),
+    }
+}
+
+/// 读取配置，配置文件不存在时写入并返回默认配置。
+/// read the config, writing and returning the default one when the config file does not exist.
+pub fn read() -> Result<AppConfig, AppError> {
+    match files::read("config.json") {
+        Ok(i) => Ok(serde_json::from_str::<AppConfig>(&i)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let config = get_default_config();
+            save(&config)?;
+            Ok(config)
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
-pub fn read() -> AppConfig {
-    let raw_config = files::read("~/.config/v2neko/config.json");
-    if raw_config.is_err() {
-        init_config();
-        return get_default_config();
+/// 保存配置，之后生成的核心配置会使用它。
+/// save the config, the core configs generated afterwards use it.
+pub fn save(config: &AppConfig) -> Result<(), AppError> {
+    let data = serde_json::to_string_pretty(config)?;
+    files::write_atomic(&files::get_path("config.json")?, &data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_mode() {
+        // 旧的配置文件没有路由模式，按规则分流。
+        // old config files have no routing mode and split traffic by rules.
+        let mut value = serde_json::to_value(get_default_config()).unwrap();
+        value.as_object_mut().unwrap().remove("routing_mode");
+        let config = serde_json::from_value::<AppConfig>(value).unwrap();
+        assert_eq!(config.routing_mode, RoutingMode::Rule);
+
+        assert_eq!(
+            serde_json::to_value(RoutingMode::BypassChina).unwrap(),
+            "bypass_china"
+        );
+    }
+
+    #[test]
+    fn test_test_options() {
+        // 旧的配置文件没有测试的并发与超时，使用默认值。
+        // old config files have no concurrency or timeout for the tests and use the defaults.
+        let mut value = serde_json::to_value(get_default_config()).unwrap();
+        let object = value.as_object_mut().unwrap();
+        for i in [
+            "probe_concurrency",
+            "probe_timeout",
+            "speed_test_concurrency",
+            "speed_test_timeout",
+        ] {
+            object.remove(i);
+        }
+        let config = serde_json::from_value::<AppConfig>(value).unwrap();
+        assert_eq!(config.probe_concurrency, 4);
+        assert_eq!(config.probe_timeout, 5);
+        assert_eq!(config.speed_test_concurrency, 2);
+        assert_eq!(config.speed_test_timeout, 5);
     }
-    serde_json::from_str::<AppConfig>(&raw_config.ok().unwrap()).unwrap()
 }
@@ -1,84 +1,328 @@
-use crate::proxy::Proxy;
-use directories::BaseDirs;
-use rusqlite::{params, Connection};
-use std::fs;
-
-/// 通过id读取代理。
-/// read proxy by id.
-pub fn get_proxy_by_id(conn: &Connection, proxy_id: &str) -> Proxy {
-    let mut stmt = conn
-        .prepare(r#"SELECT * FROM proxies where proxy_id=?"#)
-        .unwrap();
-    let mut proxy_iter = stmt
-        .query_map([proxy_id], |pair| {
-            Ok(Proxy {
-                proxy_id: pair.get(0)?,
-                proxy_name: pair.get(1)?,
-                proxy_type: pair.get(2)?,
-                proxy_upload: pair.get(3).unwrap_or(0),
-                proxy_download: pair.get(4).unwrap_or(0),
-                proxy_delay: pair.get(5).unwrap_or(-1),
-                proxy_config_path: pair.get(6)?,
-                proxy_group:pair.get(7)?
-            })
-        })
-        .unwrap();
-    proxy_iter.next().unwrap().unwrap()
-}
-
-/// 向数据库中加入代理。
-/// add a new proxy to the database.
-pub fn push_proxy(conn: &Connection, proxy: &Proxy) {
-    conn.execute("INSTER INTO proxies(proxy_id,proxy_name,proxy_type,proxy_upload,proxy_download,proxy_delay,proxy_config_path) 
-    values (?,?,?,?,?,?,?)",params![proxy.proxy_id,proxy.proxy_name,proxy.proxy_type,proxy.proxy_upload,proxy.proxy_delay,proxy.proxy_config_path]).unwrap();
-}
-
-/// 获取存储在数据库中的的代理列表。
-/// Get all proxies from the database.
-pub fn get_proxy_list(connection: &Connection) -> Vec<Proxy> {
-    let mut result = Vec::new();
-    let mut stmt = connection.prepare(r#"SELECT * FROM proxies"#).unwrap();
-    let proxy_iter = stmt
-        .query_map([], |pair| {
-            Ok(Proxy {
-                proxy_id: pair.get(0)?,
-                proxy_name: pair.get(1)?,
-                proxy_type: pair.get(2)?,
-                proxy_upload: pair.get(3).unwrap_or(0),
-                proxy_download: pair.get(4).unwrap_or(0),
-                proxy_delay: pair.get(5).unwrap_or(-1),
-                proxy_config_path: pair.get(6)?,
-                proxy_group:pair.get(7)?
-            })
-        })
-        .unwrap();
-    for i in proxy_iter {
-        let j = i.unwrap();
-        result.push(j)
+use crate::{
+    error::AppError,
+    files,
+    proxy::{Proxy, ProxyKey},
+    routing::{RoutingRule, RuleOutbound},
+    subscription::{Subscription, SubscriptionUserinfo},
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const PROXY_COLUMNS: &str = "proxy_id,proxy_name,proxy_type,proxy_upload,proxy_download,proxy_delay,proxy_config,proxy_group,proxy_sort,proxy_real_delay,proxy_speed_average,proxy_speed_peak";
+
+fn read_proxy(pair: &rusqlite::Row) -> rusqlite::Result<Proxy> {
+    Ok(Proxy {
+        proxy_id: pair.get(0)?,
+        proxy_name: pair.get(1)?,
+        proxy_type: pair.get(2)?,
+        proxy_upload: pair.get(3).unwrap_or(0),
+        proxy_download: pair.get(4).unwrap_or(0),
+        proxy_delay: pair.get(5).unwrap_or(-1),
+        proxy_config: pair.get(6)?,
+        proxy_group: pair.get(7)?,
+        proxy_sort: pair.get(8)?,
+        proxy_real_delay: pair.get(9).unwrap_or(-1),
+        proxy_speed_average: pair.get(10).unwrap_or(-1),
+        proxy_speed_peak: pair.get(11).unwrap_or(-1),
+    })
+}
+
+/// 通过id读取代理，不存在时返回 None。
+/// read proxy by id, returns None when it does not exist.
+pub fn get_proxy_by_id(conn: &Connection, proxy_id: &str) -> Result<Option<Proxy>, AppError> {
+    let proxy = conn
+        .query_row(
+            &format!("SELECT {} FROM proxies WHERE proxy_id=?", PROXY_COLUMNS),
+            [proxy_id],
+            read_proxy,
+        )
+        .optional()?;
+    Ok(proxy)
+}
+
+/// 向数据库中加入代理，新代理排在列表末尾。
+/// add a new proxy to the database, placed at the end of the list.
+pub fn push_proxy(conn: &Connection, proxy: &Proxy) -> Result<(), AppError> {
+    conn.execute("INSERT INTO proxies(proxy_id,proxy_name,proxy_type,proxy_upload,proxy_download,proxy_delay,proxy_config,proxy_group,proxy_sort) 
+    values (?,?,?,?,?,?,?,?,(SELECT IFNULL(MAX(proxy_sort),0)+1 FROM proxies))",params![proxy.proxy_id,proxy.proxy_name,proxy.proxy_type,proxy.proxy_upload,proxy.proxy_download,proxy.proxy_delay,proxy.proxy_config,proxy.proxy_group])?;
+    Ok(())
+}
+
+/// 用新的代理列表替换某个分组下的全部代理。按地址、端口与凭据与已有的代理对应，对应上的代理只更新名称与出站配置，
+/// 保留 id、排序、延迟、测速与流量统计；其余的新代理被加入，不再出现的代理被删除。
+/// replace every proxy of a group with the given proxies. They are matched to the existing proxies by address,
+/// port and credential; a matched proxy only has its name and outbound updated, keeping its id, sort order,
+/// delays, speed and traffic. The other new proxies are added and the ones no longer present are deleted.
+pub fn replace_group_proxies(
+    conn: &mut Connection,
+    proxy_group: &str,
+    proxies: &[Proxy],
+) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    let existing = {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT {} FROM proxies WHERE proxy_group=? ORDER BY proxy_sort",
+            PROXY_COLUMNS
+        ))?;
+        let rows = stmt.query_map([proxy_group], read_proxy)?;
+        rows.collect::<rusqlite::Result<Vec<Proxy>>>()?
+    };
+    // 同一个键可能对应多个已有的代理，按排序依次使用。
+    // one key may belong to several existing proxies, which are used in their sort order.
+    let mut by_key: HashMap<ProxyKey, VecDeque<String>> = HashMap::new();
+    for proxy in &existing {
+        if let Some(key) = proxy.key() {
+            by_key
+                .entry(key)
+                .or_default()
+                .push_back(proxy.proxy_id.clone());
+        }
     }
-    result
+    let mut kept = HashSet::new();
+    for proxy in proxies {
+        let matched = proxy
+            .key()
+            .and_then(|i| by_key.get_mut(&i))
+            .and_then(|i| i.pop_front());
+        match matched {
+            Some(proxy_id) => {
+                tx.execute(
+                    "UPDATE proxies SET proxy_name=?,proxy_type=?,proxy_config=? WHERE proxy_id=?",
+                    params![
+                        proxy.proxy_name,
+                        proxy.proxy_type,
+                        proxy.proxy_config,
+                        proxy_id
+                    ],
+                )?;
+                kept.insert(proxy_id);
+            }
+            None => push_proxy(&tx, proxy)?,
+        }
+    }
+    for proxy in existing {
+        if !kept.contains(&proxy.proxy_id) {
+            tx.execute("DELETE FROM proxies WHERE proxy_id=?", [proxy.proxy_id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
 }
 
-/// 获取数据库的连接。将会在数据库不存在是建立数据库
-/// get the connection from the database. Creates a new database when the database is not initialized.
-pub fn init_database() -> Connection {
-    let proj_dirs = BaseDirs::new().unwrap().config_dir().join("v2neko");
-    fs::create_dir_all(&proj_dirs).unwrap();
-    let mut conn = rusqlite::Connection::open(proj_dirs.join("proxyies.sqlite")).unwrap();
-    init_proxys(&mut conn);
-    conn
+/// 获取存储在数据库中的的代理列表，按手动排序排列。
+/// Get all proxies from the database, in their manual sort order.
+pub fn get_proxy_list(connection: &Connection) -> Result<Vec<Proxy>, AppError> {
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} FROM proxies ORDER BY proxy_sort",
+        PROXY_COLUMNS
+    ))?;
+    let proxy_iter = stmt.query_map([], read_proxy)?;
+    Ok(proxy_iter.collect::<rusqlite::Result<Vec<Proxy>>>()?)
 }
 
-/// 初始化数据库
-/// init the database.
-fn init_proxys(conn: &mut Connection) {
-    let mut stmt = conn
-        .prepare(r#"SELECT count(*) FROM sqlite_master WHERE type="table" AND name = "proxies""#)
-        .unwrap();
-    let proxy_iter = stmt.query_map([], |pair| {
-        if pair.get(0) == Ok(0) {
-            conn.execute(
-                "CREATE TABLE proxies(
+/// 按id更新代理的名称、类型、出站配置与分组。
+/// update the name, type, outbound config and group of a proxy by id.
+pub fn update_proxy(conn: &Connection, proxy: &Proxy) -> Result<(), AppError> {
+    let count = conn.execute(
+        "UPDATE proxies SET proxy_name=?,proxy_type=?,proxy_config=?,proxy_group=? WHERE proxy_id=?",
+        params![
+            proxy.proxy_name,
+            proxy.proxy_type,
+            proxy.proxy_config,
+            proxy.proxy_group,
+            proxy.proxy_id
+        ],
+    )?;
+    if count == 0 {
+        return Err(AppError::proxy_not_found(&proxy.proxy_id));
+    }
+    Ok(())
+}
+
+/// 在一个事务中删除多个代理，任意一个不存在时全部回滚。
+/// delete several proxies in one transaction, rolling back all of them when any does not exist.
+pub fn delete_proxies(conn: &mut Connection, proxy_ids: &[String]) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    for proxy_id in proxy_ids {
+        if tx.execute("DELETE FROM proxies WHERE proxy_id=?", [proxy_id])? == 0 {
+            return Err(AppError::proxy_not_found(proxy_id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// 复制一个代理，副本紧跟在原代理之后，流量与延迟重新统计。
+/// duplicate a proxy right after the original one, with traffic and delay reset.
+pub fn duplicate_proxy(conn: &mut Connection, proxy_id: &str) -> Result<Proxy, AppError> {
+    let proxy =
+        get_proxy_by_id(conn, proxy_id)?.ok_or_else(|| AppError::proxy_not_found(proxy_id))?;
+    let copy = Proxy {
+        proxy_id: uuid::Uuid::new_v4().to_string(),
+        proxy_name: format!("{} - copy", proxy.proxy_name),
+        proxy_upload: 0,
+        proxy_download: 0,
+        proxy_delay: -1,
+        proxy_real_delay: -1,
+        proxy_speed_average: -1,
+        proxy_speed_peak: -1,
+        proxy_sort: proxy.proxy_sort + 1,
+        ..proxy
+    };
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE proxies SET proxy_sort=proxy_sort+1 WHERE proxy_sort>?",
+        [proxy.proxy_sort],
+    )?;
+    tx.execute("INSERT INTO proxies(proxy_id,proxy_name,proxy_type,proxy_upload,proxy_download,proxy_delay,proxy_config,proxy_group,proxy_sort) 
+    values (?,?,?,?,?,?,?,?,?)",params![copy.proxy_id,copy.proxy_name,copy.proxy_type,copy.proxy_upload,copy.proxy_download,copy.proxy_delay,copy.proxy_config,copy.proxy_group,copy.proxy_sort])?;
+    tx.commit()?;
+    Ok(copy)
+}
+
+/// 按给定的id顺序保存代理的手动排序，未列出的代理排在其后并保持原有顺序。
+/// save the manual sort order following the given ids, unlisted proxies keep their relative order after them.
+pub fn reorder_proxies(conn: &mut Connection, proxy_ids: &[String]) -> Result<(), AppError> {
+    let mut ordered: Vec<String> = proxy_ids.to_vec();
+    for proxy in get_proxy_list(conn)? {
+        if !ordered.contains(&proxy.proxy_id) {
+            ordered.push(proxy.proxy_id);
+        }
+    }
+    let tx = conn.transaction()?;
+    for (i, proxy_id) in ordered.iter().enumerate() {
+        if tx.execute(
+            "UPDATE proxies SET proxy_sort=? WHERE proxy_id=?",
+            params![i as i64 + 1, proxy_id],
+        )? == 0
+        {
+            return Err(AppError::proxy_not_found(proxy_id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// 保存代理的延迟（毫秒），-1 表示测试失败。
+/// save the delay of a proxy in milliseconds, -1 means the test failed.
+pub fn set_proxy_delay(
+    conn: &Connection,
+    proxy_id: &str,
+    proxy_delay: i32,
+) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE proxies SET proxy_delay=? WHERE proxy_id=?",
+        params![proxy_delay, proxy_id],
+    )?;
+    Ok(())
+}
+
+/// 保存代理的真实延迟（毫秒），-1 表示测试失败。
+/// save the real delay of a proxy in milliseconds, -1 means the test failed.
+pub fn set_proxy_real_delay(
+    conn: &Connection,
+    proxy_id: &str,
+    proxy_real_delay: i32,
+) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE proxies SET proxy_real_delay=? WHERE proxy_id=?",
+        params![proxy_real_delay, proxy_id],
+    )?;
+    Ok(())
+}
+
+/// 保存代理测速的平均与峰值速度（字节每秒），-1 表示测试失败。
+/// save the average and peak speed of a proxy in bytes per second, -1 means the test failed.
+pub fn set_proxy_speed(
+    conn: &Connection,
+    proxy_id: &str,
+    average: i64,
+    peak: i64,
+) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE proxies SET proxy_speed_average=?,proxy_speed_peak=? WHERE proxy_id=?",
+        params![average, peak, proxy_id],
+    )?;
+    Ok(())
+}
+
+/// 累加代理的上传与下载流量（字节）。
+/// add to the upload and download traffic of a proxy in bytes.
+pub fn add_proxy_traffic(
+    conn: &Connection,
+    proxy_id: &str,
+    upload: i64,
+    download: i64,
+) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE proxies SET proxy_upload=IFNULL(proxy_upload,0)+?,proxy_download=IFNULL(proxy_download,0)+? WHERE proxy_id=?",
+        params![upload, download, proxy_id],
+    )?;
+    Ok(())
+}
+
+/// 获取数据库的连接。将会在数据库不存在是建立数据库，并迁移到最新的结构。只在启动时调用一次，连接由 `AppState` 管理
+/// get the connection from the database. Creates a new database when the database is not initialized and migrates it to the latest schema.
+/// Only called once at startup, the connection is managed by `AppState`.
+pub fn init_database() -> Result<Connection, AppError> {
+    let mut conn = rusqlite::Connection::open(files::get_path("proxyies.sqlite")?)?;
+    migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// 按顺序排列的迁移步骤，第 n 步完成后 `user_version` 为 n。只能在末尾追加新的步骤。
+/// the ordered migration steps, `user_version` is n after the n-th step. New steps may only be appended.
+const MIGRATIONS: [fn(&Connection) -> rusqlite::Result<()>; 6] = [
+    migrate_proxies,
+    migrate_subscriptions,
+    migrate_proxy_sort,
+    migrate_proxy_real_delay,
+    migrate_proxy_speed,
+    migrate_routing_rules,
+];
+
+/// 数据库结构的最新版本。
+/// the latest version of the database schema.
+const DATABASE_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// 读取数据库的结构版本。
+/// read the schema version of the database.
+pub fn get_database_version(conn: &Connection) -> Result<i32, AppError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |pair| pair.get(0))?)
+}
+
+/// 将数据库从 `user_version` 记录的版本逐步迁移到最新版本，每一步都在事务中完成。
+/// migrate the database step by step from the version recorded in `user_version` to the latest one, each step in its own transaction.
+pub fn migrate(conn: &mut Connection) -> Result<(), AppError> {
+    let version = get_database_version(conn)?;
+    if version > DATABASE_VERSION {
+        return Err(AppError::Database(format!(
+            "The database version {} is newer than the supported version {}",
+            version, DATABASE_VERSION
+        )));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version.max(0) as usize) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", i as i32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn get_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let column_iter = stmt.query_map([], |pair| pair.get(1))?;
+    column_iter.collect()
+}
+
+/// 版本 1：建立代理表。旧版本建立的表可能使用 `proxy_config_path` 列名或缺少 `proxy_group` 列。
+/// version 1: create the proxies table. Tables from older builds may name the column `proxy_config_path` or lack `proxy_group`.
+fn migrate_proxies(conn: &Connection) -> rusqlite::Result<()> {
+    let columns = get_columns(conn, "proxies")?;
+    if columns.is_empty() {
+        conn.execute(
+            "CREATE TABLE proxies(
                 proxy_id varchar(36) PRIMARY KEY NOT NULL,
                 proxy_name varchar(255) NOT NULL,
                 proxy_type varchar(255) NOT NULL,
@@ -86,13 +330,668 @@ fn init_proxys(conn: &mut Connection) {
                 proxy_download int,
                 proxy_delay int,
                 proxy_config varchar(65535) NOT NULL,
-                proxy_group varchar(255),
+                proxy_group varchar(255) NOT NULL DEFAULT 'default'
             )",
+            [],
+        )?;
+        return Ok(());
+    }
+    if columns.iter().any(|i| i == "proxy_config_path") {
+        conn.execute(
+            "ALTER TABLE proxies RENAME COLUMN proxy_config_path TO proxy_config",
+            [],
+        )?;
+    }
+    if !columns.iter().any(|i| i == "proxy_group") {
+        conn.execute(
+            "ALTER TABLE proxies ADD COLUMN proxy_group varchar(255) NOT NULL DEFAULT 'default'",
+            [],
+        )?;
+    }
+    conn.execute(
+        "UPDATE proxies SET proxy_group='default' WHERE proxy_group IS NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+/// 版本 2：建立订阅表与订阅流量信息表。
+/// version 2: create the subscriptions and subscription userinfo tables.
+fn migrate_subscriptions(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS subscriptions(
+        subscription_id varchar(36) PRIMARY KEY NOT NULL,
+        subscription_name varchar(255) NOT NULL,
+        subscription_url varchar(65535) NOT NULL,
+        subscription_user_agent varchar(255) NOT NULL,
+        subscription_update_interval int NOT NULL,
+        subscription_last_update int NOT NULL
+    )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS subscription_userinfo(
+        subscription_id varchar(36) PRIMARY KEY NOT NULL,
+        upload int NOT NULL,
+        download int NOT NULL,
+        total int NOT NULL,
+        expire int NOT NULL,
+        update_time int NOT NULL
+    )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// 版本 3：为代理加入手动排序，已有代理按插入顺序排列。
+/// version 3: add the manual sort order of proxies, existing proxies keep their insertion order.
+fn migrate_proxy_sort(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "ALTER TABLE proxies ADD COLUMN proxy_sort int NOT NULL DEFAULT 0",
+        [],
+    )?;
+    conn.execute("UPDATE proxies SET proxy_sort=rowid", [])?;
+    Ok(())
+}
+
+/// 版本 4：加入真实延迟，保存在 TCP 延迟旁边。
+/// version 4: add the real delay, stored next to the TCP delay.
+fn migrate_proxy_real_delay(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "ALTER TABLE proxies ADD COLUMN proxy_real_delay int NOT NULL DEFAULT -1",
+        [],
+    )?;
+    Ok(())
+}
+
+/// 版本 5：加入测速的平均与峰值速度。
+/// version 5: add the average and peak speed of the speed test.
+fn migrate_proxy_speed(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "ALTER TABLE proxies ADD COLUMN proxy_speed_average int NOT NULL DEFAULT -1",
+        [],
+    )?;
+    conn.execute(
+        "ALTER TABLE proxies ADD COLUMN proxy_speed_peak int NOT NULL DEFAULT -1",
+        [],
+    )?;
+    Ok(())
+}
+
+/// 版本 6：建立路由规则表，匹配条件以 JSON 保存。
+/// version 6: create the routing rules table, with the conditions stored as JSON.
+fn migrate_routing_rules(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS routing_rules(
+        rule_id varchar(36) PRIMARY KEY NOT NULL,
+        rule_name varchar(255) NOT NULL,
+        rule_outbound varchar(16) NOT NULL,
+        rule_priority int NOT NULL,
+        rule_enabled int NOT NULL DEFAULT 1,
+        rule_match varchar(65535) NOT NULL
+    )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn read_routing_rule(pair: &rusqlite::Row) -> rusqlite::Result<RoutingRule> {
+    let rule_outbound: String = pair.get(2)?;
+    let rule_match: String = pair.get(5)?;
+    Ok(RoutingRule {
+        rule_id: pair.get(0)?,
+        rule_name: pair.get(1)?,
+        rule_outbound: RuleOutbound::parse(&rule_outbound).unwrap_or(RuleOutbound::Proxy),
+        rule_priority: pair.get(3)?,
+        rule_enabled: pair.get(4)?,
+        rule_match: serde_json::from_str(&rule_match).unwrap_or_default(),
+    })
+}
+
+/// 获取全部路由规则，按优先级排列。
+/// Get all routing rules, in priority order.
+pub fn get_routing_rules(conn: &Connection) -> Result<Vec<RoutingRule>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT rule_id,rule_name,rule_outbound,rule_priority,rule_enabled,rule_match
+        FROM routing_rules ORDER BY rule_priority",
+    )?;
+    let rule_iter = stmt.query_map([], read_routing_rule)?;
+    Ok(rule_iter.collect::<rusqlite::Result<Vec<RoutingRule>>>()?)
+}
+
+/// 加入路由规则，新规则的优先级最低。
+/// add a routing rule, the new rule has the lowest priority.
+pub fn push_routing_rule(conn: &Connection, rule: &RoutingRule) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO routing_rules(rule_id,rule_name,rule_outbound,rule_priority,rule_enabled,rule_match)
+    values (?,?,?,(SELECT IFNULL(MAX(rule_priority),0)+1 FROM routing_rules),?,?)",
+        params![
+            rule.rule_id,
+            rule.rule_name,
+            rule.rule_outbound.as_str(),
+            rule.rule_enabled,
+            serde_json::to_string(&rule.rule_match)?
+        ],
+    )?;
+    Ok(())
+}
+
+/// 按id更新路由规则的名称、出站、启用状态与匹配条件，优先级不变。
+/// update the name, outbound, enabled state and conditions of a routing rule by id, keeping its priority.
+pub fn update_routing_rule(conn: &Connection, rule: &RoutingRule) -> Result<(), AppError> {
+    let count = conn.execute(
+        "UPDATE routing_rules SET rule_name=?,rule_outbound=?,rule_enabled=?,rule_match=? WHERE rule_id=?",
+        params![
+            rule.rule_name,
+            rule.rule_outbound.as_str(),
+            rule.rule_enabled,
+            serde_json::to_string(&rule.rule_match)?,
+            rule.rule_id
+        ],
+    )?;
+    if count == 0 {
+        return Err(AppError::rule_not_found(&rule.rule_id));
+    }
+    Ok(())
+}
+
+/// 在一个事务中删除多条路由规则，任意一条不存在时全部回滚。
+/// delete several routing rules in one transaction, rolling back all of them when any does not exist.
+pub fn delete_routing_rules(conn: &mut Connection, rule_ids: &[String]) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    for rule_id in rule_ids {
+        if tx.execute("DELETE FROM routing_rules WHERE rule_id=?", [rule_id])? == 0 {
+            return Err(AppError::rule_not_found(rule_id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// 按给定的id顺序保存路由规则的优先级，未列出的规则排在其后并保持原有顺序。
+/// save the rule priorities following the given ids, unlisted rules keep their relative order after them.
+pub fn reorder_routing_rules(conn: &mut Connection, rule_ids: &[String]) -> Result<(), AppError> {
+    let mut ordered: Vec<String> = rule_ids.to_vec();
+    for rule in get_routing_rules(conn)? {
+        if !ordered.contains(&rule.rule_id) {
+            ordered.push(rule.rule_id);
+        }
+    }
+    let tx = conn.transaction()?;
+    for (i, rule_id) in ordered.iter().enumerate() {
+        if tx.execute(
+            "UPDATE routing_rules SET rule_priority=? WHERE rule_id=?",
+            params![i as i64 + 1, rule_id],
+        )? == 0
+        {
+            return Err(AppError::rule_not_found(rule_id));
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// 获取存储在数据库中的订阅列表。
+/// Get all subscriptions from the database.
+pub fn get_subscription_list(conn: &Connection) -> Result<Vec<Subscription>, AppError> {
+    let mut stmt = conn.prepare(r#"SELECT * FROM subscriptions"#)?;
+    let subscription_iter = stmt.query_map([], read_subscription)?;
+    Ok(subscription_iter.collect::<rusqlite::Result<Vec<Subscription>>>()?)
+}
+
+/// 通过id读取订阅，不存在时返回 None。
+/// read subscription by id, returns None when it does not exist.
+pub fn get_subscription_by_id(
+    conn: &Connection,
+    subscription_id: &str,
+) -> Result<Option<Subscription>, AppError> {
+    let subscription = conn
+        .query_row(
+            r#"SELECT * FROM subscriptions WHERE subscription_id=?"#,
+            [subscription_id],
+            read_subscription,
+        )
+        .optional()?;
+    Ok(subscription)
+}
+
+fn read_subscription(pair: &rusqlite::Row) -> rusqlite::Result<Subscription> {
+    Ok(Subscription {
+        subscription_id: pair.get(0)?,
+        subscription_name: pair.get(1)?,
+        subscription_url: pair.get(2)?,
+        subscription_user_agent: pair.get(3)?,
+        subscription_update_interval: pair.get(4)?,
+        subscription_last_update: pair.get(5)?,
+    })
+}
+
+/// 向数据库中加入订阅。
+/// add a new subscription to the database.
+pub fn push_subscription(conn: &Connection, subscription: &Subscription) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO subscriptions(subscription_id,subscription_name,subscription_url,subscription_user_agent,subscription_update_interval,subscription_last_update)
+    values (?,?,?,?,?,?)",
+        params![
+            subscription.subscription_id,
+            subscription.subscription_name,
+            subscription.subscription_url,
+            subscription.subscription_user_agent,
+            subscription.subscription_update_interval,
+            subscription.subscription_last_update
+        ],
+    )?;
+    Ok(())
+}
+
+/// 删除订阅以及其分组下的全部代理，订阅不存在时返回错误。
+/// delete a subscription together with every proxy of its group, returning an error when it does not exist.
+pub fn delete_subscription(conn: &mut Connection, subscription_id: &str) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    if tx.execute(
+        "DELETE FROM subscriptions WHERE subscription_id=?",
+        [subscription_id],
+    )? == 0
+    {
+        return Err(AppError::subscription_not_found(subscription_id));
+    }
+    tx.execute(
+        "DELETE FROM subscription_userinfo WHERE subscription_id=?",
+        [subscription_id],
+    )?;
+    tx.execute("DELETE FROM proxies WHERE proxy_group=?", [subscription_id])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// 记录订阅的最后更新时间（unix 秒）。
+/// record the last update time (unix seconds) of a subscription.
+pub fn set_subscription_last_update(
+    conn: &Connection,
+    subscription_id: &str,
+    last_update: i64,
+) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE subscriptions SET subscription_last_update=? WHERE subscription_id=?",
+        params![last_update, subscription_id],
+    )?;
+    Ok(())
+}
+
+/// 保存订阅的流量与到期信息，覆盖旧的记录。
+/// save the quota and expiry of a subscription, replacing the previous record.
+pub fn set_subscription_userinfo(
+    conn: &Connection,
+    userinfo: &SubscriptionUserinfo,
+) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO subscription_userinfo(subscription_id,upload,download,total,expire,update_time)
+    values (?,?,?,?,?,?)",
+        params![
+            userinfo.subscription_id,
+            userinfo.upload,
+            userinfo.download,
+            userinfo.total,
+            userinfo.expire,
+            userinfo.update_time
+        ],
+    )?;
+    Ok(())
+}
+
+/// 获取所有订阅的流量与到期信息。
+/// Get the quota and expiry of every subscription.
+pub fn get_subscription_userinfo_list(
+    conn: &Connection,
+) -> Result<Vec<SubscriptionUserinfo>, AppError> {
+    let mut stmt = conn.prepare(r#"SELECT * FROM subscription_userinfo"#)?;
+    let userinfo_iter = stmt.query_map([], |pair| {
+        Ok(SubscriptionUserinfo {
+            subscription_id: pair.get(0)?,
+            upload: pair.get(1)?,
+            download: pair.get(2)?,
+            total: pair.get(3)?,
+            expire: pair.get(4)?,
+            update_time: pair.get(5)?,
+        })
+    })?;
+    Ok(userinfo_iter.collect::<rusqlite::Result<Vec<SubscriptionUserinfo>>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_proxy(name: &str, group: &str) -> Proxy {
+        Proxy {
+            proxy_id: uuid::Uuid::new_v4().to_string(),
+            proxy_name: name.to_string(),
+            proxy_type: "trojan".to_string(),
+            proxy_upload: 0,
+            proxy_download: 0,
+            proxy_config: "{}".to_string(),
+            proxy_delay: -1,
+            proxy_group: group.to_string(),
+            proxy_sort: 0,
+            proxy_real_delay: -1,
+            proxy_speed_average: -1,
+            proxy_speed_peak: -1,
+        }
+    }
+
+    /// 每个历史版本的数据库，包括修复前从未成功建表的空库与旧的代理表结构。
+    /// a database for every historical version, including the empty one left by the broken DDL and the old proxies layout.
+    fn get_historical_databases() -> Vec<Connection> {
+        let empty = Connection::open_in_memory().unwrap();
+
+        let legacy = Connection::open_in_memory().unwrap();
+        legacy
+            .execute_batch(
+                "CREATE TABLE proxies(
+                proxy_id varchar(36) PRIMARY KEY NOT NULL,
+                proxy_name varchar(255) NOT NULL,
+                proxy_type varchar(255) NOT NULL,
+                proxy_upload int,
+                proxy_download int,
+                proxy_delay int,
+                proxy_config_path varchar(65535) NOT NULL
+            );
+            INSERT INTO proxies VALUES ('legacy', 'legacy', 'vmess', 1, 2, 3, '~/.config/v2neko/connections/');",
+            )
+            .unwrap();
+
+        let unversioned = Connection::open_in_memory().unwrap();
+        unversioned
+            .execute_batch(
+                "CREATE TABLE proxies(
+                proxy_id varchar(36) PRIMARY KEY NOT NULL,
+                proxy_name varchar(255) NOT NULL,
+                proxy_type varchar(255) NOT NULL,
+                proxy_upload int,
+                proxy_download int,
+                proxy_delay int,
+                proxy_config varchar(65535) NOT NULL,
+                proxy_group varchar(255)
+            );
+            INSERT INTO proxies VALUES ('legacy', 'legacy', 'vmess', 1, 2, 3, '{}', NULL);
+            CREATE TABLE subscriptions(
+                subscription_id varchar(36) PRIMARY KEY NOT NULL,
+                subscription_name varchar(255) NOT NULL,
+                subscription_url varchar(65535) NOT NULL,
+                subscription_user_agent varchar(255) NOT NULL,
+                subscription_update_interval int NOT NULL,
+                subscription_last_update int NOT NULL
+            );",
+            )
+            .unwrap();
+
+        let mut result = vec![empty, legacy, unversioned];
+        for version in 1..=DATABASE_VERSION {
+            let conn = Connection::open_in_memory().unwrap();
+            for (i, migration) in MIGRATIONS.iter().enumerate().take(version as usize) {
+                migration(&conn).unwrap();
+                conn.pragma_update(None, "user_version", i as i32 + 1)
+                    .unwrap();
+            }
+            conn.execute(
+                "INSERT INTO proxies(proxy_id,proxy_name,proxy_type,proxy_upload,proxy_download,proxy_delay,proxy_config,proxy_group)
+                VALUES ('legacy', 'legacy', 'vmess', 1, 2, 3, '{}', 'default')",
                 [],
             )
             .unwrap();
+            result.push(conn);
+        }
+        result
+    }
+
+    #[test]
+    fn test_migrate() {
+        for mut conn in get_historical_databases() {
+            migrate(&mut conn).unwrap();
+            assert_eq!(get_database_version(&conn).unwrap(), DATABASE_VERSION);
+            // 再次迁移不应有任何变化。
+            // migrating again must be a no-op.
+            migrate(&mut conn).unwrap();
+            assert_eq!(get_database_version(&conn).unwrap(), DATABASE_VERSION);
+
+            let proxies = get_proxy_list(&conn).unwrap();
+            assert!(proxies.len() <= 1);
+            for proxy in proxies {
+                assert_eq!(proxy.proxy_name, "legacy");
+                assert_eq!(proxy.proxy_group, "default");
+            }
+            assert!(get_subscription_list(&conn).unwrap().is_empty());
+            assert!(get_subscription_userinfo_list(&conn).unwrap().is_empty());
+            assert!(get_routing_rules(&conn).unwrap().is_empty());
+        }
+
+        // 更新版本的数据库返回错误而不是 panic。
+        // a database from a newer version is an error instead of a panic.
+        let mut newer = Connection::open_in_memory().unwrap();
+        newer
+            .pragma_update(None, "user_version", DATABASE_VERSION + 1)
+            .unwrap();
+        assert!(matches!(migrate(&mut newer), Err(AppError::Database(_))));
+    }
+
+    #[test]
+    fn test_proxy_crud() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let proxy = get_proxy("a", "default");
+        push_proxy(&conn, &proxy).unwrap();
+        let stored = get_proxy_by_id(&conn, &proxy.proxy_id).unwrap().unwrap();
+        assert_eq!(stored.proxy_name, "a");
+        assert_eq!(stored.proxy_config, "{}");
+        assert_eq!(stored.proxy_delay, -1);
+
+        push_subscription(
+            &conn,
+            &Subscription {
+                subscription_id: "sub".to_string(),
+                subscription_name: "sub".to_string(),
+                subscription_url: "https://example.com/sub".to_string(),
+                subscription_user_agent: "v2neko".to_string(),
+                subscription_update_interval: 0,
+                subscription_last_update: 0,
+            },
+        )
+        .unwrap();
+        replace_group_proxies(
+            &mut conn,
+            "sub",
+            &[get_proxy("b", "sub"), get_proxy("c", "sub")],
+        )
+        .unwrap();
+        replace_group_proxies(&mut conn, "sub", &[get_proxy("d", "sub")]).unwrap();
+        let mut names: Vec<String> = get_proxy_list(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.proxy_name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a", "d"]);
+
+        delete_subscription(&mut conn, "sub").unwrap();
+        assert_eq!(get_proxy_list(&conn).unwrap().len(), 1);
+        assert_eq!(
+            delete_subscription(&mut conn, "sub"),
+            Err(AppError::subscription_not_found("sub"))
+        );
+    }
+
+    #[test]
+    fn test_replace_group_proxies_keeps_stats() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let get_link_proxy = |link: &str| {
+            let (outbound, name) = crate::vmess::generate::parse_share_link(link).unwrap();
+            Proxy::new(name, &outbound, "sub").unwrap()
+        };
+        replace_group_proxies(
+            &mut conn,
+            "sub",
+            &[
+                get_link_proxy("trojan://password@a.example.com:443?security=tls#a"),
+                get_link_proxy("trojan://password@b.example.com:443?security=tls#b"),
+            ],
+        )
+        .unwrap();
+        let before = get_proxy_list(&conn).unwrap();
+        let a = before.iter().find(|i| i.proxy_name == "a").unwrap();
+        set_proxy_delay(&conn, &a.proxy_id, 120).unwrap();
+        set_proxy_real_delay(&conn, &a.proxy_id, 300).unwrap();
+        set_proxy_speed(&conn, &a.proxy_id, 1000, 2000).unwrap();
+        add_proxy_traffic(&conn, &a.proxy_id, 10, 20).unwrap();
+
+        // 同一个服务器改了名称与传输方式，仍然是原来的代理。
+        // the same server with a new name and transport is still the same proxy.
+        replace_group_proxies(
+            &mut conn,
+            "sub",
+            &[
+                get_link_proxy("trojan://password@A.example.com:443?security=tls&type=ws#renamed"),
+                get_link_proxy("trojan://password@c.example.com:443?security=tls#c"),
+            ],
+        )
+        .unwrap();
+        let after = get_proxy_list(&conn).unwrap();
+        assert_eq!(after.len(), 2);
+        let kept = get_proxy_by_id(&conn, &a.proxy_id).unwrap().unwrap();
+        assert_eq!(kept.proxy_name, "renamed");
+        assert!(kept.proxy_config.contains("\"ws\""));
+        assert_eq!(kept.proxy_sort, a.proxy_sort);
+        assert_eq!(kept.proxy_delay, 120);
+        assert_eq!(kept.proxy_real_delay, 300);
+        assert_eq!(kept.proxy_speed_average, 1000);
+        assert_eq!(kept.proxy_speed_peak, 2000);
+        assert_eq!((kept.proxy_upload, kept.proxy_download), (10, 20));
+        assert!(after.iter().any(|i| i.proxy_name == "c"));
+        assert!(!after.iter().any(|i| i.proxy_name == "b"));
+    }
+
+    fn get_names(conn: &Connection) -> Vec<String> {
+        get_proxy_list(conn)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.proxy_name)
+            .collect()
+    }
+
+    #[test]
+    fn test_proxy_edit() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let proxies = [
+            get_proxy("a", "default"),
+            get_proxy("b", "default"),
+            get_proxy("c", "default"),
+        ];
+        for proxy in &proxies {
+            push_proxy(&conn, proxy).unwrap();
+        }
+        assert_eq!(get_names(&conn), ["a", "b", "c"]);
+
+        let renamed = Proxy {
+            proxy_name: "renamed".to_string(),
+            ..get_proxy_by_id(&conn, &proxies[0].proxy_id)
+                .unwrap()
+                .unwrap()
+        };
+        update_proxy(&conn, &renamed).unwrap();
+        assert_eq!(get_names(&conn), ["renamed", "b", "c"]);
+        assert!(update_proxy(&conn, &get_proxy("missing", "default")).is_err());
+
+        let copy = duplicate_proxy(&mut conn, &proxies[1].proxy_id).unwrap();
+        assert_eq!(copy.proxy_name, "b - copy");
+        assert_eq!(get_names(&conn), ["renamed", "b", "b - copy", "c"]);
+
+        reorder_proxies(
+            &mut conn,
+            &[proxies[2].proxy_id.clone(), copy.proxy_id.clone()],
+        )
+        .unwrap();
+        assert_eq!(get_names(&conn), ["c", "b - copy", "renamed", "b"]);
+        assert!(reorder_proxies(&mut conn, &["missing".to_string()]).is_err());
+        assert_eq!(get_names(&conn), ["c", "b - copy", "renamed", "b"]);
+
+        // 任意一个代理不存在时不删除任何代理。
+        // nothing is deleted when any of the proxies does not exist.
+        assert!(delete_proxies(
+            &mut conn,
+            &[proxies[0].proxy_id.clone(), "missing".to_string()]
+        )
+        .is_err());
+        assert_eq!(get_proxy_list(&conn).unwrap().len(), 4);
+        delete_proxies(
+            &mut conn,
+            &[proxies[0].proxy_id.clone(), copy.proxy_id.clone()],
+        )
+        .unwrap();
+        assert_eq!(get_names(&conn), ["c", "b"]);
+        assert!(get_proxy_by_id(&conn, &copy.proxy_id).unwrap().is_none());
+    }
+
+    fn get_rule(name: &str) -> RoutingRule {
+        RoutingRule {
+            rule_id: uuid::Uuid::new_v4().to_string(),
+            rule_name: name.to_string(),
+            rule_outbound: RuleOutbound::Direct,
+            rule_priority: 0,
+            rule_enabled: true,
+            rule_match: crate::routing::RuleMatch {
+                domain: vec![format!("domain:{}.com", name)],
+                ..Default::default()
+            },
         }
-        Ok(0)
-    });
-    for _ in proxy_iter {}
+    }
+
+    fn get_rule_names(conn: &Connection) -> Vec<String> {
+        get_routing_rules(conn)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.rule_name)
+            .collect()
+    }
+
+    #[test]
+    fn test_routing_rules() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let rules = [get_rule("a"), get_rule("b"), get_rule("c")];
+        for rule in &rules {
+            push_routing_rule(&conn, rule).unwrap();
+        }
+        assert_eq!(get_rule_names(&conn), ["a", "b", "c"]);
+        assert_eq!(get_routing_rules(&conn).unwrap()[0], RoutingRule {
+            rule_priority: 1,
+            ..rules[0].clone()
+        });
+
+        let updated = RoutingRule {
+            rule_outbound: RuleOutbound::Block,
+            rule_enabled: false,
+            ..rules[1].clone()
+        };
+        update_routing_rule(&conn, &updated).unwrap();
+        let stored = &get_routing_rules(&conn).unwrap()[1];
+        assert_eq!(stored.rule_outbound, RuleOutbound::Block);
+        assert!(!stored.rule_enabled);
+        assert_eq!(stored.rule_priority, 2);
+        assert!(update_routing_rule(&conn, &get_rule("missing")).is_err());
+
+        reorder_routing_rules(&mut conn, &[rules[2].rule_id.clone()]).unwrap();
+        assert_eq!(get_rule_names(&conn), ["c", "a", "b"]);
+        assert!(reorder_routing_rules(&mut conn, &["missing".to_string()]).is_err());
+        assert_eq!(get_rule_names(&conn), ["c", "a", "b"]);
+
+        assert!(delete_routing_rules(
+            &mut conn,
+            &[rules[0].rule_id.clone(), "missing".to_string()]
+        )
+        .is_err());
+        delete_routing_rules(&mut conn, &[rules[0].rule_id.clone()]).unwrap();
+        assert_eq!(get_rule_names(&conn), ["c", "b"]);
+    }
 }
@@ -1,27 +1,215 @@
-use std::fmt;
+use std::{fmt, io, sync::PoisonError};
 
-#[derive(Debug)]
-pub struct CoreConfigError {
-    pub msg: String,
-    pub code: i64,
+use serde::Serialize;
+
+use crate::vmess::error::{GenerateLinkError, ParseLinkError, ParseLinkErrorCode};
+
+/// 所有命令共用的错误，序列化为 `{ "kind": "not_found", "msg": "..." }`，前端根据 kind 区分错误。
+/// kind 的取值是稳定的，只会新增。
+/// the error shared by all commands, serialized as `{ "kind": "not_found", "msg": "..." }` so that the
+/// frontend can tell the errors apart by kind. The kinds are stable and only ever added to.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(tag = "kind", content = "msg", rename_all = "snake_case")]
+pub enum AppError {
+    /// 读写文件或运行进程失败
+    /// reading or writing a file or running a process failed
+    Io(String),
+    /// 数据库错误
+    /// a database error
+    Database(String),
+    /// JSON 或 YAML 无法解析或生成
+    /// JSON or YAML could not be parsed or generated
+    Serialization(String),
+    /// Base64 解码失败
+    /// base64 decoding failed
+    Base64(String),
+    /// 网络请求失败
+    /// a network request failed
+    Network(String),
+    /// 分享链接或导入的配置无效
+    /// a share link or an imported config is invalid
+    InvalidLink(String),
+    /// 不支持的代理类型、协议或传输方式
+    /// an unsupported proxy type, protocol or transport
+    Unsupported(String),
+    /// 代理或订阅不存在
+    /// the proxy or subscription does not exist
+    NotFound(String),
+    /// 找不到可用的核心
+    /// no usable core was found
+    CoreNotFound(String),
+    /// 核心无法运行或不是支持的核心
+    /// the core cannot be run or is not a supported core
+    CoreInvalid(String),
+    /// 写入配置或启动核心失败
+    /// writing the config or starting the core failed
+    CoreStart(String),
+    /// 订阅中没有可用的代理
+    /// the subscription contains no usable proxy
+    Subscription(String),
+    /// 内部状态损坏，例如锁被污染
+    /// the internal state is broken, e.g. a poisoned lock
+    Internal(String),
+    /// 路由规则无效
+    /// a routing rule is invalid
+    InvalidRule(String),
+}
+
+impl AppError {
+    pub fn proxy_not_found(proxy_id: &str) -> AppError {
+        AppError::NotFound(format!("proxy not found: {}", proxy_id))
+    }
+
+    pub fn rule_not_found(rule_id: &str) -> AppError {
+        AppError::NotFound(format!("rule not found: {}", rule_id))
+    }
+
+    pub fn subscription_not_found(subscription_id: &str) -> AppError {
+        AppError::NotFound(format!("subscription not found: {}", subscription_id))
+    }
+
+    pub fn msg(&self) -> &str {
+        match self {
+            AppError::Io(i)
+            | AppError::Database(i)
+            | AppError::Serialization(i)
+            | AppError::Base64(i)
+            | AppError::Network(i)
+            | AppError::InvalidLink(i)
+            | AppError::Unsupported(i)
+            | AppError::NotFound(i)
+            | AppError::CoreNotFound(i)
+            | AppError::CoreInvalid(i)
+            | AppError::CoreStart(i)
+            | AppError::Subscription(i)
+            | AppError::Internal(i)
+            | AppError::InvalidRule(i) => i,
+        }
+    }
 }
 
-// 为 AppError 实现 std::fmt::Display 特征
-impl fmt::Display for CoreConfigError {
+impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Core config errorr:{} \n Code:{}",self.msg,self.code)
+        let prefix = match self {
+            AppError::Io(_) => "io error",
+            AppError::Database(_) => "database error",
+            AppError::Serialization(_) => "serialization error",
+            AppError::Base64(_) => "base64 decode error",
+            AppError::Network(_) => "network error",
+            AppError::InvalidLink(_) => "invalid link",
+            AppError::Unsupported(_) => "unsupported",
+            AppError::NotFound(_) => "not found",
+            AppError::CoreNotFound(_) => "core not found",
+            AppError::CoreInvalid(_) => "invalid core",
+            AppError::CoreStart(_) => "failed to start core",
+            AppError::Subscription(_) => "subscription error",
+            AppError::Internal(_) => "internal error",
+            AppError::InvalidRule(_) => "invalid rule",
+        };
+        write!(f, "{}: {}", prefix, self.msg())
     }
 }
 
-#[derive(Debug)]
-pub struct ProxySwitchError {
-    pub msg: String,
+impl std::error::Error for AppError {}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e.to_string())
+    }
 }
 
-// 为 AppError 实现 std::fmt::Display 特征
-impl fmt::Display for ProxySwitchError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "An error occurred while switching proxies
-        msg:{}",self.msg)
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Database(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for AppError {
+    fn from(e: serde_yaml::Error) -> Self {
+        AppError::Serialization(e.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for AppError {
+    fn from(e: base64::DecodeError) -> Self {
+        AppError::Base64(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        AppError::Network(e.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for AppError {
+    fn from(e: PoisonError<T>) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<ParseLinkError> for AppError {
+    fn from(e: ParseLinkError) -> Self {
+        match e.code {
+            ParseLinkErrorCode::Base64Error => AppError::Base64(e.msg),
+            _ => AppError::InvalidLink(e.to_string()),
+        }
+    }
+}
+
+impl From<GenerateLinkError> for AppError {
+    fn from(e: GenerateLinkError) -> Self {
+        AppError::Unsupported(e.msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_value(AppError::proxy_not_found("1")).unwrap(),
+            serde_json::json!({"kind": "not_found", "msg": "proxy not found: 1"})
+        );
+        assert_eq!(
+            serde_json::to_value(AppError::CoreNotFound("no core".to_string())).unwrap()["kind"],
+            "core_not_found"
+        );
+    }
+
+    #[test]
+    fn test_from() {
+        let e: AppError = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        assert_eq!(e, AppError::Io("missing".to_string()));
+        let e: AppError = serde_json::from_str::<i32>("x").unwrap_err().into();
+        assert!(matches!(e, AppError::Serialization(_)));
+        let e: AppError = rusqlite::Connection::open_in_memory()
+            .unwrap()
+            .execute("SELECT * FROM missing", [])
+            .unwrap_err()
+            .into();
+        assert!(matches!(e, AppError::Database(_)));
+        let e: AppError = ParseLinkError {
+            msg: "bad".to_string(),
+            code: ParseLinkErrorCode::Base64Error,
+        }
+        .into();
+        assert_eq!(e, AppError::Base64("bad".to_string()));
+        let e: AppError = crate::vmess::generate::parse_share_link("ftp://x")
+            .unwrap_err()
+            .into();
+        assert!(matches!(e, AppError::InvalidLink(_)));
+        assert_eq!(
+            e.to_string(),
+            "invalid link: link error: unsupported link: ftp"
+        );
     }
 }
@@ -1,37 +1,58 @@
 use std::{
     fs,
-    io::{self, ErrorKind},
+    io::{self, Write},
+    path::{Path, PathBuf},
 };
 
-use directories::BaseDirs;
+/// 用户的配置目录
+#[cfg(not(test))]
+fn get_config_dir() -> io::Result<PathBuf> {
+    let proj_dirs = directories::BaseDirs::new()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found config directory"))?;
+    Ok(proj_dirs.config_dir().join("v2neko"))
+}
 
-pub fn write(path: &str, data: &str) -> io::Result<()> {
-    Ok(if let Some(proj_dirs) = BaseDirs::new() {
-        let mut pa = proj_dirs.config_dir().join("v2neko");
-        fs::create_dir_all(&pa)?;
-        pa = pa.join(path);
-        fs::write(pa, data)?
-    })
+/// 测试时每个测试线程使用自己的临时目录，不会读写用户真实的配置，并发的测试之间也互不影响
+#[cfg(test)]
+fn get_config_dir() -> io::Result<PathBuf> {
+    Ok(std::env::temp_dir().join(format!(
+        "v2neko-test-config-{}-{:?}",
+        std::process::id(),
+        std::thread::current().id()
+    )))
 }
 
-// pub fn read(path: &str) -> io::Result<String> {
-//     if let Some(proj_dirs) = BaseDirs::new() {
-//         let mut pa = proj_dirs.config_dir().join("v2neko");
-//         fs::create_dir_all(&pa)?;
-//         pa = pa.join(path);
-//         Ok(fs::read_to_string(pa)?)
-//     } else {
-//         Err(io::Error::new(
-//             ErrorKind::NotFound,
-//             "not found config directory",
-//         ))
-//     }
-// }
-pub fn read(path: &str) -> io::Result<String> {
-    let proj_dirs = BaseDirs::new()
-        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "not found config directory"))?;
-    let mut pa = proj_dirs.config_dir().join("v2neko");
+/// 配置目录中某个文件的绝对路径，目录不存在时会被创建
+pub fn get_path(path: &str) -> io::Result<PathBuf> {
+    let pa = get_config_dir()?;
     fs::create_dir_all(&pa)?;
-    pa = pa.join(path);
-    Ok(fs::read_to_string(pa)?)
+    Ok(pa.join(path))
+}
+
+/// 原子地写入文件：先写入同目录下的临时文件再重命名，unix 上只有当前用户可以读写
+pub fn write_atomic(path: &Path, data: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // 权限只在创建时生效，所以先删除残留的临时文件
+    fs::remove_file(&tmp).ok();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// 读取配置目录中的文件
+pub fn read(path: &str) -> io::Result<String> {
+    fs::read_to_string(get_path(path)?)
 }
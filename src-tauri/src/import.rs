@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{
+    depositor,
+    error::AppError,
+    proxy::{get_proxy_key, Proxy, ProxyKey},
+    vmess::generate::{clash, json, ImportEntry},
+};
+
+/// 将与已有代理或本次导入中靠前条目重复的代理转为跳过项。
+/// turn entries duplicating an existing proxy or an earlier entry of the same import into skipped ones.
+fn skip_duplicates(mut known: HashSet<ProxyKey>, entries: Vec<ImportEntry>) -> Vec<ImportEntry> {
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            Ok((outbound, name)) => {
+                let duplicated = match get_proxy_key(&outbound) {
+                    Some(key) => !known.insert(key),
+                    None => false,
+                };
+                if duplicated {
+                    Err((name, "duplicate of an existing proxy".to_string()))
+                } else {
+                    Ok((outbound, name))
+                }
+            }
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+/// 导入时被跳过的代理及其原因。
+/// a proxy skipped during import together with the reason.
+#[derive(Debug, Serialize, Clone)]
+pub struct SkippedProxy {
+    pub name: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: Vec<SkippedProxy>,
+}
+
+/// 将转换结果写入数据库的指定分组，并汇总导入报告。
+/// push the converted outbounds into the given group and summarize them into a report.
+fn push_entries(
+    conn: &Connection,
+    entries: Vec<ImportEntry>,
+    proxy_group: &str,
+) -> Result<ImportReport, AppError> {
+    let known = depositor::get_proxy_list(conn)?
+        .iter()
+        .filter_map(|i| i.key())
+        .collect();
+    let mut report = ImportReport::default();
+    for entry in skip_duplicates(known, entries) {
+        match entry {
+            Ok((outbound, name)) => {
+                depositor::push_proxy(conn, &Proxy::new(name, &outbound, proxy_group)?)?;
+                report.imported += 1;
+            }
+            Err((name, reason)) => report.skipped.push(SkippedProxy { name, reason }),
+        }
+    }
+    Ok(report)
+}
+
+/// 导入 Clash / Clash.Meta 配置中的代理。
+/// import the proxies of a Clash / Clash.Meta profile.
+pub fn import_clash(
+    conn: &Connection,
+    content: &str,
+    proxy_group: &str,
+) -> Result<ImportReport, AppError> {
+    let entries = clash::parse_clash_proxies(content)?;
+    push_entries(conn, entries, proxy_group)
+}
+
+/// 导入 Xray/V2Ray 或 sing-box 的 JSON 配置中的代理出站。
+/// import the proxy outbounds of a Xray/V2Ray or sing-box JSON config.
+pub fn import_json(
+    conn: &Connection,
+    content: &str,
+    proxy_group: &str,
+) -> Result<ImportReport, AppError> {
+    let entries = json::parse_json_outbounds(content)?;
+    push_entries(conn, entries, proxy_group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_duplicates() {
+        let content = r#"[
+    { "tag": "a", "protocol": "trojan", "settings": { "servers": [{ "address": "Example.com", "port": 443, "password": "p1" }] } },
+    { "tag": "b", "protocol": "trojan", "settings": { "servers": [{ "address": "example.com", "port": 443, "password": "p1" }] } },
+    { "tag": "c", "protocol": "trojan", "settings": { "servers": [{ "address": "example.com", "port": 443, "password": "p2" }] } },
+    { "tag": "d", "protocol": "trojan", "settings": { "servers": [{ "address": "other.com", "port": 443, "password": "p1" }] } }
+]"#;
+        let mut known = HashSet::new();
+        known.insert(("other.com".to_string(), 443, "p1".to_string()));
+        let entries = skip_duplicates(known, json::parse_json_outbounds(content).unwrap());
+        let imported: Vec<String> = entries
+            .iter()
+            .filter_map(|i| i.as_ref().ok().map(|(_, name)| name.clone()))
+            .collect();
+        assert_eq!(imported, ["a", "c"]);
+        let skipped: Vec<String> = entries
+            .iter()
+            .filter_map(|i| i.as_ref().err().map(|(name, _)| name.clone()))
+            .collect();
+        assert_eq!(skipped, ["b", "d"]);
+    }
+}
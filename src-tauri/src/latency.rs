@@ -0,0 +1,564 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ServerName,
+};
+use serde::Serialize;
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, Semaphore},
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    proxy::Proxy,
+    vmess::{core::ProbeCore, generate::OutboundObject},
+};
+
+/// 每测完一个代理发送一次的事件。
+/// the event sent every time a proxy has been tested.
+pub const LATENCY_PROGRESS_EVENT: &str = "latency-progress";
+
+/// 真实延迟测试中每测完一个代理发送一次的事件。
+/// the event sent every time a proxy has been tested for its real delay.
+pub const REAL_DELAY_PROGRESS_EVENT: &str = "real-delay-progress";
+
+/// 测试失败时写入的延迟。
+/// the delay written when the test failed.
+pub const FAILED_DELAY: i32 = -1;
+
+#[derive(Debug, Clone)]
+pub struct LatencyOptions {
+    /// 同时测试的代理数量
+    /// the number of proxies tested at the same time
+    pub concurrency: usize,
+    /// 单个代理的超时时间，包括连接与握手
+    /// the timeout of a single proxy, covering both connect and handshake
+    pub timeout: Duration,
+    /// 对使用 TLS 的代理额外进行一次 TLS 握手
+    /// additionally do a TLS handshake for proxies using TLS
+    pub tls: bool,
+}
+
+impl Default for LatencyOptions {
+    fn default() -> Self {
+        LatencyOptions {
+            concurrency: 16,
+            timeout: Duration::from_secs(3),
+            tls: false,
+        }
+    }
+}
+
+/// 单个代理的测试结果与整体进度。
+/// the result of a single proxy together with the overall progress.
+#[derive(Debug, Serialize, Clone)]
+pub struct LatencyResult {
+    pub proxy_id: String,
+    /// 延迟（毫秒），失败时为 -1
+    /// the delay in milliseconds, -1 when failed
+    pub delay: i32,
+    pub error: Option<String>,
+    pub finished: usize,
+    pub total: usize,
+}
+
+/// 一个待测试的服务器。
+/// a server to be tested.
+#[derive(Debug, Clone)]
+struct LatencyTarget {
+    address: String,
+    port: u16,
+    /// TLS 握手使用的 SNI，不使用 TLS 时为 None
+    /// the SNI of the TLS handshake, None without TLS
+    server_name: Option<String>,
+}
+
+impl LatencyTarget {
+    /// 从代理保存的出站配置中取出服务器地址。
+    /// take the server address from the outbound stored in the proxy.
+    fn from_proxy(proxy: &Proxy) -> Result<LatencyTarget, String> {
+        let outbound = serde_json::from_str::<OutboundObject>(&proxy.proxy_config)
+            .map_err(|e| e.to_string())?;
+        let (address, port) = outbound
+            .server()
+            .ok_or_else(|| "missing server".to_string())?;
+        Ok(LatencyTarget {
+            address,
+            port: u16::try_from(port).map_err(|_| format!("invalid port: {}", port))?,
+            server_name: outbound.tls_server_name(),
+        })
+    }
+}
+
+/// 延迟测试只关心握手耗时，因此不校验证书。
+/// the latency test only cares about the handshake time, so certificates are not verified.
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn get_tls_connector() -> TlsConnector {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn connect(target: &LatencyTarget, connector: Option<&TlsConnector>) -> Result<(), String> {
+    let stream = TcpStream::connect((target.address.as_str(), target.port))
+        .await
+        .map_err(|e| e.to_string())?;
+    if let (Some(connector), Some(server_name)) = (connector, &target.server_name) {
+        let server_name = ServerName::try_from(server_name.as_str()).map_err(|e| e.to_string())?;
+        connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// 测量到单个服务器的 TCP 连接（以及可选的 TLS 握手）耗时，单位毫秒。
+/// measure the TCP connect (and optionally the TLS handshake) time to a single server in milliseconds.
+async fn measure(
+    target: &LatencyTarget,
+    timeout: Duration,
+    connector: Option<&TlsConnector>,
+) -> Result<i32, String> {
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, connect(target, connector)).await {
+        Ok(Ok(())) => Ok(start.elapsed().as_millis().min(i32::MAX as u128) as i32),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(format!("timeout after {}ms", timeout.as_millis())),
+    }
+}
+
+/// 以有限的并发对每个代理执行一次测量，每完成一个就以 (代理id, 结果, 已完成数, 总数) 回调一次，回调顺序即完成顺序。
+/// run one measurement per proxy with bounded concurrency, calling back with (proxy id, result, finished, total)
+/// once per finished proxy in completion order.
+pub async fn run_concurrently<T, E, M, Fut, F>(
+    proxies: &[Proxy],
+    concurrency: usize,
+    measure: M,
+    mut on_result: F,
+) where
+    T: Send + 'static,
+    E: Send + 'static,
+    M: Fn(Proxy) -> Fut,
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    F: FnMut(String, Result<T, E>, usize, usize),
+{
+    let total = proxies.len();
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for proxy in proxies {
+        let semaphore = semaphore.clone();
+        let proxy_id = proxy.proxy_id.clone();
+        let future = measure(proxy.clone());
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            tx.send((proxy_id, future.await)).ok();
+        });
+    }
+    drop(tx);
+    let mut finished = 0;
+    while let Some((proxy_id, result)) = rx.recv().await {
+        finished += 1;
+        on_result(proxy_id, result, finished, total);
+    }
+}
+
+fn to_latency_result(
+    proxy_id: String,
+    result: Result<i32, String>,
+    finished: usize,
+    total: usize,
+) -> LatencyResult {
+    let (delay, error) = match result {
+        Ok(i) => (i, None),
+        Err(e) => (FAILED_DELAY, Some(e)),
+    };
+    LatencyResult {
+        proxy_id,
+        delay,
+        error,
+        finished,
+        total,
+    }
+}
+
+/// 以有限的并发测试全部代理的 TCP 延迟。出站配置无法解析的代理记为失败。
+/// test the TCP latency of every proxy with bounded concurrency. Proxies whose outbound cannot be read are reported as failed.
+pub async fn test_latency<F>(proxies: &[Proxy], options: &LatencyOptions, mut on_result: F)
+where
+    F: FnMut(LatencyResult),
+{
+    let connector = if options.tls {
+        Some(get_tls_connector())
+    } else {
+        None
+    };
+    let timeout = options.timeout;
+    let measure_proxy = move |proxy: Proxy| {
+        let connector = connector.clone();
+        async move {
+            let target = LatencyTarget::from_proxy(&proxy)?;
+            measure(&target, timeout, connector.as_ref()).await
+        }
+    };
+    run_concurrently(
+        proxies,
+        options.concurrency,
+        measure_proxy,
+        |proxy_id, result, finished, total| {
+            on_result(to_latency_result(proxy_id, result, finished, total))
+        },
+    )
+    .await;
+}
+
+#[derive(Debug, Clone)]
+pub struct RealDelayOptions {
+    /// 核心可执行文件的路径
+    /// the path of the core executable
+    pub core_path: String,
+    /// 通过代理访问的测试地址，返回 2xx 即视为成功
+    /// the probe url fetched through the proxy, any 2xx response counts as success
+    pub probe_url: String,
+    /// 同时运行的临时核心数量
+    /// the number of temporary cores running at the same time
+    pub concurrency: usize,
+    /// 启动核心与访问测试地址各自的超时时间
+    /// the timeout of starting the core and of fetching the probe url, each
+    pub timeout: Duration,
+}
+
+/// 通过 HTTP 代理访问测试地址并计时，单位毫秒。
+/// time fetching the probe url through an HTTP proxy in milliseconds.
+async fn measure_url(proxy_url: &str, probe_url: &str, timeout: Duration) -> Result<i32, String> {
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_url).map_err(|e| e.to_string())?)
+        .timeout(timeout)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    let response = client
+        .get(probe_url)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let delay = start.elapsed().as_millis().min(i32::MAX as u128) as i32;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status: {}", response.status()));
+    }
+    Ok(delay)
+}
+
+/// 为每个代理启动一个临时核心，通过它访问测试地址，得到真实延迟。
+/// start a temporary core for every proxy and fetch the probe url through it to get the real delay.
+pub async fn test_real_delay<F>(proxies: &[Proxy], options: &RealDelayOptions, mut on_result: F)
+where
+    F: FnMut(LatencyResult),
+{
+    let concurrency = options.concurrency;
+    let options = options.clone();
+    let measure_proxy = move |proxy: Proxy| {
+        let options = options.clone();
+        async move {
+            let outbound = serde_json::from_str::<OutboundObject>(&proxy.proxy_config)
+                .map_err(|e| e.to_string())?;
+            let probe = ProbeCore::start(&options.core_path, &outbound, options.timeout).await?;
+            let result = measure_url(&probe.proxy_url(), &options.probe_url, options.timeout).await;
+            probe.stop().await;
+            result
+        }
+    };
+    run_concurrently(
+        proxies,
+        concurrency,
+        measure_proxy,
+        |proxy_id, result, finished, total| {
+            on_result(to_latency_result(proxy_id, result, finished, total))
+        },
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::vmess::generate::parse_share_link;
+    #[cfg(unix)]
+    use crate::vmess::testing;
+
+    fn get_proxy(proxy_id: &str, link: &str) -> Proxy {
+        let (outbound, name) = parse_share_link(link).unwrap();
+        Proxy {
+            proxy_id: proxy_id.to_string(),
+            ..Proxy::new(name, &outbound, "default").unwrap()
+        }
+    }
+
+    /// 接受连接后读取少量数据再关闭，TLS 握手会因此失败。
+    /// accepts connections, reads a little and closes them, so TLS handshakes fail.
+    async fn serve_closing() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 16];
+                stream.read_exact(&mut buf).await.ok();
+            }
+        });
+        port
+    }
+
+    async fn get_closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_target_from_proxy() {
+        let target = LatencyTarget::from_proxy(&get_proxy(
+            "a",
+            "trojan://password@example.com:443?security=tls&sni=cdn.example.com#name",
+        ))
+        .unwrap();
+        assert_eq!(target.address, "example.com");
+        assert_eq!(target.port, 443);
+        assert_eq!(target.server_name.as_deref(), Some("cdn.example.com"));
+
+        let target = LatencyTarget::from_proxy(&get_proxy(
+            "b",
+            "ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888#ss",
+        ))
+        .unwrap();
+        assert!(target.server_name.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_latency_tcp() {
+        let open = serve_closing().await;
+        let closed = get_closed_port().await;
+        let invalid = Proxy {
+            proxy_config: "~/.config/v2neko/connections/".to_string(),
+            ..get_proxy("invalid", "ss://YWVzLTEyOC1nY206dGVzdA@127.0.0.1:1#ss")
+        };
+        let proxies = [
+            get_proxy(
+                "open",
+                &format!("ss://YWVzLTEyOC1nY206dGVzdA@127.0.0.1:{}#ss", open),
+            ),
+            get_proxy(
+                "closed",
+                &format!("ss://YWVzLTEyOC1nY206dGVzdA@127.0.0.1:{}#ss", closed),
+            ),
+            get_proxy(
+                "open-tls",
+                &format!("trojan://password@127.0.0.1:{}?security=tls#trojan", open),
+            ),
+            invalid,
+        ];
+        let mut results = Vec::new();
+        test_latency(
+            &proxies,
+            &LatencyOptions {
+                concurrency: 2,
+                ..LatencyOptions::default()
+            },
+            |i| results.push(i),
+        )
+        .await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.last().unwrap().finished, 4);
+        assert!(results.iter().all(|i| i.total == 4));
+        for result in results {
+            match result.proxy_id.as_str() {
+                // 未开启 TLS 时只测 TCP 连接。
+                // only the TCP connect is measured when TLS is disabled.
+                "open" | "open-tls" => assert!(result.delay >= 0, "{:?}", result),
+                _ => {
+                    assert_eq!(result.delay, FAILED_DELAY);
+                    assert!(result.error.is_some());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency_tls() {
+        let open = serve_closing().await;
+        let proxies = [
+            get_proxy(
+                "tcp",
+                &format!("ss://YWVzLTEyOC1nY206dGVzdA@127.0.0.1:{}#ss", open),
+            ),
+            get_proxy(
+                "tls",
+                &format!(
+                    "trojan://password@127.0.0.1:{}?security=tls&sni=example.com#trojan",
+                    open
+                ),
+            ),
+        ];
+        let mut results = Vec::new();
+        test_latency(
+            &proxies,
+            &LatencyOptions {
+                tls: true,
+                ..LatencyOptions::default()
+            },
+            |i| results.push(i),
+        )
+        .await;
+        assert_eq!(results.len(), 2);
+        for result in results {
+            match result.proxy_id.as_str() {
+                "tcp" => assert!(result.delay >= 0, "{:?}", result),
+                _ => assert_eq!(result.delay, FAILED_DELAY),
+            }
+        }
+    }
+
+    /// 一个简单的 HTTP 代理替身：读取请求头后返回给定的状态行，并把请求行发回测试。
+    /// a tiny HTTP proxy stand-in: reads the request head, answers with the given status line and hands the request line back to the test.
+    async fn serve_proxy(status: &'static str) -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_string();
+                tx.send(head.lines().next().unwrap_or_default().to_string())
+                    .ok();
+                stream
+                    .write_all(
+                        format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes(),
+                    )
+                    .await
+                    .ok();
+            }
+        });
+        (port, rx)
+    }
+
+    #[tokio::test]
+    async fn test_measure_url() {
+        let (port, mut rx) = serve_proxy("204 No Content").await;
+        let proxy_url = format!("http://127.0.0.1:{}", port);
+        let delay = measure_url(
+            &proxy_url,
+            "http://probe.invalid/generate_204",
+            Duration::from_secs(3),
+        )
+        .await
+        .unwrap();
+        assert!(delay >= 0);
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            "GET http://probe.invalid/generate_204 HTTP/1.1"
+        );
+
+        let (port, _rx) = serve_proxy("502 Bad Gateway").await;
+        let proxy_url = format!("http://127.0.0.1:{}", port);
+        assert!(measure_url(
+            &proxy_url,
+            "http://probe.invalid/generate_204",
+            Duration::from_secs(3)
+        )
+        .await
+        .is_err());
+    }
+
+    /// 写出一个假的核心：把收到的配置复制到 `config_copy` 后执行给定的命令。
+    /// write a fake core that copies the config it receives to `config_copy` and then runs the given command.
+    #[cfg(unix)]
+    fn write_fake_core(name: &str, command: &str) -> (String, std::path::PathBuf) {
+        let dir = testing::get_temp_dir();
+        let config_copy = dir.join("config.json");
+        let path = testing::write_fake_core(
+            &dir,
+            name,
+            &format!("cp \"$2\" \"{}\"\n{}\n", config_copy.display(), command),
+        );
+        (path.to_string_lossy().to_string(), config_copy)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_real_delay_fake_core() {
+        let (exiting, exiting_config) = write_fake_core("exiting", "exit 1");
+        let (silent, silent_config) = write_fake_core("silent", "exec sleep 30");
+        let proxies = [get_proxy(
+            "proxy",
+            "trojan://password@example.com:443?security=tls#trojan",
+        )];
+        for (core_path, config_copy, error) in [
+            (exiting, exiting_config, "core exited"),
+            (silent, silent_config, "did not listen"),
+        ] {
+            let mut results = Vec::new();
+            test_real_delay(
+                &proxies,
+                &RealDelayOptions {
+                    core_path,
+                    probe_url: "http://probe.invalid/generate_204".to_string(),
+                    concurrency: 1,
+                    timeout: Duration::from_millis(500),
+                },
+                |i| results.push(i),
+            )
+            .await;
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].delay, FAILED_DELAY);
+            assert!(
+                results[0].error.as_ref().unwrap().contains(error),
+                "{:?}",
+                results[0]
+            );
+
+            let config = serde_json::from_str::<serde_json::Value>(
+                &std::fs::read_to_string(config_copy).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(config["inbounds"][0]["protocol"], "http");
+            assert_eq!(config["inbounds"][0]["listen"], "127.0.0.1");
+            assert_eq!(config["outbounds"][0]["protocol"], "trojan");
+        }
+    }
+}
@@ -3,25 +3,34 @@
     windows_subsystem = "windows"
 )]
 
-use proxy::{Proxy, ProxyTrait};
-use rusqlite::Connection;
-use serde::{Deserialize, Serialize};
+use proxy::Proxy;
+use routing::RoutingRule;
+use subscription::{Subscription, SubscriptionUpdated, SubscriptionUserinfo};
+use tauri::Manager;
+use error::AppError;
+use latency::{LatencyOptions, LatencyResult, RealDelayOptions};
+use speedtest::{CancelToken, SpeedTestOptions, SpeedTestResult};
+use state::AppState;
+use std::time::Duration;
+use vmess::{
+    generate::{generate_share_link, parse_share_link, OutboundObject},
+    log::{LogLevel, LogLine, CORE_LOG_EVENT},
+    shutdown,
+    supervisor::{SupervisorOptions, CORE_STATE_EVENT},
+};
 mod config;
 mod depositor;
 mod error;
 mod files;
+mod import;
+mod latency;
 mod proxy;
+mod routing;
+mod speedtest;
+mod state;
+mod subscription;
 mod vmess;
 
-pub static mut DATABSE: Option<Connection> = None;
-static mut PROXY: Option<Box<dyn ProxyTrait>> = None;
-
-#[derive(Debug, Deserialize, Serialize)]
-struct Msg {
-    code: i32,
-    msg: String,
-}
-
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -30,82 +39,475 @@ fn greet(name: &str) -> String {
 
 #[tauri::command]
 /// 获取代理列表
-fn get_proxies_list() -> Vec<proxy::Proxy> {
-    unsafe {
-        match &DATABSE {
-            Some(i) => depositor::get_proxy_list(i),
-            None => panic!("Haven't connect to database"),
+fn get_proxies_list(state: tauri::State<'_, AppState>) -> Result<Vec<proxy::Proxy>, AppError> {
+    depositor::get_proxy_list(&*state.database()?)
+}
+
+#[tauri::command]
+/// 通过分享链接添加代理，名称为空时使用链接中的名称
+fn push_v2ray_proxy(
+    state: tauri::State<'_, AppState>,
+    name: String,
+    link: String,
+) -> Result<(), AppError> {
+    let (outbound, link_name) = parse_share_link(&link)?;
+    let name = if name.is_empty() { link_name } else { name };
+    depositor::push_proxy(&*state.database()?, &Proxy::new(name, &outbound, "default")?)
+}
+
+#[tauri::command]
+/// 切换到指定的代理，切换期间其他使用核心的命令会等待
+async fn choice_proxy(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    proxy_id: String,
+) -> Result<(), AppError> {
+    let proxy = depositor::get_proxy_by_id(&*state.database()?, &proxy_id)?
+        .ok_or_else(|| AppError::proxy_not_found(&proxy_id))?;
+    let rules = routing::get_rule_objects(&depositor::get_routing_rules(&*state.database()?)?);
+    let core_path = state.core_path.get(&config::read()?.core_path).await?;
+    let mut core = state.core.lock().await;
+    core.switch(app.clone(), core_path, &proxy, rules).await
+}
+
+#[tauri::command]
+/// 获取代理的分享链接，以代理的名称作为链接备注
+fn get_share_link(state: tauri::State<'_, AppState>, proxy_id: String) -> Result<String, AppError> {
+    let proxy = depositor::get_proxy_by_id(&*state.database()?, &proxy_id)?
+        .ok_or_else(|| AppError::proxy_not_found(&proxy_id))?;
+    let outbound = serde_json::from_str::<OutboundObject>(&proxy.proxy_config)?;
+    Ok(generate_share_link(&outbound, &proxy.proxy_name)?)
+}
+
+#[tauri::command]
+/// 更新代理的名称、出站配置与分组
+fn update_proxy(state: tauri::State<'_, AppState>, proxy: Proxy) -> Result<(), AppError> {
+    let outbound = serde_json::from_str::<OutboundObject>(&proxy.proxy_config)
+        .map_err(|e| AppError::Serialization(format!("invalid outbound config: {}", e)))?;
+    let proxy = Proxy {
+        proxy_type: outbound.protocol().to_owned(),
+        ..proxy
+    };
+    depositor::update_proxy(&*state.database()?, &proxy)
+}
+
+#[tauri::command]
+/// 删除单个代理
+fn delete_proxy(state: tauri::State<'_, AppState>, proxy_id: String) -> Result<(), AppError> {
+    depositor::delete_proxies(&mut *state.database()?, &[proxy_id])
+}
+
+#[tauri::command]
+/// 批量删除代理
+fn delete_proxies(
+    state: tauri::State<'_, AppState>,
+    proxy_ids: Vec<String>,
+) -> Result<(), AppError> {
+    depositor::delete_proxies(&mut *state.database()?, &proxy_ids)
+}
+
+#[tauri::command]
+/// 复制代理，返回新的副本
+fn duplicate_proxy(state: tauri::State<'_, AppState>, proxy_id: String) -> Result<Proxy, AppError> {
+    depositor::duplicate_proxy(&mut *state.database()?, &proxy_id)
+}
+
+#[tauri::command]
+/// 保存代理的手动排序
+fn reorder_proxies(
+    state: tauri::State<'_, AppState>,
+    proxy_ids: Vec<String>,
+) -> Result<(), AppError> {
+    depositor::reorder_proxies(&mut *state.database()?, &proxy_ids)
+}
+
+/// 把数据库中的路由规则应用到正在运行的核心
+async fn apply_routing_rules(state: &tauri::State<'_, AppState>) -> Result<(), AppError> {
+    let rules = routing::get_rule_objects(&depositor::get_routing_rules(&*state.database()?)?);
+    state.core.lock().await.set_rules(rules).await
+}
+
+#[tauri::command]
+/// 获取路由规则，按优先级排列
+fn get_routing_rules(state: tauri::State<'_, AppState>) -> Result<Vec<RoutingRule>, AppError> {
+    depositor::get_routing_rules(&*state.database()?)
+}
+
+#[tauri::command]
+/// 添加路由规则，排在最后并立即生效，返回保存后的规则
+async fn push_routing_rule(
+    state: tauri::State<'_, AppState>,
+    rule: RoutingRule,
+) -> Result<RoutingRule, AppError> {
+    rule.validate()?;
+    let rule = RoutingRule {
+        rule_id: uuid::Uuid::new_v4().to_string(),
+        ..rule
+    };
+    depositor::push_routing_rule(&*state.database()?, &rule)?;
+    apply_routing_rules(&state).await?;
+    depositor::get_routing_rules(&*state.database()?)?
+        .into_iter()
+        .find(|i| i.rule_id == rule.rule_id)
+        .ok_or_else(|| AppError::rule_not_found(&rule.rule_id))
+}
+
+#[tauri::command]
+/// 更新路由规则并立即生效
+async fn update_routing_rule(
+    state: tauri::State<'_, AppState>,
+    rule: RoutingRule,
+) -> Result<(), AppError> {
+    rule.validate()?;
+    depositor::update_routing_rule(&*state.database()?, &rule)?;
+    apply_routing_rules(&state).await
+}
+
+#[tauri::command]
+/// 批量删除路由规则并立即生效
+async fn delete_routing_rules(
+    state: tauri::State<'_, AppState>,
+    rule_ids: Vec<String>,
+) -> Result<(), AppError> {
+    depositor::delete_routing_rules(&mut *state.database()?, &rule_ids)?;
+    apply_routing_rules(&state).await
+}
+
+#[tauri::command]
+/// 保存路由规则的顺序并立即生效
+async fn reorder_routing_rules(
+    state: tauri::State<'_, AppState>,
+    rule_ids: Vec<String>,
+) -> Result<(), AppError> {
+    depositor::reorder_routing_rules(&mut *state.database()?, &rule_ids)?;
+    apply_routing_rules(&state).await
+}
+
+#[tauri::command]
+/// 获取当前的路由模式
+fn get_routing_mode() -> Result<config::RoutingMode, AppError> {
+    Ok(config::read()?.routing_mode)
+}
+
+#[tauri::command]
+/// 切换路由模式，保存到配置中并重新生成正在运行的核心的配置
+async fn set_routing_mode(
+    state: tauri::State<'_, AppState>,
+    mode: config::RoutingMode,
+) -> Result<(), AppError> {
+    let mut core = state.core.lock().await;
+    let app_config = config::AppConfig {
+        routing_mode: mode,
+        ..config::read()?
+    };
+    config::save(&app_config)?;
+    core.apply().await
+}
+
+#[tauri::command]
+/// 测试代理的 TCP 延迟（可选 TLS 握手）并保存，未指定代理时测试全部代理，进度通过事件推送
+async fn test_proxies_latency(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    proxy_ids: Vec<String>,
+    tls: bool,
+) -> Result<Vec<LatencyResult>, AppError> {
+    let proxies: Vec<Proxy> = depositor::get_proxy_list(&*state.database()?)?
+        .into_iter()
+        .filter(|i| proxy_ids.is_empty() || proxy_ids.contains(&i.proxy_id))
+        .collect();
+    let options = LatencyOptions {
+        tls,
+        ..LatencyOptions::default()
+    };
+    let mut results = Vec::new();
+    latency::test_latency(&proxies, &options, |result| {
+        if let Ok(conn) = state.database() {
+            depositor::set_proxy_delay(&conn, &result.proxy_id, result.delay).ok();
         }
-    }
+        app.emit_all(latency::LATENCY_PROGRESS_EVENT, result.clone())
+            .ok();
+        results.push(result);
+    })
+    .await;
+    Ok(results)
 }
 
 #[tauri::command]
-fn push_v2ray_proxy(name: String, proxy_type: String) {
-    unsafe {
-        match &DATABSE {
-            Some(i) => {
-                let id = uuid::Uuid::new_v4().to_string();
-                let proxy = Proxy {
-                    proxy_name: name,
-                    proxy_type: proxy_type,
-                    proxy_config_path: "~/.config/v2neko/connections/".to_owned(),
-                    proxy_delay: -1,
-                    proxy_download: 0,
-                    proxy_upload: 0,
-                    proxy_id: id,
-                    proxy_group: "default".to_owned(),
-                };
-                depositor::push_proxy(i, &proxy)
-            }
-            None => panic!("Haven't connect to database"),
+/// 通过临时核心测试代理访问测试地址的真实延迟并保存，未指定代理时测试全部代理，进度通过事件推送
+async fn test_proxies_real_delay(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    proxy_ids: Vec<String>,
+    probe_url: Option<String>,
+) -> Result<Vec<LatencyResult>, AppError> {
+    let proxies: Vec<Proxy> = depositor::get_proxy_list(&*state.database()?)?
+        .into_iter()
+        .filter(|i| proxy_ids.is_empty() || proxy_ids.contains(&i.proxy_id))
+        .collect();
+    let app_config = config::read()?;
+    let options = RealDelayOptions {
+        core_path: state.core_path.get(&app_config.core_path).await?,
+        probe_url: probe_url.unwrap_or(app_config.probe_url),
+        concurrency: app_config.probe_concurrency,
+        timeout: Duration::from_secs(app_config.probe_timeout),
+    };
+    let mut results = Vec::new();
+    latency::test_real_delay(&proxies, &options, |result| {
+        if let Ok(conn) = state.database() {
+            depositor::set_proxy_real_delay(&conn, &result.proxy_id, result.delay).ok();
         }
-    }
+        app.emit_all(latency::REAL_DELAY_PROGRESS_EVENT, result.clone())
+            .ok();
+        results.push(result);
+    })
+    .await;
+    Ok(results)
 }
 
 #[tauri::command]
-fn choice_proxy(proxy_id: &str) -> Msg {
-    let proxy;
-    unsafe {
-        proxy = depositor::get_proxy_by_id(&(&DATABSE).as_ref().unwrap(), proxy_id);
-        let core = proxy::use_proxy(&proxy);
-        if core.is_ok() {
-            PROXY = Some(Box::new(core.ok().unwrap()));
-            Msg {
-                code: 0,
-                msg: "success".to_owned(),
-            }
-        } else {
-            Msg {
-                code: -1,
-                msg: core.err().unwrap().msg,
+/// 对一个分组内的代理逐个测速并保存平均与峰值速度，进度通过事件推送，可通过 cancel_speed_test 取消
+async fn test_group_speed(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    group: String,
+    url: Option<String>,
+    seconds: Option<u64>,
+) -> Result<Vec<SpeedTestResult>, AppError> {
+    let proxies: Vec<Proxy> = depositor::get_proxy_list(&*state.database()?)?
+        .into_iter()
+        .filter(|i| i.proxy_group == group)
+        .collect();
+    let app_config = config::read()?;
+    let options = SpeedTestOptions {
+        core_path: state.core_path.get(&app_config.core_path).await?,
+        url: url.unwrap_or(app_config.speed_test_url),
+        duration: Duration::from_secs(seconds.unwrap_or(10)),
+        concurrency: app_config.speed_test_concurrency,
+        timeout: Duration::from_secs(app_config.speed_test_timeout),
+    };
+    let cancel = CancelToken::default();
+    if let Some(i) = state.speed_test.lock()?.replace(cancel.clone()) {
+        i.cancel();
+    }
+    let mut results = Vec::new();
+    speedtest::test_speed(&proxies, &options, &cancel, |result| {
+        if !result.cancelled {
+            if let Ok(conn) = state.database() {
+                depositor::set_proxy_speed(&conn, &result.proxy_id, result.average, result.peak)
+                    .ok();
             }
         }
+        app.emit_all(speedtest::SPEED_TEST_PROGRESS_EVENT, result.clone())
+            .ok();
+        results.push(result);
+    })
+    .await;
+    // 测速结束后清除取消标记，除非它已经被新的测速替换
+    let mut speed_test = state.speed_test.lock()?;
+    if matches!(&*speed_test, Some(i) if i.same_as(&cancel)) {
+        *speed_test = None;
+    }
+    Ok(results)
+}
+
+#[tauri::command]
+/// 取消正在进行的测速
+fn cancel_speed_test(state: tauri::State<'_, AppState>) -> Result<(), AppError> {
+    if let Some(i) = state.speed_test.lock()?.take() {
+        i.cancel();
     }
+    Ok(())
 }
 
 #[tauri::command]
-fn poll_output() -> Option<String> {
-    unsafe {
-        match &mut PROXY {
-            Some(i) => i.poll_output(),
-            None => None,
+/// 获取最后若干行核心日志，可按最低级别过滤，新的日志通过事件推送
+async fn get_core_logs(
+    state: tauri::State<'_, AppState>,
+    count: Option<usize>,
+    level: Option<LogLevel>,
+) -> Result<Vec<LogLine>, AppError> {
+    let core = state.core.lock().await;
+    Ok(core.get_logs(count.unwrap_or(200), level))
+}
+
+#[tauri::command]
+/// 获取订阅列表
+fn get_subscription_list(state: tauri::State<'_, AppState>) -> Result<Vec<Subscription>, AppError> {
+    depositor::get_subscription_list(&*state.database()?)
+}
+
+#[tauri::command]
+/// 添加订阅，返回订阅的id
+fn push_subscription(
+    state: tauri::State<'_, AppState>,
+    name: String,
+    url: String,
+    user_agent: String,
+    update_interval: i64,
+) -> Result<String, AppError> {
+    let subscription = Subscription {
+        subscription_id: uuid::Uuid::new_v4().to_string(),
+        subscription_name: name,
+        subscription_url: url,
+        subscription_user_agent: if user_agent.is_empty() {
+            format!("v2neko/{}", env!("CARGO_PKG_VERSION"))
+        } else {
+            user_agent
+        },
+        subscription_update_interval: update_interval,
+        subscription_last_update: 0,
+    };
+    depositor::push_subscription(&*state.database()?, &subscription)?;
+    Ok(subscription.subscription_id)
+}
+
+#[tauri::command]
+/// 删除订阅及其分组下的代理
+fn delete_subscription(
+    state: tauri::State<'_, AppState>,
+    subscription_id: String,
+) -> Result<(), AppError> {
+    depositor::delete_subscription(&mut *state.database()?, &subscription_id)
+}
+
+#[tauri::command]
+/// 获取各订阅的剩余流量与到期时间
+fn get_subscription_userinfo_list(
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<SubscriptionUserinfo>, AppError> {
+    let conn = state.database()?;
+    depositor::get_subscription_userinfo_list(&conn)
+}
+
+#[tauri::command]
+/// 立即刷新订阅
+async fn update_subscription(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, AppState>,
+    subscription_id: String,
+) -> Result<SubscriptionUpdated, AppError> {
+    // 刷新订阅期间不占用数据库
+    let subscription = depositor::get_subscription_by_id(&*state.database()?, &subscription_id)?
+        .ok_or_else(|| AppError::subscription_not_found(&subscription_id))?;
+    let result = subscription::refresh(&subscription).await;
+    let event = subscription::store(&mut *state.database()?, &subscription, result);
+    app.emit_all(subscription::SUBSCRIPTION_UPDATED_EVENT, event.clone())
+        .ok();
+    Ok(event)
+}
+
+#[tauri::command]
+/// 导入 Clash 配置中的代理
+fn import_clash_profile(
+    state: tauri::State<'_, AppState>,
+    content: String,
+    group: String,
+) -> Result<import::ImportReport, AppError> {
+    import::import_clash(&*state.database()?, &content, &group)
+}
+
+#[tauri::command]
+/// 导入 Xray/V2Ray 或 sing-box JSON 配置中的代理
+fn import_json_config(
+    state: tauri::State<'_, AppState>,
+    content: String,
+    group: String,
+) -> Result<import::ImportReport, AppError> {
+    import::import_json(&*state.database()?, &content, &group)
+}
+
+/// 等待正在进行的切换等操作结束后停止核心，等待超时时按 PID 文件结束核心
+fn stop_core_on_exit(app: &tauri::AppHandle) {
+    let app = app.clone();
+    // 当前线程已经处于异步运行时中，不能在这里 block_on，所以在新的线程中等待
+    let stopped = std::thread::spawn(move || {
+        tauri::async_runtime::block_on(async move {
+            let state = app.state::<AppState>();
+            let core = tokio::time::timeout(shutdown::EXIT_TIMEOUT, state.core.lock()).await;
+            match core {
+                Ok(mut core) => {
+                    core.shutdown();
+                    true
+                }
+                Err(_) => false,
+            }
+        })
+    })
+    .join()
+    .unwrap_or(false);
+    if !stopped {
+        if let Ok(i) = shutdown::PidFile::open() {
+            i.cleanup_stale();
         }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    unsafe {
-        DATABSE = Some(depositor::init_database());
+    // 清理上次异常退出时残留的核心
+    let pid_file = shutdown::PidFile::open();
+    if let Ok(i) = &pid_file {
+        i.cleanup_stale();
     }
     tauri::Builder::default()
+        .setup(|app| {
+            // 核心的状态变化与日志以事件的形式推送给前端
+            let handle = app.handle();
+            // 核心的路径在切换代理时才确定
+            let mut core = vmess::core::init("");
+            if let Ok(i) = pid_file {
+                core.set_pid_file(i);
+            }
+            core.set_supervisor(SupervisorOptions::default(), move |i| {
+                handle.emit_all(CORE_STATE_EVENT, i).ok();
+            });
+            let handle = app.handle();
+            core.set_log_listener(move |i| {
+                handle.emit_all(CORE_LOG_EVENT, i.clone()).ok();
+            });
+            app.manage(AppState::new(depositor::init_database()?, Box::new(core)));
+            // 自动更新订阅使用上面管理的数据库连接
+            subscription::spawn_auto_update(app.handle());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             get_proxies_list,
             push_v2ray_proxy,
             choice_proxy,
-            poll_output
+            get_share_link,
+            update_proxy,
+            delete_proxy,
+            delete_proxies,
+            duplicate_proxy,
+            reorder_proxies,
+            get_routing_rules,
+            push_routing_rule,
+            update_routing_rule,
+            delete_routing_rules,
+            reorder_routing_rules,
+            get_routing_mode,
+            set_routing_mode,
+            test_proxies_latency,
+            test_proxies_real_delay,
+            test_group_speed,
+            cancel_speed_test,
+            get_core_logs,
+            get_subscription_list,
+            push_subscription,
+            delete_subscription,
+            update_subscription,
+            get_subscription_userinfo_list,
+            import_clash_profile,
+            import_json_config
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            // 退出时停止核心，不让它在应用关闭后继续运行
+            if let tauri::RunEvent::Exit = event {
+                stop_core_on_exit(app);
+            }
+        });
 }
@@ -1,52 +1,132 @@
-
-
 use crate::{
-    error::{CoreConfigError, ProxySwitchError},
-    vmess, files,
+    error::AppError,
+    vmess::{
+        self,
+        generate::{OutboundObject, RuleObject},
+        log::{LogLevel, LogLine},
+    },
 };
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Proxy {
     pub proxy_id: String,
     pub proxy_name: String,
     pub proxy_type: String,
     pub proxy_upload: i64,
     pub proxy_download: i64,
-    pub proxy_config_path: String,
+    pub proxy_config: String,
     pub proxy_delay: i32,
-    pub proxy_group:String
+    pub proxy_group:String,
+    #[serde(default)]
+    pub proxy_sort: i64,
+    /// 通过核心访问测试地址的真实延迟，-1 表示未测试或失败
+    /// the real delay of fetching the probe url through the core, -1 when untested or failed
+    #[serde(default)]
+    pub proxy_real_delay: i32,
+    /// 测速的平均与峰值速度（字节每秒），-1 表示未测试或失败
+    /// the average and peak speed of the speed test in bytes per second, -1 when untested or failed
+    #[serde(default)]
+    pub proxy_speed_average: i64,
+    #[serde(default)]
+    pub proxy_speed_peak: i64,
+}
+
+impl Proxy {
+    /// 由出站配置创建一个新的代理，延迟与流量统计均为初始值。出站配置无法序列化时返回错误。
+    /// create a new proxy from an outbound, with delay and traffic at their initial values.
+    /// Returns an error when the outbound cannot be serialized.
+    pub fn new(
+        proxy_name: String,
+        outbound: &OutboundObject,
+        proxy_group: &str,
+    ) -> Result<Proxy, AppError> {
+        Ok(Proxy {
+            proxy_id: uuid::Uuid::new_v4().to_string(),
+            proxy_name,
+            proxy_type: outbound.protocol().to_string(),
+            proxy_upload: 0,
+            proxy_download: 0,
+            proxy_config: serde_json::to_string(outbound)?,
+            proxy_delay: -1,
+            proxy_group: proxy_group.to_string(),
+            proxy_sort: 0,
+            proxy_real_delay: -1,
+            proxy_speed_average: -1,
+            proxy_speed_peak: -1,
+        })
+    }
+
+    /// 代理的去重键，出站配置无法解析或没有服务器时为 None。
+    /// the duplicate detection key of the proxy, None when the outbound cannot be parsed or has no server.
+    pub fn key(&self) -> Option<ProxyKey> {
+        get_proxy_key(&serde_json::from_str::<OutboundObject>(&self.proxy_config).ok()?)
+    }
+}
+
+/// 用于判断重复代理的键：小写地址、端口与凭据（id 或密码）。
+/// the key used to detect duplicated proxies: lowercased address, port and credential (id or password).
+pub type ProxyKey = (String, i32, String);
+
+pub fn get_proxy_key(outbound: &OutboundObject) -> Option<ProxyKey> {
+    let (address, port) = outbound.server()?;
+    Some((
+        address.to_lowercase(),
+        port,
+        outbound.credential().unwrap_or_default(),
+    ))
 }
 
-pub trait ProxyTrait {
-    fn restart(&mut self);
-    fn start(&mut self);
+pub trait ProxyTrait: Send {
     fn stop(&mut self);
-    fn check_version(&self) -> Result<String, CoreConfigError>;
-    fn poll_output(&mut self) -> Option<String>;
-    fn generate_config(&self) -> Option<String>;
+    fn check_version(&self) -> Result<String, AppError>;
+    /// 最后 `count` 行不低于 `level` 的核心日志
+    /// the last `count` core log lines at or above `level`
+    fn get_logs(&self, count: usize, level: Option<LogLevel>) -> Vec<LogLine>;
+    /// 设置之后启动核心时使用的可执行文件
+    /// set the executable used the next time the core is started
+    fn set_core_path(&mut self, path: &str);
+    /// 切换到新的出站，尽量不中断已有的连接
+    /// switch to a new outbound, keeping existing connections where possible
+    fn switch_outbound(&mut self, outbound: OutboundObject) -> Result<(), AppError>;
+    /// 设置之后生成配置时使用的路由规则
+    /// set the routing rules used the next time the config is generated
+    fn set_rules(&mut self, rules: Vec<RuleObject>);
+    /// 核心 API 入站的端口，核心启动时选择
+    /// the port of the API inbound of the core, chosen when the core starts
+    fn api_port(&self) -> i32;
+    /// 让正在运行的核心使用最新的路由规则
+    /// make the running core use the latest routing rules
+    fn apply(&mut self) -> Result<(), AppError>;
 }
 
-pub fn use_proxy(proxy: &Proxy) -> Result<impl ProxyTrait, ProxySwitchError> {
+/// 切换到给定的代理。已有核心时沿用它进行热切换，否则用 `core_path` 启动一个新的核心。
+/// switch to the given proxy. An existing core is reused for hot switching, otherwise a new one is started from `core_path`.
+pub fn use_proxy(
+    current: &mut Option<Box<dyn ProxyTrait>>,
+    core_path: &str,
+    proxy: &Proxy,
+    rules: Vec<RuleObject>,
+) -> Result<(), AppError> {
     match proxy.proxy_type.as_str() {
-        "v2ray" => {
-            let mut a = vmess::core::init("/usr/bin/xray");
-            if let Err(i) = files::write(
-                "connection.json",
-                &a.generate_config().unwrap(),
-            ) {
-                return Err(ProxySwitchError {
-                    msg: format!(
-                        "在切换代理时遇到了错误：写入配置文件时错误：{}",
-                        i.to_string()
-                    ),
-                });
-            }
-            a.restart();
-            Ok(a)
+        "v2ray" | "vmess" | "vless" | "trojan" | "shadowsocks" => {
+            let outbound = match serde_json::from_str::<OutboundObject>(&proxy.proxy_config) {
+                Ok(i) => i,
+                Err(e) => {
+                    return Err(AppError::Serialization(format!(
+                        "在切换代理时遇到了错误：读取出站配置时错误：{}",
+                        e
+                    )))
+                }
+            };
+            let core = current.get_or_insert_with(|| Box::new(vmess::core::init(core_path)));
+            core.set_core_path(core_path);
+            core.set_rules(rules);
+            core.switch_outbound(outbound)
         }
-        _ => Err(ProxySwitchError {
-            msg: format!("在切换代理时遇到了错误：不支持的类型：{}", proxy.proxy_type),
-        }),
+        _ => Err(AppError::Unsupported(format!(
+            "在切换代理时遇到了错误：不支持的类型：{}",
+            proxy.proxy_type
+        ))),
     }
 }
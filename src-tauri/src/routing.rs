@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::AppError,
+    vmess::generate::{RuleObject, BLOCK_TAG, DIRECT_TAG, PROXY_TAG},
+};
+
+/// 规则命中后流量的去向。
+/// where the traffic goes when a rule matches.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleOutbound {
+    Proxy,
+    Direct,
+    Block,
+}
+
+impl RuleOutbound {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleOutbound::Proxy => "proxy",
+            RuleOutbound::Direct => "direct",
+            RuleOutbound::Block => "block",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<RuleOutbound> {
+        match value {
+            "proxy" => Some(RuleOutbound::Proxy),
+            "direct" => Some(RuleOutbound::Direct),
+            "block" => Some(RuleOutbound::Block),
+            _ => None,
+        }
+    }
+
+    /// 生成的配置中对应出站的 tag。
+    /// the tag of the matching outbound in the generated config.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            RuleOutbound::Proxy => PROXY_TAG,
+            RuleOutbound::Direct => DIRECT_TAG,
+            RuleOutbound::Block => BLOCK_TAG,
+        }
+    }
+}
+
+/// 规则的匹配条件，与 Xray 路由规则的字段一一对应。流量满足全部非空条件时规则命中。
+/// the conditions of a rule, one for one with the fields of an Xray routing rule.
+/// A rule matches when the traffic meets every non-empty condition.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RuleMatch {
+    /// 域名，支持 `domain:`、`full:`、`regexp:`、`keyword:` 与 `geosite:` 前缀
+    /// domains, supporting the `domain:`, `full:`, `regexp:`, `keyword:` and `geosite:` prefixes
+    #[serde(default)]
+    pub domain: Vec<String>,
+    /// 目标 IP 或 CIDR，支持 `geoip:` 前缀
+    /// destination IPs or CIDRs, supporting the `geoip:` prefix
+    #[serde(default)]
+    pub ip: Vec<String>,
+    /// 目标端口，例如 `53,443,1000-2000`
+    /// destination ports, e.g. `53,443,1000-2000`
+    #[serde(default)]
+    pub port: String,
+    /// `tcp`、`udp` 或 `tcp,udp`
+    /// `tcp`, `udp` or `tcp,udp`
+    #[serde(default)]
+    pub network: String,
+    /// 嗅探出的协议：`http`、`tls`、`quic` 或 `bittorrent`
+    /// the sniffed protocols: `http`, `tls`, `quic` or `bittorrent`
+    #[serde(default)]
+    pub protocol: Vec<String>,
+    /// 来源 IP 或 CIDR
+    /// source IPs or CIDRs
+    #[serde(default)]
+    pub source: Vec<String>,
+    /// 入站的 tag，例如 `SOCK5_IN` 或 `HTTP_IN`
+    /// the tags of the inbounds, e.g. `SOCK5_IN` or `HTTP_IN`
+    #[serde(default)]
+    pub inbound_tag: Vec<String>,
+}
+
+impl RuleMatch {
+    fn is_empty(&self) -> bool {
+        self.domain.is_empty()
+            && self.ip.is_empty()
+            && self.port.is_empty()
+            && self.network.is_empty()
+            && self.protocol.is_empty()
+            && self.source.is_empty()
+            && self.inbound_tag.is_empty()
+    }
+}
+
+/// 用户的路由规则，按 `rule_priority` 从小到大依次匹配，第一条命中的规则决定流量的去向，
+/// 没有规则命中时流量走代理。
+/// a user routing rule. Rules are tried by ascending `rule_priority` and the first matching one decides
+/// where the traffic goes, traffic matching no rule goes through the proxy.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RoutingRule {
+    /// 新建规则时由后端分配
+    /// assigned by the backend when the rule is created
+    #[serde(default)]
+    pub rule_id: String,
+    pub rule_name: String,
+    pub rule_outbound: RuleOutbound,
+    /// 新建规则时排在最后
+    /// new rules are placed last
+    #[serde(default)]
+    pub rule_priority: i64,
+    #[serde(default = "default_enabled")]
+    pub rule_enabled: bool,
+    pub rule_match: RuleMatch,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+const NETWORKS: [&str; 4] = ["", "tcp", "udp", "tcp,udp"];
+const PROTOCOLS: [&str; 4] = ["http", "tls", "quic", "bittorrent"];
+
+/// 校验 `53,443,1000-2000` 形式的端口列表。
+/// validate a port list like `53,443,1000-2000`.
+fn is_valid_port(port: &str) -> bool {
+    port.split(',').all(|i| {
+        let range = match i.trim().split_once('-') {
+            Some((start, end)) => (start.trim().parse::<u16>(), end.trim().parse::<u16>()),
+            None => (i.trim().parse::<u16>(), i.trim().parse::<u16>()),
+        };
+        matches!(range, (Ok(start), Ok(end)) if start <= end)
+    })
+}
+
+impl RoutingRule {
+    /// 检查规则能否被核心接受：至少有一个条件，并且每个条件的格式正确。
+    /// check that the core accepts the rule: it has at least one condition and every condition is well formed.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let rule_match = &self.rule_match;
+        if rule_match.is_empty() {
+            return Err(AppError::InvalidRule(
+                "a rule needs at least one condition".to_string(),
+            ));
+        }
+        let lists = [
+            &rule_match.domain,
+            &rule_match.ip,
+            &rule_match.protocol,
+            &rule_match.source,
+            &rule_match.inbound_tag,
+        ];
+        if lists.iter().any(|i| i.iter().any(|j| j.trim().is_empty())) {
+            return Err(AppError::InvalidRule("empty condition value".to_string()));
+        }
+        if !rule_match.port.is_empty() && !is_valid_port(&rule_match.port) {
+            return Err(AppError::InvalidRule(format!(
+                "invalid port: {}",
+                rule_match.port
+            )));
+        }
+        if !NETWORKS.contains(&rule_match.network.as_str()) {
+            return Err(AppError::InvalidRule(format!(
+                "invalid network: {}",
+                rule_match.network
+            )));
+        }
+        if let Some(i) = rule_match
+            .protocol
+            .iter()
+            .find(|i| !PROTOCOLS.contains(&i.as_str()))
+        {
+            return Err(AppError::InvalidRule(format!("invalid protocol: {}", i)));
+        }
+        Ok(())
+    }
+
+    pub fn to_rule_object(&self) -> RuleObject {
+        let rule_match = self.rule_match.clone();
+        RuleObject {
+            domain: rule_match.domain,
+            ip: rule_match.ip,
+            port: rule_match.port,
+            network: rule_match.network,
+            protocol: rule_match.protocol,
+            source: rule_match.source,
+            inbound_tag: rule_match.inbound_tag,
+            ..RuleObject::new(self.rule_outbound.tag())
+        }
+    }
+}
+
+/// 按顺序把启用的规则转换为核心的路由规则。
+/// convert the enabled rules, in order, into routing rules of the core.
+pub fn get_rule_objects(rules: &[RoutingRule]) -> Vec<RuleObject> {
+    rules
+        .iter()
+        .filter(|i| i.rule_enabled)
+        .map(|i| i.to_rule_object())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_rule(rule_match: RuleMatch) -> RoutingRule {
+        RoutingRule {
+            rule_id: String::new(),
+            rule_name: "rule".to_string(),
+            rule_outbound: RuleOutbound::Direct,
+            rule_priority: 0,
+            rule_enabled: true,
+            rule_match,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = get_rule(RuleMatch {
+            domain: vec!["geosite:cn".to_string()],
+            ip: vec!["geoip:private".to_string(), "10.0.0.0/8".to_string()],
+            port: "53, 443,1000-2000".to_string(),
+            network: "tcp,udp".to_string(),
+            protocol: vec!["bittorrent".to_string()],
+            ..RuleMatch::default()
+        });
+        assert!(valid.validate().is_ok());
+
+        assert!(matches!(
+            get_rule(RuleMatch::default()).validate(),
+            Err(AppError::InvalidRule(_))
+        ));
+        let invalid = [
+            RuleMatch {
+                domain: vec![" ".to_string()],
+                ..RuleMatch::default()
+            },
+            RuleMatch {
+                port: "2000-1000".to_string(),
+                ..RuleMatch::default()
+            },
+            RuleMatch {
+                port: "65536".to_string(),
+                ..RuleMatch::default()
+            },
+            RuleMatch {
+                network: "icmp".to_string(),
+                ..RuleMatch::default()
+            },
+            RuleMatch {
+                protocol: vec!["ftp".to_string()],
+                ..RuleMatch::default()
+            },
+        ];
+        for i in invalid {
+            assert!(get_rule(i).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_get_rule_objects() {
+        let mut rules = vec![
+            get_rule(RuleMatch {
+                domain: vec!["geosite:cn".to_string()],
+                ..RuleMatch::default()
+            }),
+            get_rule(RuleMatch {
+                protocol: vec!["bittorrent".to_string()],
+                ..RuleMatch::default()
+            }),
+            get_rule(RuleMatch {
+                inbound_tag: vec!["HTTP_IN".to_string()],
+                ..RuleMatch::default()
+            }),
+        ];
+        rules[1].rule_outbound = RuleOutbound::Block;
+        rules[2].rule_enabled = false;
+
+        let objects = get_rule_objects(&rules);
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].rule_type, "field");
+        assert_eq!(objects[0].domain, ["geosite:cn"]);
+        assert_eq!(objects[0].outbound_tag, DIRECT_TAG);
+        assert_eq!(objects[1].protocol, ["bittorrent"]);
+        assert_eq!(objects[1].outbound_tag, BLOCK_TAG);
+        assert_eq!(
+            RuleOutbound::parse(RuleOutbound::Proxy.as_str()),
+            Some(RuleOutbound::Proxy)
+        );
+    }
+}
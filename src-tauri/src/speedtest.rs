@@ -0,0 +1,367 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    latency::run_concurrently,
+    proxy::Proxy,
+    vmess::{core::ProbeCore, generate::OutboundObject},
+};
+
+/// 每测完一个代理发送一次的事件。
+/// the event sent every time a proxy has been tested.
+pub const SPEED_TEST_PROGRESS_EVENT: &str = "speed-test-progress";
+
+/// 测试失败时写入的速度。
+/// the speed written when the test failed.
+pub const FAILED_SPEED: i64 = -1;
+
+/// 计算峰值速度时的采样窗口。
+/// the sampling window used to compute the peak speed.
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// 可以在多个任务间共享的取消标记。
+/// a cancellation flag that can be shared between tasks.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// 两个标记是否来自同一次测速。
+    /// whether both tokens belong to the same test.
+    pub fn same_as(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpeedTestOptions {
+    /// 核心可执行文件的路径
+    /// the path of the core executable
+    pub core_path: String,
+    /// 下载测试使用的地址
+    /// the url downloaded by the test
+    pub url: String,
+    /// 每个代理下载的时长
+    /// how long every proxy downloads for
+    pub duration: Duration,
+    /// 同时测试的代理数量
+    /// the number of proxies tested at the same time
+    pub concurrency: usize,
+    /// 启动核心与建立下载连接的超时时间
+    /// the timeout of starting the core and of opening the download
+    pub timeout: Duration,
+}
+
+/// 单个代理测速失败的原因。
+/// why testing a single proxy failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeedTestError {
+    /// 测速被取消，代理的速度未知而不是失败
+    /// the test was cancelled, the speed of the proxy is unknown rather than failed
+    Cancelled,
+    Failed(String),
+}
+
+impl fmt::Display for SpeedTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpeedTestError::Cancelled => write!(f, "cancelled"),
+            SpeedTestError::Failed(i) => write!(f, "{}", i),
+        }
+    }
+}
+
+impl From<String> for SpeedTestError {
+    fn from(e: String) -> Self {
+        SpeedTestError::Failed(e)
+    }
+}
+
+/// 单个代理的测速结果与整体进度，速度单位为字节每秒。
+/// the result of a single proxy together with the overall progress, speeds are in bytes per second.
+#[derive(Debug, Serialize, Clone)]
+pub struct SpeedTestResult {
+    pub proxy_id: String,
+    pub average: i64,
+    pub peak: i64,
+    pub error: Option<String>,
+    /// 测速被取消时为真，此时不应保存速度
+    /// true when the test was cancelled, the speed should not be saved then
+    pub cancelled: bool,
+    pub finished: usize,
+    pub total: usize,
+}
+
+/// 通过 HTTP 代理下载给定的时长，返回平均与峰值速度（字节每秒）。下载提前结束时按实际时长计算。
+/// download through an HTTP proxy for the given duration and return the average and peak speed in bytes per second.
+/// When the download ends early the actual duration is used.
+pub async fn measure_speed(
+    proxy_url: &str,
+    url: &str,
+    duration: Duration,
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<(i64, i64), SpeedTestError> {
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_url).map_err(|e| e.to_string())?)
+        .connect_timeout(timeout)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut response = tokio::time::timeout(timeout, client.get(url).send())
+        .await
+        .map_err(|_| format!("timeout after {}ms", timeout.as_millis()))?
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected status: {}", response.status()).into());
+    }
+    let start = Instant::now();
+    let mut total = 0;
+    let mut peak = 0;
+    let mut window_start = start;
+    let mut window_bytes = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(SpeedTestError::Cancelled);
+        }
+        let remaining = match duration.checked_sub(start.elapsed()) {
+            Some(i) if !i.is_zero() => i,
+            _ => break,
+        };
+        let chunk = match tokio::time::timeout(remaining, response.chunk()).await {
+            Ok(Ok(Some(i))) => i,
+            Ok(Ok(None)) | Err(_) => break,
+            Ok(Err(e)) => return Err(e.to_string().into()),
+        };
+        total += chunk.len() as u64;
+        window_bytes += chunk.len() as u64;
+        let window = window_start.elapsed();
+        if window >= PEAK_WINDOW {
+            peak = peak.max(get_speed(window_bytes, window));
+            window_start = Instant::now();
+            window_bytes = 0;
+        }
+    }
+    let elapsed = start.elapsed();
+    let average = get_speed(total, elapsed);
+    // 不足一个采样窗口的下载以平均速度作为峰值。
+    // downloads shorter than one sampling window use the average as the peak.
+    Ok((average, peak.max(average)))
+}
+
+fn get_speed(bytes: u64, elapsed: Duration) -> i64 {
+    let seconds = elapsed.as_secs_f64().max(0.001);
+    (bytes as f64 / seconds) as i64
+}
+
+/// 以有限的并发为每个代理启动临时核心并测速，每完成一个就回调一次。取消后尚未完成的代理的结果都标记为已取消。
+/// start a temporary core for every proxy and test its speed with bounded concurrency, calling back once per finished proxy.
+/// After cancellation the results of every unfinished proxy are marked as cancelled.
+pub async fn test_speed<F>(
+    proxies: &[Proxy],
+    options: &SpeedTestOptions,
+    cancel: &CancelToken,
+    mut on_result: F,
+) where
+    F: FnMut(SpeedTestResult),
+{
+    let concurrency = options.concurrency;
+    let options = options.clone();
+    let cancel = cancel.clone();
+    let measure_proxy = move |proxy: Proxy| {
+        let options = options.clone();
+        let cancel = cancel.clone();
+        async move {
+            if cancel.is_cancelled() {
+                return Err(SpeedTestError::Cancelled);
+            }
+            let outbound = serde_json::from_str::<OutboundObject>(&proxy.proxy_config)
+                .map_err(|e| SpeedTestError::Failed(e.to_string()))?;
+            let probe = ProbeCore::start(&options.core_path, &outbound, options.timeout).await?;
+            let result = measure_speed(
+                &probe.proxy_url(),
+                &options.url,
+                options.duration,
+                options.timeout,
+                &cancel,
+            )
+            .await;
+            probe.stop().await;
+            result
+        }
+    };
+    run_concurrently(
+        proxies,
+        concurrency,
+        measure_proxy,
+        |proxy_id, result, finished, total| {
+            let ((average, peak), error) = match result {
+                Ok(i) => (i, None),
+                Err(e) => ((FAILED_SPEED, FAILED_SPEED), Some(e)),
+            };
+            on_result(SpeedTestResult {
+                proxy_id,
+                average,
+                peak,
+                cancelled: error == Some(SpeedTestError::Cancelled),
+                error: error.map(|i| i.to_string()),
+                finished,
+                total,
+            })
+        },
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// 一个 HTTP 代理替身：对任何请求都以固定速率持续返回数据，总量为 `size` 字节。
+    /// an HTTP proxy stand-in answering any request with `size` bytes sent at a steady pace.
+    async fn serve_download(size: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", size);
+                    if stream.write_all(header.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    let chunk = [0u8; 16 * 1024];
+                    let mut sent = 0;
+                    while sent < size {
+                        let n = chunk.len().min(size - sent);
+                        if stream.write_all(&chunk[..n]).await.is_err() {
+                            return;
+                        }
+                        sent += n;
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                });
+            }
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    #[tokio::test]
+    async fn test_measure_speed() {
+        let proxy_url = serve_download(64 * 1024).await;
+        let (average, peak) = measure_speed(
+            &proxy_url,
+            "http://speed.invalid/down",
+            Duration::from_secs(3),
+            Duration::from_secs(3),
+            &CancelToken::default(),
+        )
+        .await
+        .unwrap();
+        assert!(average > 0);
+        assert!(peak >= average);
+    }
+
+    #[tokio::test]
+    async fn test_measure_speed_duration() {
+        // 数据量远大于测试时长内能传输的量，测试应按时结束。
+        // far more data than fits in the test duration, so the test must stop on time.
+        let proxy_url = serve_download(1024 * 1024 * 1024).await;
+        let start = Instant::now();
+        let (average, peak) = measure_speed(
+            &proxy_url,
+            "http://speed.invalid/down",
+            Duration::from_millis(1500),
+            Duration::from_secs(3),
+            &CancelToken::default(),
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(average > 0);
+        assert!(peak >= average);
+    }
+
+    #[tokio::test]
+    async fn test_measure_speed_cancel() {
+        let proxy_url = serve_download(1024 * 1024 * 1024).await;
+        let cancel = CancelToken::default();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let start = Instant::now();
+        let result = measure_speed(
+            &proxy_url,
+            "http://speed.invalid/down",
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+            &cancel,
+        )
+        .await;
+        assert_eq!(result, Err(SpeedTestError::Cancelled));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_speed_cancelled() {
+        let (outbound, name) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.com:443?security=tls#trojan",
+        )
+        .unwrap();
+        let proxies = [
+            Proxy::new(name.clone(), &outbound, "default").unwrap(),
+            Proxy::new(name, &outbound, "default").unwrap(),
+        ];
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let mut results = Vec::new();
+        test_speed(
+            &proxies,
+            &SpeedTestOptions {
+                core_path: "/nonexistent/xray".to_string(),
+                url: "http://speed.invalid/down".to_string(),
+                duration: Duration::from_secs(1),
+                concurrency: 1,
+                timeout: Duration::from_secs(1),
+            },
+            &cancel,
+            |i| results.push(i),
+        )
+        .await;
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result.average, FAILED_SPEED);
+            assert!(result.cancelled);
+            assert_eq!(result.error.as_deref(), Some("cancelled"));
+        }
+    }
+}
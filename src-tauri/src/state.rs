@@ -0,0 +1,234 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Mutex, MutexGuard},
+};
+
+use rusqlite::Connection;
+use tauri::{AppHandle, Manager};
+
+use crate::{
+    error::AppError,
+    proxy::{self, Proxy, ProxyTrait},
+    speedtest::CancelToken,
+    vmess::{
+        discovery::CorePathCache,
+        generate::{get_api_server, RuleObject},
+        log::{LogLevel, LogLine},
+        traffic::{self, Monitor},
+    },
+};
+
+/// 由 Tauri 管理、在命令与后台任务之间共享的应用状态。订阅自动更新与流量监视也通过它访问数据库和核心，
+/// 不会打开自己的连接。
+/// the app state managed by Tauri and shared between commands and background tasks. The subscription auto
+/// update and the traffic monitor reach the database and the core through it and never open connections of their own.
+pub struct AppState {
+    /// 数据库连接，同一时间只有一个命令可以使用
+    /// the database connection, used by one command at a time
+    pub database: Mutex<Connection>,
+    /// 当前的核心，切换代理等耗时操作期间其他命令异步等待
+    /// the current core, other commands wait asynchronously during slow operations such as switching proxies
+    pub core: tokio::sync::Mutex<CoreManager>,
+    /// 正在进行的测速的取消标记
+    /// the cancellation token of the running speed test
+    pub speed_test: Mutex<Option<CancelToken>>,
+    /// 查找过的核心路径，设置的 core_path 变化时才重新查找
+    /// the core path found before, looked up again only when the configured core_path changes
+    pub core_path: CorePathCache,
+}
+
+impl AppState {
+    pub fn new(database: Connection, core: Box<dyn ProxyTrait>) -> AppState {
+        AppState {
+            database: Mutex::new(database),
+            core: tokio::sync::Mutex::new(CoreManager {
+                proxy: Some(core),
+                traffic: None,
+            }),
+            speed_test: Mutex::new(None),
+            core_path: CorePathCache::default(),
+        }
+    }
+
+    /// 获取数据库连接，锁被污染时返回错误而不是 panic。
+    /// lock the database connection, returning an error instead of panicking when the lock is poisoned.
+    pub fn database(&self) -> Result<MutexGuard<'_, Connection>, AppError> {
+        Ok(self.database.lock()?)
+    }
+}
+
+/// 当前的核心以及它的流量监视。
+/// the current core together with its traffic monitor.
+pub struct CoreManager {
+    proxy: Option<Box<dyn ProxyTrait>>,
+    traffic: Option<Monitor>,
+}
+
+impl CoreManager {
+    /// 使用给定的路由规则切换到给定的代理，并重新开始监视它的流量。
+    /// 切换前先把核心中尚未读取的流量算到之前的代理上。
+    /// switch to the given proxy with the given routing rules and start monitoring its traffic afresh.
+    /// The traffic not yet read from the core is added to the previous proxy before switching.
+    pub async fn switch(
+        &mut self,
+        app: AppHandle,
+        core_path: String,
+        proxy: &Proxy,
+        rules: Vec<RuleObject>,
+    ) -> Result<(), AppError> {
+        if let Some(i) = self.traffic.take() {
+            i.finish(&app.state::<AppState>()).await;
+        }
+        let path = core_path.clone();
+        let next = proxy.clone();
+        self.run_blocking(move |i| proxy::use_proxy(i, &path, &next, rules))
+            .await?;
+        let server = self.api_server().unwrap_or_default();
+        self.traffic = Some(traffic::spawn_monitor(
+            app,
+            core_path,
+            server,
+            proxy.proxy_id.clone(),
+        ));
+        Ok(())
+    }
+
+    /// 当前核心的 API 地址，还没有核心时为 None。
+    /// the API address of the current core, None while there is no core.
+    pub fn api_server(&self) -> Option<String> {
+        self.proxy.as_ref().map(|i| get_api_server(i.api_port()))
+    }
+
+    /// 在阻塞线程池中对当前核心执行 `f`。写入配置、调用核心的 API 与启停进程都是阻塞的，
+    /// 不能在异步运行时的线程上进行；执行期间核心仍由调用者持有的锁保护。
+    /// run `f` on the current core in the blocking thread pool. Writing the config, calling the core API and
+    /// starting or stopping the process all block and must stay off the threads of the async runtime;
+    /// the core stays guarded by the lock held by the caller meanwhile.
+    /// `f` 发生 panic 时核心仍然会被放回，不会丢失正在运行的进程。
+    /// the core is put back even when `f` panics, so the running process is never lost.
+    async fn run_blocking<F>(&mut self, f: F) -> Result<(), AppError>
+    where
+        F: FnOnce(&mut Option<Box<dyn ProxyTrait>>) -> Result<(), AppError> + Send + 'static,
+    {
+        let mut current = self.proxy.take();
+        let (current, result) = tokio::task::spawn_blocking(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut current)))
+                .unwrap_or_else(|_| Err(AppError::Internal("core task panicked".to_string())));
+            (current, result)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("core task failed: {}", e)))?;
+        self.proxy = current;
+        result
+    }
+
+    /// 更新路由规则，核心正在运行时立即生效。
+    /// update the routing rules, taking effect immediately while the core runs.
+    pub async fn set_rules(&mut self, rules: Vec<RuleObject>) -> Result<(), AppError> {
+        self.run_blocking(|current| match current {
+            Some(i) => {
+                i.set_rules(rules);
+                i.apply()
+            }
+            None => Ok(()),
+        })
+        .await
+    }
+
+    /// 重新生成正在运行的核心的配置，例如在路由模式变化之后。
+    /// regenerate the config of the running core, e.g. after the routing mode changed.
+    pub async fn apply(&mut self) -> Result<(), AppError> {
+        self.run_blocking(|current| match current {
+            Some(i) => i.apply(),
+            None => Ok(()),
+        })
+        .await
+    }
+
+    pub fn get_logs(&self, count: usize, level: Option<LogLevel>) -> Vec<LogLine> {
+        match &self.proxy {
+            Some(i) => i.get_logs(count, level),
+            None => Vec::new(),
+        }
+    }
+
+    /// 停止流量监视与核心。
+    /// stop the traffic monitor and the core.
+    pub fn shutdown(&mut self) {
+        if let Some(i) = self.traffic.take() {
+            i.abort();
+        }
+        if let Some(mut i) = self.proxy.take() {
+            i.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::vmess::core;
+
+    #[test]
+    fn test_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE counter (value INTEGER)", [])
+            .unwrap();
+        conn.execute("INSERT INTO counter VALUES (0)", []).unwrap();
+        let state = Arc::new(AppState::new(conn, Box::new(core::init(""))));
+
+        // 并发的命令依次使用同一个连接。
+        // concurrent commands take turns on the same connection.
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        state
+                            .database()
+                            .unwrap()
+                            .execute("UPDATE counter SET value = value + 1", [])
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for i in handles {
+            i.join().unwrap();
+        }
+        let value: i64 = state
+            .database()
+            .unwrap()
+            .query_row("SELECT value FROM counter", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(value, 400);
+
+        let mut core = state.core.try_lock().unwrap();
+        assert!(core.get_logs(10, None).is_empty());
+        // 核心启动之前还没有选择 API 端口。
+        // no API port is chosen before the core starts.
+        assert_eq!(core.api_server().unwrap(), "127.0.0.1:0");
+        core.shutdown();
+        assert!(core.proxy.is_none());
+        assert!(core.api_server().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_blocking_panic() {
+        let mut core = CoreManager {
+            proxy: Some(Box::new(core::init(""))),
+            traffic: None,
+        };
+        // 操作核心时发生 panic 返回错误，核心仍然保留。
+        // a panic while working on the core is an error and the core is kept.
+        let result = core.run_blocking(|_| panic!("broken core")).await;
+        assert_eq!(
+            result,
+            Err(AppError::Internal("core task panicked".to_string()))
+        );
+        assert!(core.proxy.is_some());
+        assert!(core.apply().await.is_ok());
+    }
+}
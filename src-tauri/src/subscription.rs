@@ -0,0 +1,407 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::{depositor, error::AppError, proxy::Proxy, state::AppState, vmess::generate};
+
+/// 订阅刷新完成后发送给前端的事件名。
+/// event emitted to the frontend after a subscription was refreshed.
+pub const SUBSCRIPTION_UPDATED_EVENT: &str = "subscription-updated";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Subscription {
+    pub subscription_id: String,
+    pub subscription_name: String,
+    pub subscription_url: String,
+    pub subscription_user_agent: String,
+    /// 自动更新间隔（秒），0 表示不自动更新。
+    /// auto update interval in seconds, 0 disables auto update.
+    pub subscription_update_interval: i64,
+    /// 最后一次成功更新的时间（unix 秒）。
+    /// unix seconds of the last successful update.
+    pub subscription_last_update: i64,
+}
+
+impl Subscription {
+    /// 是否已到自动更新的时间。
+    /// whether the subscription is due for an auto update.
+    pub fn is_due(&self, now: i64) -> bool {
+        self.subscription_update_interval > 0
+            && now - self.subscription_last_update >= self.subscription_update_interval
+    }
+}
+
+/// 服务商通过 `subscription-userinfo` 响应头下发的流量与到期信息，单位为字节。
+/// quota and expiry sent by the provider in the `subscription-userinfo` header, in bytes.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct SubscriptionUserinfo {
+    pub subscription_id: String,
+    pub upload: i64,
+    pub download: i64,
+    pub total: i64,
+    /// 到期时间（unix 秒），0 表示永不过期。
+    /// expiry in unix seconds, 0 means it never expires.
+    pub expire: i64,
+    /// 获取该信息的时间（unix 秒）。
+    /// unix seconds when this was fetched.
+    pub update_time: i64,
+}
+
+impl SubscriptionUserinfo {
+    /// 解析 `upload=..; download=..; total=..; expire=..` 格式的响应头，没有任何已知字段时返回 None。
+    /// parse a `upload=..; download=..; total=..; expire=..` header, or None when no known field is present.
+    pub fn parse(subscription_id: &str, header: &str) -> Option<SubscriptionUserinfo> {
+        let mut userinfo = SubscriptionUserinfo {
+            subscription_id: subscription_id.to_string(),
+            update_time: now(),
+            ..Default::default()
+        };
+        let mut found = false;
+        for (key, value) in header.split(';').filter_map(|i| i.split_once('=')) {
+            // 部分服务商会以浮点数或科学计数法下发数值。
+            // some providers send floats or scientific notation.
+            let value = match value.trim().parse::<i64>() {
+                Ok(i) => i,
+                Err(_) => match value.trim().parse::<f64>() {
+                    Ok(i) => i as i64,
+                    Err(_) => continue,
+                },
+            };
+            match key.trim().to_lowercase().as_str() {
+                "upload" => userinfo.upload = value,
+                "download" => userinfo.download = value,
+                "total" => userinfo.total = value,
+                "expire" => userinfo.expire = value,
+                _ => continue,
+            }
+            found = true;
+        }
+        if found {
+            Some(userinfo)
+        } else {
+            None
+        }
+    }
+}
+
+/// 一次订阅刷新得到的内容。
+/// the content obtained by refreshing a subscription.
+#[derive(Debug)]
+pub struct SubscriptionContent {
+    pub proxies: Vec<Proxy>,
+    pub skipped: usize,
+    pub userinfo: Option<SubscriptionUserinfo>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SubscriptionUpdated {
+    pub subscription_id: String,
+    pub proxy_count: usize,
+    pub skipped: usize,
+    pub userinfo: Option<SubscriptionUserinfo>,
+    pub error: Option<AppError>,
+}
+
+/// 当前的 unix 时间（秒）。
+/// current unix time in seconds.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|i| i.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// 下载订阅内容，同时读取 `subscription-userinfo` 响应头。
+/// download the raw subscription content together with its `subscription-userinfo` header.
+pub async fn fetch(
+    subscription: &Subscription,
+) -> Result<(String, Option<SubscriptionUserinfo>), AppError> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .user_agent(subscription.subscription_user_agent.as_str())
+        .build()?;
+    let response = client
+        .get(&subscription.subscription_url)
+        .send()
+        .await
+        .and_then(|i| i.error_for_status())?;
+    let userinfo = response
+        .headers()
+        .get("subscription-userinfo")
+        .and_then(|i| i.to_str().ok())
+        .and_then(|i| SubscriptionUserinfo::parse(&subscription.subscription_id, i));
+    let content = response.text().await?;
+    Ok((content, userinfo))
+}
+
+/// 解码订阅内容并逐行解析分享链接，返回解析出的代理以及无法解析的行数。
+/// decode the subscription content and parse every share link in it,
+/// returning the proxies and the number of lines that could not be parsed.
+pub fn parse_content(content: &str, proxy_group: &str) -> (Vec<Proxy>, usize) {
+    let decoded = generate::decode_base64_lenient(content)
+        .ok()
+        .and_then(|i| String::from_utf8(i).ok())
+        .filter(|i| i.contains("://"));
+    let content = decoded.as_deref().unwrap_or(content);
+    let mut proxies = Vec::new();
+    let mut skipped = 0;
+    for line in content.lines().map(|i| i.trim()).filter(|i| !i.is_empty()) {
+        let proxy = generate::parse_share_link(line)
+            .ok()
+            .and_then(|(outbound, name)| Proxy::new(name, &outbound, proxy_group).ok());
+        match proxy {
+            Some(i) => proxies.push(i),
+            None => skipped += 1,
+        }
+    }
+    (proxies, skipped)
+}
+
+/// 下载并解析订阅。
+/// download and parse a subscription.
+pub async fn refresh(subscription: &Subscription) -> Result<SubscriptionContent, AppError> {
+    let (content, userinfo) = fetch(subscription).await?;
+    let (proxies, skipped) = parse_content(&content, &subscription.subscription_id);
+    // 不要用一个空的响应清空已有的分组。
+    // never wipe an existing group because of an empty response.
+    if proxies.is_empty() {
+        return Err(AppError::Subscription(format!(
+            "no proxy found, {} lines skipped",
+            skipped
+        )));
+    }
+    Ok(SubscriptionContent {
+        proxies,
+        skipped,
+        userinfo,
+    })
+}
+
+/// 替换该订阅分组下的代理，并记录更新时间与流量信息。
+/// replace the proxies of the subscription group and record the update time and quota.
+fn save(
+    conn: &mut Connection,
+    subscription: &Subscription,
+    content: &SubscriptionContent,
+) -> Result<(), AppError> {
+    depositor::replace_group_proxies(conn, &subscription.subscription_id, &content.proxies)?;
+    depositor::set_subscription_last_update(conn, &subscription.subscription_id, now())?;
+    if let Some(userinfo) = &content.userinfo {
+        depositor::set_subscription_userinfo(conn, userinfo)?;
+    }
+    Ok(())
+}
+
+/// 将刷新结果写入数据库，并生成发送给前端的事件。写入失败时事件中带有该错误。
+/// store a refresh result and build the event reported to the frontend.
+/// The event carries the error when storing fails.
+pub fn store(
+    conn: &mut Connection,
+    subscription: &Subscription,
+    result: Result<SubscriptionContent, AppError>,
+) -> SubscriptionUpdated {
+    let result = result.and_then(|content| save(conn, subscription, &content).map(|_| content));
+    match result {
+        Ok(content) => SubscriptionUpdated {
+            subscription_id: subscription.subscription_id.clone(),
+            proxy_count: content.proxies.len(),
+            skipped: content.skipped,
+            userinfo: content.userinfo,
+            error: None,
+        },
+        Err(e) => store_failed(subscription, e),
+    }
+}
+
+/// 生成刷新失败时发送给前端的事件。
+/// build the event reported to the frontend when a refresh failed.
+fn store_failed(subscription: &Subscription, error: AppError) -> SubscriptionUpdated {
+    SubscriptionUpdated {
+        subscription_id: subscription.subscription_id.clone(),
+        proxy_count: 0,
+        skipped: 0,
+        userinfo: None,
+        error: Some(error),
+    }
+}
+
+/// 读取已到更新时间的订阅。
+/// read the subscriptions due for an auto update.
+fn get_due_list(state: &AppState) -> Result<Vec<Subscription>, AppError> {
+    let now = now();
+    Ok(depositor::get_subscription_list(&*state.database()?)?
+        .into_iter()
+        .filter(|i| i.is_due(now))
+        .collect())
+}
+
+/// 启动后台任务，每分钟检查一次并刷新到期的订阅。任务使用应用状态中的数据库连接，
+/// 刷新订阅期间不占用该连接。
+/// spawn the background task that refreshes due subscriptions every minute. The task uses the
+/// database connection of the app state and does not hold it while a subscription is downloading.
+pub fn spawn_auto_update(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let due = get_due_list(&app.state::<AppState>()).unwrap_or_default();
+            for subscription in due {
+                let result = refresh(&subscription).await;
+                let event = match app.state::<AppState>().database() {
+                    Ok(mut conn) => store(&mut conn, &subscription, result),
+                    Err(e) => store_failed(&subscription, e),
+                };
+                app.emit_all(SUBSCRIPTION_UPDATED_EVENT, event).ok();
+            }
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const LINKS: &str = "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@example.com:443?encryption=none&security=tls&type=ws&path=%2F#vless
+trojan://password@example.com:443?security=tls&type=tcp#trojan
+
+ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888#ss
+not a link
+";
+
+    fn get_subscription(url: String) -> Subscription {
+        Subscription {
+            subscription_id: "group".to_string(),
+            subscription_name: "test".to_string(),
+            subscription_url: url,
+            subscription_user_agent: "v2neko-test".to_string(),
+            subscription_update_interval: 3600,
+            subscription_last_update: 0,
+        }
+    }
+
+    /// 在本地启动一个只响应一次请求的 HTTP 服务，返回其地址与收到的请求。
+    /// serve a single HTTP response on a local port, returning its url and the received request.
+    async fn serve_once(
+        headers: &'static str,
+        body: String,
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/sub", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                headers,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_parse_content() {
+        let encoded = general_purpose::STANDARD.encode(LINKS);
+        for content in [LINKS.to_string(), encoded] {
+            let (proxies, skipped) = parse_content(&content, "group");
+            assert_eq!(skipped, 1);
+            let types: Vec<&str> = proxies.iter().map(|i| i.proxy_type.as_str()).collect();
+            assert_eq!(types, ["vless", "trojan", "shadowsocks"]);
+            assert!(proxies.iter().all(|i| i.proxy_group == "group"));
+            assert_eq!(proxies[2].proxy_name, "ss");
+        }
+    }
+
+    #[test]
+    fn test_parse_content_incomplete_vmess() {
+        // 订阅来自远端，缺少字段或无法解码的链接只能被跳过，不能 panic。
+        // subscriptions are remote input, links with missing fields or bad encoding are skipped instead of panicking.
+        let vmess = |json: &str| format!("vmess://{}", general_purpose::STANDARD.encode(json));
+        let content = [
+            vmess(r#"{"v":"2","ps":"h2","add":"example.com","port":443,"id":"c7c1c985-9421-4d0f-fa19-0efda80343af","aid":0,"net":"h2","type":"none","tls":"tls"}"#),
+            vmess(r#"{"v":"2","ps":"quic","add":"example.com","port":443,"id":"c7c1c985-9421-4d0f-fa19-0efda80343af","aid":0,"net":"quic","type":"none","tls":"tls"}"#),
+            format!("vmess://{}", general_purpose::STANDARD.encode([0xff, 0xfe, 0xfd])),
+        ]
+        .join("\n");
+        let (proxies, skipped) = parse_content(&content, "group");
+        assert_eq!(skipped, 1);
+        let names: Vec<&str> = proxies.iter().map(|i| i.proxy_name.as_str()).collect();
+        assert_eq!(names, ["h2", "quic"]);
+    }
+
+    #[test]
+    fn test_parse_userinfo() {
+        let userinfo = SubscriptionUserinfo::parse(
+            "group",
+            "upload=455727941; download=6174315083; total=1073741824000; expire=1671815872",
+        )
+        .unwrap();
+        assert_eq!(userinfo.upload, 455727941);
+        assert_eq!(userinfo.download, 6174315083);
+        assert_eq!(userinfo.total, 1073741824000);
+        assert_eq!(userinfo.expire, 1671815872);
+
+        let userinfo =
+            SubscriptionUserinfo::parse("group", " Upload=0;download=1.5e3 ;total=2048;foo=bar")
+                .unwrap();
+        assert_eq!(userinfo.download, 1500);
+        assert_eq!(userinfo.total, 2048);
+        assert_eq!(userinfo.expire, 0);
+
+        assert!(SubscriptionUserinfo::parse("group", "").is_none());
+        assert!(SubscriptionUserinfo::parse("group", "foo=1; bar").is_none());
+    }
+
+    #[test]
+    fn test_is_due() {
+        let mut subscription = get_subscription(String::new());
+        subscription.subscription_last_update = 1000;
+        assert!(!subscription.is_due(1000 + 3599));
+        assert!(subscription.is_due(1000 + 3600));
+        subscription.subscription_update_interval = 0;
+        assert!(!subscription.is_due(i64::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let (url, handle) = serve_once(
+            "Subscription-Userinfo: upload=1024; download=2048; total=10737418240; expire=1735660800\r\n",
+            general_purpose::STANDARD.encode(LINKS),
+        )
+        .await;
+        let content = refresh(&get_subscription(url)).await.unwrap();
+        assert_eq!(content.proxies.len(), 3);
+        assert_eq!(content.skipped, 1);
+        let userinfo = content.userinfo.unwrap();
+        assert_eq!(userinfo.subscription_id, "group");
+        assert_eq!(
+            (
+                userinfo.upload,
+                userinfo.download,
+                userinfo.total,
+                userinfo.expire
+            ),
+            (1024, 2048, 10737418240, 1735660800)
+        );
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("GET /sub HTTP/1.1"));
+        assert!(request.to_lowercase().contains("user-agent: v2neko-test"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_empty() {
+        let (url, _handle) = serve_once("", "<html></html>".to_string()).await;
+        assert!(refresh(&get_subscription(url)).await.is_err());
+    }
+}
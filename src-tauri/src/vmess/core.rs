@@ -1,78 +1,487 @@
-use crate::{error::CoreConfigError, proxy::ProxyTrait};
+use crate::{
+    config::{self, AppConfig},
+    error::AppError,
+    files,
+    proxy::ProxyTrait,
+};
 use std::{
-    io::{BufReader, Read},
+    fs, io,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use super::generate::generate;
+use super::{
+    generate::{
+        choose_api_port, generate, generate_probe, get_api_server, OutboundObject, Outbounds,
+        RuleObject, PROXY_TAG,
+    },
+    log::{LogBuffer, LogLevel, LogLine},
+    shutdown::{self, PidFile},
+    supervisor::{self, CoreState, CoreStateChanged, Process, StateListener, SupervisorOptions},
+};
 
 pub struct Core {
     path: String,
-    child: Option<Child>,
+    /// 配置文件的绝对路径
+    /// the absolute path of the config file
+    config_path: PathBuf,
+    process: Arc<Mutex<Process>>,
+    outbound: Option<OutboundObject>,
+    /// 生成配置时使用的用户路由规则
+    /// the user routing rules used when generating the config
+    rules: Vec<RuleObject>,
+    /// 正在运行的核心所使用的配置
+    /// the config the running core was started with
+    running: Option<serde_json::Value>,
+    /// API 入站的端口，核心启动时选择一次，热切换与重启都沿用它
+    /// the port of the API inbound, chosen once when the core starts and kept across hot switches and restarts
+    api_port: i32,
+    /// 设置后核心意外退出时会被自动重启，状态变化通知给监听者
+    /// when set the core is restarted after exiting unexpectedly and state changes are reported to the listener
+    supervisor: Option<(SupervisorOptions, StateListener)>,
+    logs: LogBuffer,
+    pid_file: Option<PidFile>,
 }
 
 pub fn init(path: &str) -> Core {
+    let logs = LogBuffer::default();
     Core {
         path: path.to_owned(),
-        child: None,
+        config_path: get_session_config_path(),
+        process: Arc::new(Mutex::new(Process::new(logs.clone()))),
+        outbound: None,
+        rules: Vec::new(),
+        running: None,
+        api_port: 0,
+        supervisor: None,
+        logs,
+        pid_file: None,
     }
 }
 
-impl ProxyTrait for Core {
-    /// Restart the
-    fn restart(self: &mut Core) {
-        if let Some(_) = &self.child {
-            Self::stop(self);
-        }
-        Self::start(self);
-    }
-    // fn start(&mut self) -> Child {
-    //     Command::new(&self.path)
-    //         .args(["-config", "connection.json"])
-    //         .stdout(Stdio::piped())
-    //         .spawn()
-    //         .unwrap()
-
-    // }
-
-    fn start(&mut self) {
-        let child = Command::new(&self.path)
-            .args(["-config", "connection.json"])
-            .stdout(Stdio::piped())
-            .spawn()
-            .unwrap();
-        self.child = Some(child);
+/// 去掉代理出站后的配置。两份配置只有代理出站不同时可以通过 API 热切换。
+/// the config without the proxy outbounds. Two configs differing only in them can be hot switched through the API.
+fn without_proxy(config: &serde_json::Value) -> serde_json::Value {
+    let mut config = config.clone();
+    if let Some(outbounds) = config["outbounds"].as_array_mut() {
+        outbounds.retain(|i| i["tag"] != PROXY_TAG);
     }
+    config
+}
 
+/// 配置中的代理出站。
+/// the proxy outbounds of the config.
+fn proxy_outbounds(config: &serde_json::Value) -> Vec<serde_json::Value> {
+    config["outbounds"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|i| i["tag"] == PROXY_TAG)
+        .cloned()
+        .collect()
+}
+
+/// 本次运行使用的配置文件，位于配置目录中并以进程号区分，配置目录不可用时放在临时目录。
+/// the config file of this session, in the config directory and named after the process id,
+/// or in the temp directory when the config directory is unavailable.
+fn get_session_config_path() -> PathBuf {
+    let name = format!("connection-{}.json", std::process::id());
+    files::get_path(&name).unwrap_or_else(|_| std::env::temp_dir().join(name))
+}
+
+fn spawn_child(path: &str, config_path: &Path, pid_file: Option<&PidFile>) -> io::Result<Child> {
+    let child = Command::new(path)
+        .arg("-config")
+        .arg(config_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(i) = pid_file {
+        i.write(child.id(), config_path).ok();
+    }
+    Ok(child)
+}
+
+impl Core {
+    /// 设置启动核心时传入的配置文件路径。
+    /// set the config file passed to the core when starting it.
+    pub fn set_config_path(&mut self, config_path: &Path) {
+        self.config_path = config_path.to_path_buf();
+    }
+
+    /// 启动核心时把进程号记录到 `pid_file` 中，停止时删除。
+    /// record the pid in `pid_file` when starting the core and remove it when stopping.
+    pub fn set_pid_file(&mut self, pid_file: PidFile) {
+        self.pid_file = Some(pid_file);
+    }
+
+    /// 原子地写入配置文件，返回写入的绝对路径，启动核心时使用的正是这个路径。
+    /// write the config file atomically and return the absolute path written, which is exactly the path the
+    /// core is started with.
+    pub fn write_config(&self, config: &str) -> io::Result<PathBuf> {
+        files::write_atomic(&self.config_path, config)?;
+        Ok(self.config_path.clone())
+    }
+
+    /// 监视核心进程，意外退出时自动重启，并把状态变化通知给 `listener`。
+    /// supervise the core process, restarting it after unexpected exits and reporting state changes to `listener`.
+    pub fn set_supervisor<F>(&mut self, options: SupervisorOptions, listener: F)
+    where
+        F: Fn(CoreStateChanged) + Send + Sync + 'static,
+    {
+        self.supervisor = Some((options, Arc::new(listener)));
+    }
+
+    /// 核心每输出一行日志都交给 `listener`。
+    /// hand every log line printed by the core to `listener`.
+    pub fn set_log_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(&LogLine) + Send + Sync + 'static,
+    {
+        self.logs.set_listener(Arc::new(listener));
+    }
+
+    fn notify(&self, changed: CoreStateChanged) {
+        if let Some((_, listener)) = &self.supervisor {
+            listener(changed);
+        }
+    }
+
+    /// 启动核心进程，失败时返回错误而不是 panic。
+    /// spawn the core process, returning an error instead of panicking.
+    pub fn spawn(&mut self) -> io::Result<()> {
+        self.notify(CoreStateChanged::new(CoreState::Starting));
+        let child = match spawn_child(&self.path, &self.config_path, self.pid_file.as_ref()) {
+            Ok(i) => i,
+            Err(e) => {
+                self.notify(CoreStateChanged {
+                    state: CoreState::Crashed,
+                    reason: Some(format!("failed to start core: {}", e)),
+                    restarts: 0,
+                });
+                return Err(e);
+            }
+        };
+        // 每次启动都使用新的共享状态，旧的监视线程随旧状态一起退出。
+        // every start uses fresh shared state, the old watching thread exits together with the old state.
+        let process = Arc::new(Mutex::new(Process::new(self.logs.clone())));
+        process.lock().unwrap().attach(&process, child);
+        self.process = process.clone();
+        if let Some((options, listener)) = &self.supervisor {
+            let path = self.path.clone();
+            let config_path = self.config_path.clone();
+            let pid_file = self.pid_file.clone();
+            supervisor::watch(process, options.clone(), listener.clone(), move || {
+                spawn_child(&path, &config_path, pid_file.as_ref())
+            });
+        }
+        self.notify(CoreStateChanged::new(CoreState::Running));
+        Ok(())
+    }
+
+    /// 核心进程是否仍在运行。
+    /// whether the core process is still running.
+    pub fn is_running(&mut self) -> bool {
+        match &mut self.process.lock().unwrap().child {
+            Some(i) => matches!(i.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    /// 用 `config`、当前的 API 端口与路由规则为 `outbound` 生成核心的配置。
+    /// generate the core config for `outbound` from `config`, the current API port and routing rules.
+    fn generate_with(&self, config: &AppConfig, outbound: &OutboundObject) -> String {
+        generate(
+            config,
+            self.api_port,
+            &Outbounds::new(vec![outbound.clone()]),
+            &self.rules,
+        )
+    }
+
+    /// 结束核心进程但保留配置文件，随后用同一份配置重新启动时使用。
+    /// stop the core process but keep the config file, used when starting again from the same config.
+    fn stop_process(&mut self) {
+        self.running = None;
+        let child = {
+            let mut process = self.process.lock().unwrap();
+            process.stopped = true;
+            process.child.take()
+        };
+        if let Some(mut i) = child {
+            shutdown::terminate(&mut i, shutdown::STOP_TIMEOUT);
+            if let Some(i) = &self.pid_file {
+                i.remove();
+            }
+            self.notify(CoreStateChanged::new(CoreState::Stopped));
+        }
+    }
+
+    /// 不等待地强制结束核心进程并删除配置文件，用于不能阻塞的地方，进程在后台线程中回收。
+    /// kill the core process without waiting and remove the config file, used where blocking is not allowed.
+    /// The process is reaped in a background thread.
+    pub fn kill(&mut self) {
+        self.running = None;
+        let child = {
+            let mut process = self.process.lock().unwrap();
+            process.stopped = true;
+            process.child.take()
+        };
+        if let Some(mut i) = child {
+            i.kill().ok();
+            thread::spawn(move || i.wait());
+            if let Some(i) = &self.pid_file {
+                i.remove();
+            }
+            self.notify(CoreStateChanged::new(CoreState::Stopped));
+        }
+        fs::remove_file(&self.config_path).ok();
+    }
+
+    /// 调用核心的 API 命令，例如 `api rmo`。
+    /// run an API command of the core, e.g. `api rmo`.
+    fn call_api(&self, command: &str, args: &[String]) -> Result<(), String> {
+        let output = Command::new(&self.path)
+            .args([
+                "api",
+                command,
+                &format!("--server={}", get_api_server(self.api_port)),
+            ])
+            .args(args)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        Ok(())
+    }
+
+    /// 通过 HandlerService 移除正在运行的代理出站并添加新配置中的代理出站，不会中断入站上的连接。
+    /// 直连与阻断出站保持不变。
+    /// remove the running proxy outbounds and add the ones of the new config through HandlerService,
+    /// without dropping the connections of the inbounds. The direct and block outbounds are kept.
+    fn replace_outbounds(
+        &self,
+        running: &serde_json::Value,
+        config: &serde_json::Value,
+    ) -> Result<(), String> {
+        let tags = proxy_outbounds(running)
+            .iter()
+            .filter_map(|i| i["tag"].as_str())
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>();
+        let path =
+            std::env::temp_dir().join(format!("v2neko-outbound-{}.json", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            serde_json::json!({ "outbounds": proxy_outbounds(config) }).to_string(),
+        )
+        .map_err(|e| e.to_string())?;
+        // Xray 拒绝重复的 tag，所以需要先移除旧的出站。
+        // Xray rejects duplicate tags, so the old outbounds have to be removed first.
+        let result = self
+            .call_api("rmo", &tags)
+            .and_then(|_| self.call_api("ado", &[path.to_string_lossy().to_string()]));
+        fs::remove_file(&path).ok();
+        result
+    }
+}
+
+/// 用于通过单个出站进行测试的临时核心实例，只带一个本地 HTTP 入站。用完后通过 `stop` 停止进程并删除配置文件，
+/// 没有停止就被 drop 时（例如测试被取消）直接结束进程。
+/// a temporary core instance for testing through a single outbound, with only one local HTTP inbound.
+/// `stop` stops the process and removes its config file once done; dropping it without stopping,
+/// e.g. when the test is cancelled, kills the process right away.
+pub struct ProbeCore {
+    /// 已经停止时为 None
+    /// None once stopped
+    core: Option<Core>,
+    port: u16,
+}
+
+impl ProbeCore {
+    /// 在空闲端口上启动临时实例，并等待入站可以连接。写入配置与启动进程在阻塞线程池中进行。
+    /// start a temporary instance on a free port and wait until its inbound accepts connections.
+    /// Writing the config and spawning the process happen in the blocking thread pool.
+    pub async fn start(
+        path: &str,
+        outbound: &OutboundObject,
+        timeout: Duration,
+    ) -> Result<ProbeCore, String> {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|i| i.local_addr())
+            .map_err(|e| e.to_string())?
+            .port();
+        let path = path.to_owned();
+        let config = generate_probe(outbound, port as i32);
+        let core = tokio::task::spawn_blocking(move || {
+            let mut core = init(&path);
+            core.set_config_path(
+                &std::env::temp_dir().join(format!("v2neko-probe-{}.json", uuid::Uuid::new_v4())),
+            );
+            core.write_config(&config).map_err(|e| e.to_string())?;
+            if let Err(e) = core.spawn() {
+                fs::remove_file(&core.config_path).ok();
+                return Err(format!("failed to start core: {}", e));
+            }
+            Ok(core)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        let mut probe = ProbeCore {
+            core: Some(core),
+            port,
+        };
+        match probe.wait_listening(timeout).await {
+            Ok(_) => Ok(probe),
+            Err(e) => {
+                probe.stop().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// 等待入站可以连接，核心提前退出或超时时返回错误。
+    /// wait until the inbound accepts connections, failing when the core exits early or the timeout passes.
+    async fn wait_listening(&mut self, timeout: Duration) -> Result<(), String> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
+        let start = Instant::now();
+        loop {
+            let running = match &mut self.core {
+                Some(i) => i.is_running(),
+                None => false,
+            };
+            if !running {
+                return Err("core exited before listening".to_string());
+            }
+            let connect = tokio::net::TcpStream::connect(addr);
+            if let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(100), connect).await {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(format!(
+                    "core did not listen within {}ms",
+                    timeout.as_millis()
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// 临时实例的 HTTP 代理地址。
+    /// the HTTP proxy address of the temporary instance.
+    pub fn proxy_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// 在阻塞线程池中停止临时实例并删除配置文件，停止时会等待进程自行退出。
+    /// stop the temporary instance and remove its config file in the blocking thread pool,
+    /// giving the process time to exit on its own.
+    pub async fn stop(mut self) {
+        if let Some(mut i) = self.core.take() {
+            tokio::task::spawn_blocking(move || i.stop()).await.ok();
+        }
+    }
+}
+
+impl Drop for ProbeCore {
+    fn drop(&mut self) {
+        if let Some(mut i) = self.core.take() {
+            i.kill();
+        }
+    }
+}
+
+impl ProxyTrait for Core {
+    /// 停止核心并删除配置文件，配置中带有代理的凭据，不能留在磁盘上。
+    /// stop the core and remove the config file, which holds the credentials of the proxy and must not stay on disk.
     fn stop(&mut self) {
-        if let Some(i) = &mut self.child {
-            i.kill().unwrap();
+        self.stop_process();
+        fs::remove_file(&self.config_path).ok();
+    }
+
+    /// 切换到新的出站。配置总是先写入文件，核心正在运行且只有代理出站变化时通过 API 热切换，否则重启核心。
+    /// switch to a new outbound. The config is always written first, then when the core is running and only
+    /// the proxy outbound changed it is hot switched through the API, otherwise the core is restarted.
+    fn switch_outbound(&mut self, outbound: OutboundObject) -> Result<(), AppError> {
+        let app_config = config::read()?;
+        // 核心没有运行时才选择新的 API 端口，正在运行的核心保持原来的入站。
+        // a new API port is only chosen while the core is not running, a running core keeps its inbounds.
+        if !self.is_running() {
+            self.api_port = choose_api_port(app_config.api_port);
+        }
+        let config = self.generate_with(&app_config, &outbound);
+        self.outbound = Some(outbound);
+        let value = serde_json::from_str::<serde_json::Value>(&config)?;
+        // 热切换时也要写入，监视线程重启核心时读取的是这个文件，否则会恢复到之前的代理。
+        // written on hot switches too, the supervisor restarts the core from this file and would otherwise
+        // bring back the previous proxy.
+        if let Err(i) = self.write_config(&config) {
+            return Err(AppError::CoreStart(format!(
+                "在切换代理时遇到了错误：写入配置文件时错误：{}",
+                i
+            )));
+        }
+        if self.is_running() {
+            if let Some(running) = &self.running {
+                if without_proxy(running) == without_proxy(&value)
+                    && self.replace_outbounds(running, &value).is_ok()
+                {
+                    self.running = Some(value);
+                    return Ok(());
+                }
+            }
+        }
+        self.stop_process();
+        if let Err(i) = self.spawn() {
+            return Err(AppError::CoreStart(format!(
+                "在切换代理时遇到了错误：启动核心时错误：{}",
+                i
+            )));
         }
+        self.running = Some(value);
+        Ok(())
     }
-    fn check_version(&self) -> Result<String, CoreConfigError> {
+
+    fn check_version(&self) -> Result<String, AppError> {
         let output = Command::new(&self.path).arg("-version").output();
         match output {
-            Ok(o) => Ok(String::from_utf8(o.stdout).unwrap()),
-            Err(_) => Err(CoreConfigError {
-                msg: "failed to run command".to_string(),
-                code: -1,
-            }),
+            Ok(o) => Ok(String::from_utf8_lossy(&o.stdout).to_string()),
+            Err(e) => Err(AppError::CoreInvalid(format!(
+                "failed to run {}: {}",
+                self.path, e
+            ))),
         }
     }
 
-    fn poll_output(&mut self) -> Option<String> {
-        let stdout = self.child.as_mut().unwrap().stdout.as_mut().unwrap();
-        let mut buf = BufReader::new(stdout);
-        let mut vec: Vec<u8> = Vec::new();
-        match buf.read_to_end(&mut vec) {
-            Ok(_) => Some(String::from_utf8(vec).unwrap()),
-            Err(_) => None,
-        }
+    fn get_logs(&self, count: usize, level: Option<LogLevel>) -> Vec<LogLine> {
+        self.logs.tail(count, level)
+    }
+
+    fn set_core_path(&mut self, path: &str) {
+        self.path = path.to_owned();
     }
 
-    fn generate_config(&self) -> Option<String> {
-        let outbound = &serde_json::from_str("").unwrap();
-        Some(generate(outbound))
+    fn set_rules(&mut self, rules: Vec<RuleObject>) {
+        self.rules = rules;
+    }
+
+    fn api_port(&self) -> i32 {
+        self.api_port
+    }
+
+    /// 核心正在运行时用当前的出站和规则重新生成配置，路由变化时核心会被重启。
+    /// regenerate the config from the current outbound and rules while the core runs,
+    /// the core is restarted when the routing changed.
+    fn apply(&mut self) -> Result<(), AppError> {
+        match self.outbound.clone() {
+            Some(outbound) if self.is_running() => self.switch_outbound(outbound),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -82,19 +491,354 @@ mod tests {
     use std::thread;
 
     use super::*;
+    #[cfg(unix)]
+    use crate::vmess::testing::{get_temp_dir, write_fake_core};
+    /// 核心 `-version` 的输出。
+    /// the output of `-version` of the core.
+    const VERSION: &str = r#"Xray 1.7.2 (Xray, Penetrates Everything.) Custom (go1.19.4 linux/amd64)
+A unified platform for anti-censorship.
+"#;
+
+    #[cfg(unix)]
     #[test]
     fn test_check_version() {
-        let core = init("/usr/bin/xray");
-        let output = core.check_version().unwrap();
+        let path = write_fake_core(
+            &get_temp_dir(),
+            "xray",
+            &format!("printf '%s' '{}'\n", VERSION),
+        );
+        let core = init(&path.to_string_lossy());
+        assert_eq!(core.check_version().unwrap(), VERSION);
+    }
 
+    #[test]
+    fn test_generate_config() {
+        let mut core = init("/usr/bin/xray");
+        core.api_port = 10085;
+
+        let (outbound, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.com:443?security=tls&sni=example.com#name",
+        )
+        .unwrap();
+        let config = serde_json::from_str::<serde_json::Value>(
+            &core.generate_with(&config::get_default_config(), &outbound),
+        )
+        .unwrap();
+        assert_eq!(config["outbounds"][0]["protocol"], "trojan");
         assert_eq!(
-            output,
-            String::from(
-                r#"Xray 1.7.2 (Xray, Penetrates Everything.) Custom (go1.19.4 linux/amd64)
-A unified platform for anti-censorship.
-"#
-            )
+            config["outbounds"][0]["settings"]["servers"][0]["address"],
+            "example.com"
+        );
+        assert_eq!(config["log"]["loglevel"], "error");
+        assert_eq!(config["inbounds"][0]["tag"], "V2Neko_API_INBOUND");
+        assert_eq!(config["inbounds"][0]["protocol"], "dokodemo-door");
+        assert_eq!(config["inbounds"][0]["listen"], "127.0.0.1");
+        assert_eq!(config["inbounds"][0]["port"], 10085);
+        assert!(config["inbounds"][1]["settings"]["socks"].is_null());
+    }
+
+    fn pid(core: &Core) -> u32 {
+        core.process.lock().unwrap().child.as_ref().unwrap().id()
+    }
+
+    /// 写一个假的核心：启动后把收到的配置复制到 `running.json` 并一直运行，API 命令的参数追加到 `args` 中，
+    /// 以 `api_status` 退出。
+    /// write a fake core that copies the config it was started with to `running.json` and keeps running,
+    /// and appends the arguments of API commands to `args`, exiting with `api_status`.
+    #[cfg(unix)]
+    fn fake_core(api_status: i32) -> (PathBuf, PathBuf) {
+        let dir = get_temp_dir();
+        let args = dir.join("args");
+        write_fake_core(
+            &dir,
+            "xray",
+            &format!(
+                "if [ \"$1\" = api ]; then\n  echo \"$@\" >> \"{}\"\n  exit {}\nfi\ncp \"$2\" \"{}\"\nexec sleep 30\n",
+                args.display(),
+                api_status,
+                dir.join("running.json").display()
+            ),
+        );
+        (dir, args)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_switch_outbound() {
+        let (first, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.com:443?security=tls#first",
+        )
+        .unwrap();
+        let (second, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.org:443?security=tls#second",
+        )
+        .unwrap();
+
+        // API 可用时切换不会重启核心。
+        // switching does not restart the core while the API works.
+        let (dir, args) = fake_core(0);
+        let mut core = init(&dir.join("xray").to_string_lossy());
+        core.set_config_path(&dir.join("connection.json"));
+        core.switch_outbound(first.clone()).unwrap();
+        assert!(core.is_running());
+        assert!(!args.exists());
+        let started = pid(&core);
+        core.switch_outbound(second.clone()).unwrap();
+        assert_eq!(pid(&core), started);
+        let calls = fs::read_to_string(&args).unwrap();
+        let calls = calls.lines().collect::<Vec<&str>>();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].starts_with("api rmo --server=127.0.0.1:"));
+        assert!(calls[0].ends_with(" PROXY"));
+        assert!(calls[1].starts_with("api ado --server=127.0.0.1:"));
+
+        // 路由规则变化时重启核心。
+        // the core is restarted when the routing rules change.
+        core.set_rules(vec![RuleObject {
+            domain: vec!["geosite:cn".to_string()],
+            ..RuleObject::new(crate::vmess::generate::DIRECT_TAG)
+        }]);
+        core.apply().unwrap();
+        assert_ne!(pid(&core), started);
+        assert_eq!(fs::read_to_string(&args).unwrap().lines().count(), 2);
+        let config = fs::read_to_string(dir.join("connection.json")).unwrap();
+        assert!(config.contains("geosite:cn"));
+        core.stop();
+        assert!(!core.is_running());
+
+        // API 失败时回退到重启核心。
+        // fall back to restarting the core when the API fails.
+        let (dir, args) = fake_core(1);
+        let mut core = init(&dir.join("xray").to_string_lossy());
+        core.set_config_path(&dir.join("connection.json"));
+        core.switch_outbound(first).unwrap();
+        let started = pid(&core);
+        core.switch_outbound(second).unwrap();
+        assert_ne!(pid(&core), started);
+        assert!(core.is_running());
+        assert_eq!(fs::read_to_string(&args).unwrap().lines().count(), 1);
+        let config = fs::read_to_string(dir.join("connection.json")).unwrap();
+        assert!(config.contains("example.org"));
+        core.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_switch_outbound_restart() {
+        let (first, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.com:443?security=tls#first",
+        )
+        .unwrap();
+        let (second, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.org:443?security=tls#second",
+        )
+        .unwrap();
+
+        let (dir, _) = fake_core(0);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let mut core = init(&dir.join("xray").to_string_lossy());
+        core.set_config_path(&dir.join("connection.json"));
+        {
+            let states = states.clone();
+            core.set_supervisor(
+                SupervisorOptions {
+                    initial_backoff: Duration::from_millis(10),
+                    ..SupervisorOptions::default()
+                },
+                move |i| states.lock().unwrap().push(i.state),
+            );
+        }
+        core.switch_outbound(first).unwrap();
+        let started = pid(&core);
+        core.switch_outbound(second).unwrap();
+        assert_eq!(pid(&core), started);
+
+        // 热切换之后核心崩溃，重启的核心使用的是新的出站。
+        // the core crashes after a hot switch and the restarted core runs the new outbound.
+        Command::new("kill")
+            .arg(started.to_string())
+            .status()
+            .unwrap();
+        let running = dir.join("running.json");
+        let start = Instant::now();
+        while (states.lock().unwrap().len() < 5
+            || !fs::read_to_string(&running)
+                .unwrap_or_default()
+                .contains("example.org"))
+            && start.elapsed() < Duration::from_secs(5)
+        {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        assert_ne!(pid(&core), started);
+        let config = fs::read_to_string(&running).unwrap();
+        assert!(config.contains("example.org"));
+        assert!(!config.contains("example.com"));
+        core.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_config() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = get_temp_dir();
+        let args = dir.join("args");
+        let path = write_fake_core(
+            &dir,
+            "xray",
+            &format!("echo \"$@\" > \"{}\"\nexec sleep 30\n", args.display()),
+        );
+
+        assert!(init(&path.to_string_lossy()).config_path.is_absolute());
+
+        let (outbound, _) = crate::vmess::generate::parse_share_link(
+            "trojan://password@example.com:443?security=tls#name",
+        )
+        .unwrap();
+        let mut core = init(&path.to_string_lossy());
+        core.set_config_path(&dir.join("session").join("connection.json"));
+        core.set_pid_file(PidFile::new(&dir.join("core.pid")));
+        core.switch_outbound(outbound).unwrap();
+        let start = Instant::now();
+        while !args.exists() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        assert!(fs::read_to_string(dir.join("core.pid"))
+            .unwrap()
+            .starts_with(&format!("{}\n", pid(&core))));
+
+        // 核心收到的正是写入的绝对路径。
+        // the core receives exactly the absolute path that was written.
+        let config_path = dir.join("session").join("connection.json");
+        assert_eq!(
+            fs::read_to_string(&args).unwrap().trim(),
+            format!("-config {}", config_path.display())
+        );
+        let config = fs::read_to_string(&config_path).unwrap();
+        assert!(config.contains("example.com"));
+        assert_eq!(
+            fs::metadata(&config_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!dir.join("session").join("connection.json.tmp").exists());
+
+        // 停止核心时删除 PID 文件与带有凭据的配置文件。
+        // stopping the core removes the pid file and the config file holding the credentials.
+        core.stop();
+        assert!(!dir.join("core.pid").exists());
+        assert!(!config_path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_supervisor() {
+        let (dir, _) = fake_core(0);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let mut core = init(&dir.join("xray").to_string_lossy());
+        {
+            let states = states.clone();
+            core.set_supervisor(
+                SupervisorOptions {
+                    initial_backoff: Duration::from_millis(10),
+                    ..SupervisorOptions::default()
+                },
+                move |i| states.lock().unwrap().push(i.state),
+            );
+        }
+        core.spawn().unwrap();
+        let started = pid(&core);
+        Command::new("kill")
+            .arg(started.to_string())
+            .status()
+            .unwrap();
+        // 等待监视线程发现退出并重启核心。
+        // wait for the watching thread to notice the exit and restart the core.
+        let start = Instant::now();
+        while states.lock().unwrap().len() < 5 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        assert!(core.is_running());
+        assert_ne!(pid(&core), started);
+        core.stop();
+        assert_eq!(
+            *states.lock().unwrap(),
+            vec![
+                CoreState::Starting,
+                CoreState::Running,
+                CoreState::Crashed,
+                CoreState::Starting,
+                CoreState::Running,
+                CoreState::Stopped,
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_logs() {
+        let path = write_fake_core(
+            &get_temp_dir(),
+            "xray",
+            "echo '2023/01/01 12:00:00 [Info] started'\necho 'bad outbound' >&2\nexec sleep 30\n",
         );
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut core = init(&path.to_string_lossy());
+        {
+            let received = received.clone();
+            core.set_log_listener(move |i| received.lock().unwrap().push(i.clone()));
+        }
+        // 读取日志不会阻塞，即使核心仍在运行。
+        // reading the logs does not block even though the core is still running.
+        core.spawn().unwrap();
+        let start = Instant::now();
+        while core.get_logs(10, None).len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        assert!(core.is_running());
+        assert_eq!(core.get_logs(10, None).len(), 2);
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let errors = core.get_logs(10, Some(LogLevel::Error));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "bad outbound");
+        core.stop();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_probe_core_stop() {
+        // 忽略 SIGTERM 的核心，只有被强制结束时才会退出。
+        // a core ignoring SIGTERM, exiting only when it is killed.
+        let dir = get_temp_dir();
+        let path = write_fake_core(&dir, "xray", "trap '' TERM\nwhile true; do sleep 1; done\n");
+        let start_probe = |name: &str| {
+            let mut core = init(&path.to_string_lossy());
+            core.set_config_path(&dir.join(name));
+            core.write_config("{}").unwrap();
+            core.spawn().unwrap();
+            let process = core.process.clone();
+            (
+                ProbeCore {
+                    core: Some(core),
+                    port: 0,
+                },
+                process,
+            )
+        };
+
+        // drop 不会等待进程退出，但仍然结束进程并删除配置文件。
+        // dropping does not wait for the process to exit but still kills it and removes the config file.
+        let (probe, process) = start_probe("dropped.json");
+        let start = Instant::now();
+        drop(probe);
+        assert!(start.elapsed() < shutdown::STOP_TIMEOUT);
+        assert!(process.lock().unwrap().child.is_none());
+        assert!(!dir.join("dropped.json").exists());
+
+        let (probe, process) = start_probe("stopped.json");
+        probe.stop().await;
+        assert!(process.lock().unwrap().child.is_none());
+        assert!(!dir.join("stopped.json").exists());
     }
 
     #[test]
@@ -105,14 +849,21 @@ A unified platform for anti-censorship.
         assert!(output.is_err());
     }
 
-    #[tokio::test]
-    async fn test_start() {
-        let mut core = init("/usr/bin/xray");
-        core.start();
-        thread::sleep(time::Duration::from_secs(1));
-        tokio::spawn(async {
-            thread::sleep(time::Duration::from_secs(1));
-            assert!(false, "timeout");
-        });
+    #[cfg(unix)]
+    #[test]
+    fn test_start() {
+        let (dir, _) = fake_core(0);
+        let mut core = init(&dir.join("xray").to_string_lossy());
+        core.set_config_path(&dir.join("connection.json"));
+        core.spawn().unwrap();
+        assert!(core.is_running());
+        core.stop();
+        assert!(!core.is_running());
+
+        // 核心无法启动时返回错误而不是 panic。
+        // a core that cannot be started is an error instead of a panic.
+        let mut core = init("/nonexistent/xray");
+        assert!(core.spawn().is_err());
+        assert!(!core.is_running());
     }
 }
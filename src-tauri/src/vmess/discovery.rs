@@ -0,0 +1,246 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use super::core;
+use crate::{error::AppError, files, proxy::ProxyTrait};
+
+/// 支持的核心可执行文件名。
+/// the executable names of the supported cores.
+const CORE_NAMES: [&str; 2] = ["xray", "v2ray"];
+
+/// 常见的安装目录。
+/// the common install locations.
+#[cfg(not(windows))]
+const COMMON_DIRS: [&str; 6] = [
+    "/usr/local/bin",
+    "/usr/bin",
+    "/opt/homebrew/bin",
+    "/usr/local/share/xray",
+    "/opt/xray",
+    "/opt/v2ray",
+];
+#[cfg(windows)]
+const COMMON_DIRS: [&str; 2] = ["C:\\Program Files\\Xray", "C:\\Program Files\\v2ray"];
+
+/// 从 `-version` 的输出中解析出的核心版本。
+/// the core version parsed from the output of `-version`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct CoreVersion {
+    /// 核心名称，例如 Xray 或 V2Ray
+    /// the name of the core, e.g. Xray or V2Ray
+    pub name: String,
+    /// 语义化版本号，例如 1.7.2
+    /// the semantic version, e.g. 1.7.2
+    pub version: String,
+    /// 构建核心使用的 Go 版本，例如 1.19.4
+    /// the Go version the core was built with, e.g. 1.19.4
+    pub go_version: String,
+}
+
+fn is_semver(version: &str) -> bool {
+    let parts = version.split('.').collect::<Vec<&str>>();
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|i| !i.is_empty() && i.chars().all(|j| j.is_ascii_digit()))
+}
+
+/// 解析 `-version` 输出的第一行，例如
+/// `Xray 1.7.2 (Xray, Penetrates Everything.) Custom (go1.19.4 linux/amd64)`。
+/// parse the first line printed by `-version`, e.g.
+/// `Xray 1.7.2 (Xray, Penetrates Everything.) Custom (go1.19.4 linux/amd64)`.
+pub fn parse_version(output: &str) -> Option<CoreVersion> {
+    let line = output.lines().next()?;
+    let mut words = line.split_whitespace();
+    let name = words.next()?;
+    if !CORE_NAMES.contains(&name.to_lowercase().as_str()) {
+        return None;
+    }
+    let version = words.next()?.trim_start_matches('v');
+    if !is_semver(version) {
+        return None;
+    }
+    let go_version = words.find_map(|i| i.strip_prefix("(go"))?;
+    Some(CoreVersion {
+        name: name.to_string(),
+        version: version.to_string(),
+        go_version: go_version.to_string(),
+    })
+}
+
+/// 运行核心的 `-version` 并校验输出。
+/// run `-version` of the core and validate its output.
+pub fn check_core(path: &str) -> Result<CoreVersion, AppError> {
+    let output = core::init(path).check_version()?;
+    parse_version(&output).ok_or_else(|| {
+        AppError::CoreInvalid(format!(
+            "{} is not a supported core: {}",
+            path,
+            output.trim()
+        ))
+    })
+}
+
+fn get_executables(dir: &Path) -> Vec<PathBuf> {
+    CORE_NAMES
+        .iter()
+        .map(|i| dir.join(format!("{}{}", i, env::consts::EXE_SUFFIX)))
+        .collect()
+}
+
+/// 自动查找核心时依次尝试的路径：PATH、配置目录以及常见的安装目录。
+/// the paths tried in order when looking for a core: PATH, the config directory and the common install locations.
+fn get_candidates() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::var_os("PATH")
+        .map(|i| env::split_paths(&i).collect())
+        .unwrap_or_default();
+    if let Ok(i) = files::get_path("") {
+        dirs.push(i);
+    }
+    dirs.extend(COMMON_DIRS.iter().map(PathBuf::from));
+    dirs.iter().flat_map(|i| get_executables(i)).collect()
+}
+
+/// 返回第一个存在且版本有效的核心。
+/// return the first core that exists and has a valid version.
+fn find_in(candidates: &[PathBuf]) -> Option<(String, CoreVersion)> {
+    candidates
+        .iter()
+        .filter(|i| i.is_file())
+        .map(|i| i.to_string_lossy().to_string())
+        .find_map(|i| check_core(&i).ok().map(|j| (i, j)))
+}
+
+/// 查找要使用的核心：设置了路径时只校验该路径，否则自动查找。找不到可用的核心时返回错误。
+/// find the core to use: a configured path is only validated, otherwise the core is looked up automatically.
+/// Returns an error when no usable core exists.
+pub fn find_core(configured: &str) -> Result<(String, CoreVersion), AppError> {
+    if !configured.is_empty() {
+        return check_core(configured).map(|i| (configured.to_string(), i));
+    }
+    find_in(&get_candidates()).ok_or_else(|| {
+        AppError::CoreNotFound(
+            "no usable xray or v2ray core found, please install one or set core_path".to_string(),
+        )
+    })
+}
+
+/// 查找并校验过的核心路径。查找需要扫描多个目录并运行 `-version`，所以只在设置的 core_path 变化时重新进行，
+/// 并且在阻塞线程池中执行。
+/// the core path found and validated before. Looking it up scans several directories and runs `-version`,
+/// so it is only repeated when the configured core_path changes, and runs in the blocking thread pool.
+#[derive(Default)]
+pub struct CorePathCache {
+    /// 设置的 core_path 以及据此找到的核心路径
+    /// the configured core_path and the core path found from it
+    found: Mutex<Option<(String, String)>>,
+}
+
+impl CorePathCache {
+    /// 按设置的 core_path 查找核心并返回其路径，设置没有变化时直接使用上次的结果。
+    /// find the core for the configured core_path and return its path, reusing the last result while the
+    /// setting is unchanged.
+    pub async fn get(&self, configured: &str) -> Result<String, AppError> {
+        if let Some((i, path)) = &*self.found.lock()? {
+            if i == configured {
+                return Ok(path.clone());
+            }
+        }
+        let lookup = configured.to_string();
+        let (path, _) = tokio::task::spawn_blocking(move || find_core(&lookup))
+            .await
+            .map_err(|e| AppError::Internal(format!("core lookup failed: {}", e)))??;
+        *self.found.lock()? = Some((configured.to_string(), path.clone()));
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::vmess::testing::{get_temp_dir, write_fake_core};
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            parse_version(
+                "Xray 1.7.2 (Xray, Penetrates Everything.) Custom (go1.19.4 linux/amd64)\nA unified platform for anti-censorship.\n"
+            ),
+            Some(CoreVersion {
+                name: "Xray".to_string(),
+                version: "1.7.2".to_string(),
+                go_version: "1.19.4".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_version(
+                "V2Ray 5.4.1 (V2Fly, a community-driven edition of V2Ray.) Custom (go1.20.3 linux/amd64)"
+            )
+            .unwrap()
+            .name,
+            "V2Ray"
+        );
+        assert!(parse_version("").is_none());
+        assert!(parse_version("sing-box version 1.3.0").is_none());
+        assert!(parse_version("Xray 1.7 (Xray) Custom (go1.19.4 linux/amd64)").is_none());
+        assert!(parse_version("Xray 1.7.2 (Xray, Penetrates Everything.)").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_core() {
+        let dir = get_temp_dir();
+        let write_script =
+            |name: &str, output: &str| write_fake_core(&dir, name, &format!("echo '{}'\n", output));
+        let bad = write_script("bad", "unknown flag: -version");
+        let good = write_script(
+            "xray",
+            "Xray 1.8.4 (Xray, Penetrates Everything.) 1b58d1c (go1.21.1 linux/amd64)",
+        );
+
+        let (path, version) = find_in(&[dir.join("missing"), bad.clone(), good.clone()]).unwrap();
+        assert_eq!(path, good.to_string_lossy());
+        assert_eq!(version.version, "1.8.4");
+        assert_eq!(version.go_version, "1.21.1");
+        assert!(find_in(std::slice::from_ref(&bad)).is_none());
+        assert_eq!(get_executables(&dir)[0], dir.join("xray"));
+
+        // 设置的路径无效时不会回退到自动查找。
+        // an invalid configured path does not fall back to the automatic lookup.
+        assert!(find_core(&bad.to_string_lossy()).is_err());
+        assert!(find_core("/nonexistent/xray").is_err());
+        assert_eq!(find_core(&good.to_string_lossy()).unwrap().1, version);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_core_path_cache() {
+        let dir = get_temp_dir();
+        let write_script = |name: &str, output: &str| {
+            write_fake_core(&dir, name, &format!("echo '{}'\n", output))
+                .to_string_lossy()
+                .to_string()
+        };
+        let version = "Xray 1.8.4 (Xray, Penetrates Everything.) 1b58d1c (go1.21.1 linux/amd64)";
+        let first = write_script("xray", version);
+        let second = write_script("v2ray", version);
+
+        let cache = CorePathCache::default();
+        assert_eq!(cache.get(&first).await.unwrap(), first);
+        // 设置没有变化时不会再次校验核心。
+        // the core is not validated again while the setting is unchanged.
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(cache.get(&first).await.unwrap(), first);
+        // 设置变化时重新查找并校验。
+        // the core is looked up and validated again when the setting changes.
+        assert_eq!(cache.get(&second).await.unwrap(), second);
+        assert!(cache.get(&first).await.is_err());
+        assert!(cache.get("/nonexistent/xray").await.is_err());
+    }
+}
@@ -4,7 +4,9 @@ use std::fmt;
 pub enum ParseLinkErrorCode {
     Base64Error,
     LinkError,
-    JsonEror
+    JsonEror,
+    UrlError,
+    YamlError,
 }
 
 /// 在解析连接时可能出现的错误。
@@ -19,28 +21,24 @@ pub struct ParseLinkError {
 impl fmt::Display for ParseLinkError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.code {
-            ParseLinkErrorCode::LinkError => write!(f, "link error: could not parse link"),
+            ParseLinkErrorCode::LinkError => write!(f, "link error: {}", self.msg),
             ParseLinkErrorCode::Base64Error => write!(f, "Base64 Decode error: {}", self.msg),
             ParseLinkErrorCode::JsonEror => write!(f, "Json parse error: {}", self.msg),
+            ParseLinkErrorCode::UrlError => write!(f, "Url parse error: {}", self.msg),
+            ParseLinkErrorCode::YamlError => write!(f, "Yaml parse error: {}", self.msg),
         }
     }
 }
 
+/// 出站无法转换为分享链接，例如协议或传输方式不受支持。
+/// the outbound cannot be turned into a share link, e.g. its protocol or transport is not supported.
 #[derive(Debug)]
 pub struct GenerateLinkError {
     pub msg: String,
-    pub code: i32,
 }
 
-// 为 AppError 实现 std::fmt::Display 特征
 impl fmt::Display for GenerateLinkError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.code {
-            1 => write!(f, "link error: could not parse link"),
-            2 => write!(f, "Base64 Decode error: {}", self.msg),
-            3 => write!(f, "Json parse error: {}", self.msg),
-            _ => write!(f, "Unknow error: {}", self.msg),
-        }
+        write!(f, "could not generate link: {}", self.msg)
     }
 }
-
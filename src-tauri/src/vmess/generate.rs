@@ -1,18 +1,33 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::TcpListener};
 
 use base64::{engine::general_purpose, Engine};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use url::{form_urlencoded, Host, Url};
 
-use crate::config::{read, AppConfig};
+use crate::config::{AppConfig, RoutingMode};
 
 use super::error::{GenerateLinkError, ParseLinkError};
 
+pub mod clash;
+pub mod json;
+
+/// 代理出站的 tag。
+/// the tag of the proxy outbound.
+pub const PROXY_TAG: &str = "PROXY";
+/// 直连出站的 tag。
+/// the tag of the direct outbound.
+pub const DIRECT_TAG: &str = "DIRECT";
+/// 拦截出站的 tag。
+/// the tag of the block outbound.
+pub const BLOCK_TAG: &str = "BLOCK";
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct ConfigJson {
     log: LogObject,
     api: ApiObject,
     dns: DnsObject,
     inbounds: Vec<InboundObject>,
-    outbound: Vec<OutboundObject>,
+    outbounds: Vec<OutboundObject>,
     policy: PolicyObject,
     routing: RoutingObject,
     stats: StatsObject,
@@ -20,7 +35,7 @@ struct ConfigJson {
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct LogObject {
-    #[serde(alias = "loglevel")]
+    #[serde(rename = "loglevel")]
     log_level: String,
 }
 
@@ -32,7 +47,7 @@ struct ApiObject {
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct DnsObject {
-    server: Vec<String>,
+    servers: Vec<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
@@ -45,21 +60,28 @@ struct InboundObject {
     sniffing: SniffingObject,
 }
 
+/// Xray 的入站 settings 不带协议名包裹，协议由 InboundObject.protocol 指定。
+/// Xray inbound settings are not wrapped in the protocol name, which lives in InboundObject.protocol.
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
+#[serde(untagged)]
 enum InboundConfigurationObject {
     Socks {
         auth: String,
         udp: bool,
         ip: String,
+        #[serde(rename = "userLevel")]
         user_level: i32,
     },
     Http {
         timeout: Option<i32>,
         accounts: Option<HttpUserObject>,
+        #[serde(rename = "allowTransparent")]
         allow_transparent: bool,
         level: Option<i32>,
     },
+    Dokodemo {
+        address: String,
+    },
 }
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct HttpUserObject {
@@ -69,21 +91,101 @@ struct HttpUserObject {
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-struct OutboundObject {
+pub struct OutboundObject {
     #[serde(rename = "sendThrough")]
     #[serde(skip_serializing_if = "Option::is_none")]
     send_through: Option<String>,
     protocol: String,
     settings: OutboundConfigurationObject,
+    #[serde(default)]
     tag: String,
 
+    #[serde(default)]
     stream_settings: StreamSettingsObject,
     #[serde(skip_serializing_if = "Option::is_none")]
     proxy_settings: Option<ProxySettingsObject>,
+    #[serde(default)]
     mux: MuxObject,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+impl OutboundObject {
+    /// 出站协议，例如 vmess、vless、trojan、shadowsocks。
+    /// the outbound protocol, e.g. vmess, vless, trojan or shadowsocks.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// 第一个服务器的地址与端口。
+    /// the address and port of the first server.
+    pub fn server(&self) -> Option<(String, i32)> {
+        match &self.settings {
+            OutboundConfigurationObject::Vless { vnext } => {
+                vnext.first().map(|i| (i.address.clone(), i.port))
+            }
+            OutboundConfigurationObject::Vmess { vnext } => {
+                vnext.first().map(|i| (i.address.clone(), i.port))
+            }
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                servers.first().map(|i| (i.address.clone(), i.port))
+            }
+            OutboundConfigurationObject::Trojan { servers } => {
+                servers.first().map(|i| (i.address.clone(), i.port))
+            }
+            OutboundConfigurationObject::Freedom { .. }
+            | OutboundConfigurationObject::Blackhole { .. } => None,
+        }
+    }
+
+    /// 第一个服务器的用户凭据：VMess/VLESS 的 id 或 Trojan/Shadowsocks 的密码。
+    /// the credential of the first server: the VMess/VLESS id or the Trojan/Shadowsocks password.
+    pub fn credential(&self) -> Option<String> {
+        match &self.settings {
+            OutboundConfigurationObject::Vless { vnext } => vnext
+                .first()
+                .and_then(|i| i.users.first())
+                .map(|i| i.id.clone()),
+            OutboundConfigurationObject::Vmess { vnext } => vnext
+                .first()
+                .and_then(|i| i.users.first())
+                .map(|i| i.id.clone()),
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                servers.first().map(|i| i.password.clone())
+            }
+            OutboundConfigurationObject::Trojan { servers } => {
+                servers.first().map(|i| i.password.clone())
+            }
+            OutboundConfigurationObject::Freedom { .. }
+            | OutboundConfigurationObject::Blackhole { .. } => None,
+        }
+    }
+
+    /// 使用 TLS 或 REALITY 时握手所用的 SNI，未设置时为服务器地址；不使用 TLS 时为 None。
+    /// the SNI of the handshake when TLS or REALITY is used, falling back to the server address; None without TLS.
+    pub fn tls_server_name(&self) -> Option<String> {
+        let stream = &self.stream_settings;
+        let server_name = match stream.security.as_deref() {
+            Some("tls") => stream
+                .tls_settings
+                .as_ref()
+                .and_then(|i| i.server_name.clone()),
+            Some("reality") => stream
+                .reality_settings
+                .as_ref()
+                .and_then(|i| i.server_name.clone()),
+            _ => return None,
+        };
+        server_name
+            .filter(|i| !i.is_empty())
+            .or_else(|| self.server().map(|(address, _)| address))
+    }
+}
+
+/// 批量导入时一个条目的转换结果：成功时为出站配置与名称，失败时为名称与跳过原因。
+/// the conversion result of one entry of a bulk import: the outbound and its name,
+/// or the name and the reason it was skipped.
+pub type ImportEntry = Result<(OutboundObject, String), (String, String)>;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
 struct MuxObject {
     #[serde(skip_serializing_if = "Option::is_none")]
     enabled: Option<bool>,
@@ -99,8 +201,42 @@ struct ProxySettingsObject {
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(untagged)]
 enum OutboundConfigurationObject {
+    // 必须位于 Vmess 之前，否则带有 encryption 的用户会被当作 Vmess 解析。
+    // must come before Vmess, otherwise users with an encryption deserialize as Vmess.
+    #[serde(rename = "vnext")]
+    Vless {
+        vnext: Vec<VlessServerObject>,
+    },
     #[serde(rename = "vnext")]
-    Vmess { vnext: Vec<VmessServerObject> },
+    Vmess {
+        vnext: Vec<VmessServerObject>,
+    },
+    // 必须位于 Trojan 之前，否则带有 method 的服务器会被当作 Trojan 解析。
+    // must come before Trojan, otherwise servers with a method deserialize as Trojan.
+    #[serde(rename = "servers")]
+    Shadowsocks {
+        servers: Vec<ShadowsocksServerObject>,
+    },
+    #[serde(rename = "servers")]
+    Trojan {
+        servers: Vec<TrojanServerObject>,
+    },
+    // 直连与拦截出站没有服务器，它们的字段互不相同，也不会出现在代理出站中。
+    // the direct and block outbounds have no servers, their fields differ from each other and never appear in
+    // proxy outbounds.
+    Freedom {
+        #[serde(rename = "domainStrategy")]
+        domain_strategy: String,
+    },
+    Blackhole {
+        response: BlackholeResponseObject,
+    },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct BlackholeResponseObject {
+    #[serde(rename = "type")]
+    response_type: String,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
@@ -123,6 +259,12 @@ struct StreamSettingsObject {
     #[serde(skip_serializing_if = "Option::is_none")]
     quic_settings: Option<QUICObject>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    grpc_settings: Option<GrpcObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_settings: Option<TlsObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reality_settings: Option<RealityObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     sockopt: Option<SockoptObject>,
 }
 
@@ -130,6 +272,61 @@ fn default_network() -> String {
     "tcp".to_string()
 }
 
+impl Default for StreamSettingsObject {
+    fn default() -> Self {
+        StreamSettingsObject {
+            network: default_network(),
+            security: None,
+            tcp_settings: None,
+            kcp_settings: None,
+            ws_settings: None,
+            http_settings: None,
+            ds_settings: None,
+            quic_settings: None,
+            grpc_settings: None,
+            tls_settings: None,
+            reality_settings: None,
+            sockopt: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TlsObject {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_insecure: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alpn: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RealityObject {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
+    public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spider_x: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct GrpcObject {
+    #[serde(default)]
+    service_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multi_mode: Option<bool>,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct QUICObject {
     security: String,
@@ -150,6 +347,7 @@ struct DomainSocketObject {
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct HttpObject {
+    #[serde(default)]
     host: Vec<String>,
     path: Option<String>,
 }
@@ -178,6 +376,8 @@ struct KcpObject {
     read_buffer_size: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     write_buffer_size: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<String>,
     header: KcpHeaderObject,
 }
 
@@ -202,8 +402,8 @@ enum TcpHeaderObject {
     HttpHeaderObject {
         #[serde(rename = "type")]
         tcp_type: String,
-        request: Option<HTTPRequestObject>,
-        response: Option<HTTPResponseObject>,
+        request: Option<Box<HTTPRequestObject>>,
+        response: Option<Box<HTTPResponseObject>>,
     },
 }
 
@@ -223,8 +423,8 @@ struct HTTPResponseObject {
     headers: HashMap<String, Vec<String>>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
 struct SockoptObject {
     mark: i32,
     tcp_fast_open: bool,
@@ -246,9 +446,56 @@ struct UserObject {
     alter_id: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     level: Option<i32>,
+    #[serde(default = "default_security")]
     security: String,
 }
 
+fn default_security() -> String {
+    "auto".to_string()
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct VlessServerObject {
+    address: String,
+    port: i32,
+    users: Vec<VlessUserObject>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct VlessUserObject {
+    id: String,
+    encryption: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct ShadowsocksServerObject {
+    address: String,
+    port: i32,
+    method: String,
+    password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uot: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct TrojanServerObject {
+    address: String,
+    port: i32,
+    password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct SniffingObject {
@@ -283,15 +530,60 @@ struct RoutingObject {
     rules: Vec<RuleObject>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+/// Xray 的路由规则。流量满足全部非空条件时交给 `outbound_tag`，空的条件不会写入配置。
+/// an Xray routing rule. Traffic meeting every non-empty condition goes to `outbound_tag`, empty conditions are
+/// left out of the config.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
-struct RuleObject {
+pub struct RuleObject {
     #[serde(rename = "type")]
-    rule_type: String,
-    #[serde(alias = "inboundTag")]
-    inbound_tag: Vec<String>,
+    pub rule_type: String,
+    /// 域名，支持 `domain:`、`full:`、`regexp:`、`keyword:` 与 `geosite:` 前缀
+    /// domains, supporting the `domain:`, `full:`, `regexp:`, `keyword:` and `geosite:` prefixes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain: Vec<String>,
+    /// 目标 IP 或 CIDR，支持 `geoip:` 前缀
+    /// destination IPs or CIDRs, supporting the `geoip:` prefix
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip: Vec<String>,
+    /// 目标端口，例如 `53,443,1000-2000`
+    /// destination ports, e.g. `53,443,1000-2000`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub port: String,
+    /// `tcp`、`udp` 或 `tcp,udp`
+    /// `tcp`, `udp` or `tcp,udp`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub network: String,
+    /// 嗅探出的协议：`http`、`tls`、`quic` 或 `bittorrent`
+    /// the sniffed protocols: `http`, `tls`, `quic` or `bittorrent`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocol: Vec<String>,
+    /// 来源 IP 或 CIDR
+    /// source IPs or CIDRs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source: Vec<String>,
+    #[serde(alias = "inboundTag", default, skip_serializing_if = "Vec::is_empty")]
+    pub inbound_tag: Vec<String>,
     #[serde(alias = "outboundTag")]
-    outbound_tag: String,
+    pub outbound_tag: String,
+}
+
+impl RuleObject {
+    /// 只带出站的空规则，条件由调用者填入。
+    /// an empty rule with only the outbound, the caller fills in the conditions.
+    pub fn new(outbound_tag: &str) -> RuleObject {
+        RuleObject {
+            rule_type: "field".to_string(),
+            domain: Vec::new(),
+            ip: Vec::new(),
+            port: String::new(),
+            network: String::new(),
+            protocol: Vec::new(),
+            source: Vec::new(),
+            inbound_tag: Vec::new(),
+            outbound_tag: outbound_tag.to_string(),
+        }
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
@@ -300,6 +592,12 @@ pub struct Outbounds {
     outbounds: Vec<OutboundObject>,
 }
 
+impl Outbounds {
+    pub fn new(outbounds: Vec<OutboundObject>) -> Outbounds {
+        Outbounds { outbounds }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct StatsObject {}
 
@@ -323,8 +621,46 @@ pub struct Base64LinkObject {
     pub sni: Option<String>,
 }
 
-fn get_inbound_object(config: &AppConfig) -> Vec<InboundObject> {
-    let mut result = Vec::new();
+/// 选择 API 入站的端口：配置的端口可用时使用它，否则由系统分配一个空闲端口。
+/// 核心启动时选择一次，之后生成的配置都沿用该端口，这样热切换时入站保持不变。
+/// choose the port of the API inbound: the configured one when it is available, otherwise a free one from the system.
+/// It is chosen once when the core starts and kept by every config generated afterwards, so that the inbounds
+/// stay the same when hot switching.
+pub fn choose_api_port(port: i32) -> i32 {
+    if let Ok(port) = u16::try_from(port) {
+        if port != 0 && TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port as i32;
+        }
+    }
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|i| i.local_addr())
+        .map(|i| i.port() as i32)
+        .unwrap_or(port)
+}
+
+/// 核心 API 的地址，供统计与出站管理使用。
+/// the address of the core API, used by stats and outbound management.
+pub fn get_api_server(api_port: i32) -> String {
+    format!("127.0.0.1:{}", api_port)
+}
+
+fn get_inbound_object(config: &AppConfig, api_port: i32) -> Vec<InboundObject> {
+    // 路由规则把该入站转发到 API，统计与出站管理都经由它访问核心。API 只监听本地回环地址。
+    // the routing rule forwards this inbound to the API, stats and outbound management reach the core through it.
+    // The API only listens on loopback.
+    let mut result = vec![InboundObject {
+        port: api_port,
+        listen: "127.0.0.1".to_string(),
+        protocol: "dokodemo-door".to_string(),
+        settings: InboundConfigurationObject::Dokodemo {
+            address: "127.0.0.1".to_string(),
+        },
+        tag: "V2Neko_API_INBOUND".to_string(),
+        sniffing: SniffingObject {
+            dest_override: Vec::new(),
+            enabled: false,
+        },
+    }];
     if config.sock5_status {
         result.push(InboundObject {
             port: config.socks_port,
@@ -364,9 +700,100 @@ fn get_inbound_object(config: &AppConfig) -> Vec<InboundObject> {
     result
 }
 
-pub fn generate(outbound: &Outbounds) -> String {
-    let config = read();
-    let bind = outbound.clone();
+/// 直连与拦截出站，排在代理出站之后，未匹配任何规则的流量仍然走代理。
+/// the direct and block outbounds, placed after the proxy outbound so that traffic matching no rule still goes
+/// through the proxy.
+fn get_builtin_outbounds() -> Vec<OutboundObject> {
+    let get_outbound = |protocol: &str, settings, tag: &str| OutboundObject {
+        send_through: None,
+        protocol: protocol.to_string(),
+        settings,
+        tag: tag.to_string(),
+        stream_settings: StreamSettingsObject::default(),
+        proxy_settings: None,
+        mux: MuxObject::default(),
+    };
+    vec![
+        get_outbound(
+            "freedom",
+            OutboundConfigurationObject::Freedom {
+                domain_strategy: "AsIs".to_string(),
+            },
+            DIRECT_TAG,
+        ),
+        get_outbound(
+            "blackhole",
+            OutboundConfigurationObject::Blackhole {
+                response: BlackholeResponseObject {
+                    response_type: "none".to_string(),
+                },
+            },
+            BLOCK_TAG,
+        ),
+    ]
+}
+
+/// 路由：先把 API 入站交给 API，再按顺序应用用户规则。
+/// 有 IP 规则时，域名在没有匹配的域名规则后解析为 IP 再匹配一次。
+/// the routing: the API inbound goes to the API first, then the user rules apply in order.
+/// When there are IP rules, a domain that matched no domain rule is resolved and matched again by IP.
+fn get_routing_object(rules: &[RuleObject]) -> RoutingObject {
+    let mut api = RuleObject::new("V2Neko_API");
+    api.inbound_tag = vec!["V2Neko_API_INBOUND".to_string()];
+    let domain_strategy = if rules.iter().any(|i| !i.ip.is_empty()) {
+        "IPIfNonMatch"
+    } else {
+        "AsIs"
+    };
+    RoutingObject {
+        domain_strategy: domain_strategy.to_string(),
+        domain_matcher: "mph".to_string(),
+        rules: std::iter::once(api).chain(rules.iter().cloned()).collect(),
+    }
+}
+
+/// 路由模式对应的规则。全局模式忽略用户规则，直连模式把全部流量交给直连出站，
+/// 绕过大陆模式在用户规则之后把局域网与中国大陆的域名和 IP 交给直连出站。
+/// the rules of a routing mode. The global mode ignores the user rules, the direct mode sends all traffic to
+/// the direct outbound, and the China bypass mode sends LAN and mainland China domains and IPs to the direct
+/// outbound after the user rules.
+fn get_mode_rules(mode: RoutingMode, rules: &[RuleObject]) -> Vec<RuleObject> {
+    match mode {
+        RoutingMode::Global => Vec::new(),
+        RoutingMode::Rule => rules.to_vec(),
+        RoutingMode::Direct => {
+            let mut direct = RuleObject::new(DIRECT_TAG);
+            direct.network = "tcp,udp".to_string();
+            vec![direct]
+        }
+        RoutingMode::BypassChina => {
+            let mut domain = RuleObject::new(DIRECT_TAG);
+            domain.domain = vec!["geosite:private".to_string(), "geosite:cn".to_string()];
+            let mut ip = RuleObject::new(DIRECT_TAG);
+            ip.ip = vec!["geoip:private".to_string(), "geoip:cn".to_string()];
+            rules.iter().cloned().chain(vec![domain, ip]).collect()
+        }
+    }
+}
+
+/// 按 `config` 生成核心的完整配置，API 入站监听 `api_port`，路由模式决定的规则按顺序排在内部的 API 规则之后。
+/// 代理出站的 TCP Fast Open 跟随配置。
+/// generate the full config of the core from `config` with the API inbound on `api_port`, and the rules decided
+/// by the routing mode in order after the internal API rule. TCP Fast Open of the proxy outbounds follows the config.
+pub fn generate(
+    config: &AppConfig,
+    api_port: i32,
+    outbound: &Outbounds,
+    rules: &[RuleObject],
+) -> String {
+    let rules = get_mode_rules(config.routing_mode, rules);
+    let mut bind = outbound.clone();
+    for i in &mut bind.outbounds {
+        if let Some(sockopt) = &mut i.stream_settings.sockopt {
+            sockopt.tcp_fast_open = config.tcp_fast_open;
+        }
+    }
+    bind.outbounds.extend(get_builtin_outbounds());
     let config = ConfigJson {
         api: ApiObject {
             tag: "V2Neko_API".to_owned(),
@@ -381,10 +808,10 @@ pub fn generate(outbound: &Outbounds) -> String {
             log_level: "error".to_string(),
         },
         dns: DnsObject {
-            server: config.dns.clone(),
+            servers: config.dns.clone(),
         },
-        inbounds: get_inbound_object(&config),
-        outbound: bind.outbounds,
+        inbounds: get_inbound_object(config, api_port),
+        outbounds: bind.outbounds,
         policy: PolicyObject {
             system: SystemPolicyObject {
                 stats_inbound_uplink: true,
@@ -393,31 +820,63 @@ pub fn generate(outbound: &Outbounds) -> String {
                 stats_outbound_downlink: true,
             },
         },
-        routing: RoutingObject {
-            domain_strategy: "mph".to_string(),
-            domain_matcher: "AsIs".to_string(),
-            rules: vec![RuleObject {
-                rule_type: "field".to_string(),
-                inbound_tag: vec!["V2Neko_API_INBOUND".to_string()],
-                outbound_tag: "V2Neko_API".to_string(),
-            }],
-        },
+        routing: get_routing_object(&rules),
         stats: StatsObject {},
     };
     serde_json::to_string_pretty(&config).unwrap()
 }
 
-fn parse_by_share_link_base64(link: &str) -> Result<String, ParseLinkError> {
-    let config = read();
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+struct ProbeConfigJson {
+    log: LogObject,
+    inbounds: Vec<InboundObject>,
+    outbounds: Vec<OutboundObject>,
+}
+
+/// 生成用于测试单个出站的临时配置：只有一个监听在本地端口上的 HTTP 入站。
+/// generate a temporary config for testing a single outbound, with only one HTTP inbound on a local port.
+pub fn generate_probe(outbound: &OutboundObject, port: i32) -> String {
+    let config = ProbeConfigJson {
+        log: LogObject {
+            log_level: "none".to_string(),
+        },
+        inbounds: vec![InboundObject {
+            port,
+            listen: "127.0.0.1".to_string(),
+            protocol: "http".to_string(),
+            settings: InboundConfigurationObject::Http {
+                timeout: Some(0),
+                accounts: None,
+                allow_transparent: false,
+                level: None,
+            },
+            tag: "PROBE_IN".to_string(),
+            sniffing: SniffingObject {
+                dest_override: Vec::new(),
+                enabled: false,
+            },
+        }],
+        outbounds: vec![outbound.clone()],
+    };
+    serde_json::to_string_pretty(&config).unwrap()
+}
+
+/// 解析 vmess:// 分享链接，返回出站配置与链接备注。
+/// parse a vmess:// share link into an outbound and its remark.
+fn parse_by_share_link_vmess(link: &str) -> Result<(OutboundObject, String), ParseLinkError> {
     if let Some(data) = link.to_lowercase().find("vmess://") {
-        let decoded = general_purpose::STANDARD.decode(&link[data + 8..]);
-        if decoded.is_err() {
-            return Err(ParseLinkError {
-                msg: decoded.err().unwrap().to_string(),
-                code: super::error::ParseLinkErrorCode::Base64Error,
-            });
-        }
-        let from_utf8 = String::from_utf8(decoded.unwrap()).unwrap();
+        // 很多链接没有填充或使用 URL 安全的字符。
+        // many links are unpadded or use the URL safe alphabet.
+        let decoded = decode_base64_lenient(&link[data + 8..])?;
+        let from_utf8 = match String::from_utf8(decoded) {
+            Ok(i) => i,
+            Err(e) => {
+                return Err(ParseLinkError {
+                    msg: e.to_string(),
+                    code: super::error::ParseLinkErrorCode::Base64Error,
+                })
+            }
+        };
         let json = serde_json::from_str::<Base64LinkObject>(&from_utf8);
         if json.is_err() {
             return Err(ParseLinkError {
@@ -426,113 +885,175 @@ fn parse_by_share_link_base64(link: &str) -> Result<String, ParseLinkError> {
             });
         }
         let json = json.ok().unwrap();
-        let outbound = Outbounds {
-            outbounds: vec![OutboundObject {
-                send_through: None,
-                protocol: json.base64_type.clone(),
-                settings: OutboundConfigurationObject::Vmess {
-                    vnext: vec![VmessServerObject {
-                        address: json.add,
-                        port: json.port,
-                        users: vec![UserObject {
-                            id: json.id,
-                            alter_id: Some(json.aid),
-                            level: None,
-                            security: json.scy.unwrap_or("auto".to_string()),
-                        }],
+        let name = json.ps.clone();
+        // 没有 sni 时使用伪装域名作为 TLS 的服务器名称，CDN 后面的节点需要它才能握手。
+        // the camouflage host serves as the TLS server name when sni is missing, nodes behind a CDN
+        // need it to complete the handshake.
+        let tls_settings = if json.tls == "tls" {
+            let host = match json.net.as_str() {
+                "quic" => None,
+                _ => json
+                    .host
+                    .as_deref()
+                    .and_then(|i| i.split(',').next())
+                    .map(|i| i.trim().to_string()),
+            };
+            Some(TlsObject {
+                server_name: json
+                    .sni
+                    .clone()
+                    .filter(|i| !i.is_empty())
+                    .or(host)
+                    .filter(|i| !i.is_empty()),
+                allow_insecure: None,
+                alpn: None,
+                fingerprint: None,
+            })
+        } else {
+            None
+        };
+        let outbound = OutboundObject {
+            send_through: None,
+            protocol: "vmess".to_string(),
+            settings: OutboundConfigurationObject::Vmess {
+                vnext: vec![VmessServerObject {
+                    address: json.add,
+                    port: json.port,
+                    users: vec![UserObject {
+                        id: json.id,
+                        alter_id: Some(json.aid),
+                        level: None,
+                        security: json.scy.unwrap_or("auto".to_string()),
                     }],
-                },
-                tag: "PROXY".to_string(),
-                stream_settings: StreamSettingsObject {
-                    network: json.net.clone(),
-                    security: Some(json.tls),
-                    tcp_settings: if json.net.as_str() == "tcp" {
-                        match json.base64_type.as_str() {
-                            "none" => Some(TcpObject {
-                                header: TcpHeaderObject::NoneHeaderObject {
-                                    tcp_type: "none".to_string(),
-                                },
-                            }),
-                            "http" => Some(TcpObject {
-                                header: TcpHeaderObject::HttpHeaderObject {
-                                    tcp_type: "http".to_string(),
-                                    request: None,
-                                    response: None,
-                                },
-                            }),
-
-                            _ => None,
-                        }
-                    } else {
-                        None
-                    },
-                    kcp_settings: if json.net == "kcp" {
-                        Some(KcpObject {
-                            header: KcpHeaderObject {
-                                kcp_type: json.base64_type,
+                }],
+            },
+            tag: "PROXY".to_string(),
+            stream_settings: StreamSettingsObject {
+                network: json.net.clone(),
+                security: Some(json.tls),
+                tcp_settings: if json.net.as_str() == "tcp" {
+                    match json.base64_type.as_str() {
+                        "none" => Some(TcpObject {
+                            header: TcpHeaderObject::NoneHeaderObject {
+                                tcp_type: "none".to_string(),
                             },
-                            mtu: None,
-                            tti: None,
-                            uplink_capacity: None,
-                            downlink_capacity: None,
-                            congestion: None,
-                            read_buffer_size: None,
-                            write_buffer_size: None,
-                        })
-                    } else {
-                        None
-                    },
-                    ws_settings: if json.net == "ws" {
-                        Some(WebSocketObject {
-                            path: json.path.clone(),
-                            headers: None,
-                        })
-                    } else {
-                        None
-                    },
-                    http_settings: if json.net == "h2" {
-                        Some(HttpObject {
-                            host: vec![json.host.clone().unwrap()],
-                            path: json.path.clone(),
-                        })
-                    } else {
-                        None
-                    },
-                    ds_settings: None,
-                    quic_settings: if json.net == "quic" {
-                        Some(QUICObject {
-                            security: json.host.unwrap(),
-                            key: json.path.unwrap(),
-                            header: None,
-                        })
-                    } else {
-                        None
-                    },
-                    sockopt: Some(SockoptObject {
-                        mark: 0,
-                        tcp_fast_open: config.tcp_fast_open,
-                        tproxy: "off".to_string(),
-                    }),
+                        }),
+                        "http" => Some(TcpObject {
+                            header: TcpHeaderObject::HttpHeaderObject {
+                                tcp_type: "http".to_string(),
+                                request: None,
+                                response: None,
+                            },
+                        }),
+
+                        _ => None,
+                    }
+                } else {
+                    None
                 },
-                proxy_settings: None,
-                mux: MuxObject {
-                    enabled: None,
-                    concurrency: None,
+                kcp_settings: if json.net == "kcp" {
+                    Some(KcpObject {
+                        header: KcpHeaderObject {
+                            kcp_type: json.base64_type,
+                        },
+                        mtu: None,
+                        tti: None,
+                        uplink_capacity: None,
+                        downlink_capacity: None,
+                        congestion: None,
+                        read_buffer_size: None,
+                        write_buffer_size: None,
+                        seed: None,
+                    })
+                } else {
+                    None
                 },
-            }],
+                ws_settings: if json.net == "ws" {
+                    Some(WebSocketObject {
+                        path: json.path.clone(),
+                        headers: None,
+                    })
+                } else {
+                    None
+                },
+                http_settings: if json.net == "h2" {
+                    Some(HttpObject {
+                        host: json
+                            .host
+                            .clone()
+                            .into_iter()
+                            .filter(|i| !i.is_empty())
+                            .collect(),
+                        path: json.path.clone(),
+                    })
+                } else {
+                    None
+                },
+                ds_settings: None,
+                quic_settings: if json.net == "quic" {
+                    Some(QUICObject {
+                        // 缺少时使用 Xray 的默认值。
+                        // fall back to the defaults of Xray when missing.
+                        security: json.host.unwrap_or_else(|| "none".to_string()),
+                        key: json.path.unwrap_or_default(),
+                        header: None,
+                    })
+                } else {
+                    None
+                },
+                grpc_settings: None,
+                tls_settings,
+                reality_settings: None,
+                sockopt: Some(SockoptObject {
+                    mark: 0,
+                    tcp_fast_open: false,
+                    tproxy: "off".to_string(),
+                }),
+            },
+            proxy_settings: None,
+            mux: MuxObject {
+                enabled: None,
+                concurrency: None,
+            },
         };
-        return Ok(generate(&outbound));
+        return Ok((outbound, name));
     }
     Err(ParseLinkError {
-        msg: r#""#.to_string(),
+        msg: "not a vmess link".to_string(),
         code: super::error::ParseLinkErrorCode::LinkError,
     })
 }
 
-fn generate_share_link_base64(outbound: &OutboundObject, name: &str) -> String {
-    let OutboundConfigurationObject::Vmess { vnext } = &outbound.settings;
-    let vnext = &vnext[0];
-    let user_object = vnext.users[0].clone();
+/// 由 VMess 出站配置生成 vmess:// 分享链接，缺少的传输层配置按默认值处理。
+/// generate a vmess:// share link from a vmess outbound, missing transport settings fall back to the defaults.
+fn generate_share_link_base64(
+    outbound: &OutboundObject,
+    name: &str,
+) -> Result<String, GenerateLinkError> {
+    let vnext = match &outbound.settings {
+        OutboundConfigurationObject::Vmess { vnext } if !vnext.is_empty() => &vnext[0],
+        _ => {
+            return Err(GenerateLinkError {
+                msg: "not a vmess outbound".to_string(),
+            })
+        }
+    };
+    let user_object = match vnext.users.first() {
+        Some(i) => i.clone(),
+        None => {
+            return Err(GenerateLinkError {
+                msg: "vmess outbound without user".to_string(),
+            })
+        }
+    };
+    let stream = &outbound.stream_settings;
+    let request = match stream.tcp_settings.as_ref().map(|i| &i.header) {
+        Some(TcpHeaderObject::HttpHeaderObject { request, .. }) => Some(request.as_ref()),
+        _ => None,
+    };
+    let ws = stream.ws_settings.as_ref();
+    let http = stream.http_settings.as_ref();
+    let quic = stream.quic_settings.as_ref();
     let result = Base64LinkObject {
         v: 2.to_string(),
         ps: name.to_owned(),
@@ -544,146 +1065,859 @@ fn generate_share_link_base64(outbound: &OutboundObject, name: &str) -> String {
             "auto" => None,
             _ => Some(user_object.security),
         },
-        net: if outbound.stream_settings.network == "http" {
+        net: if stream.network == "http" {
             "h2".to_owned()
         } else {
-            outbound.stream_settings.network.clone()
+            stream.network.clone()
         },
-        base64_type: match outbound.stream_settings.network.as_str() {
-            "tcp" => match outbound
-                .stream_settings
-                .tcp_settings
-                .as_ref()
-                .unwrap()
-                .header
-            {
-                TcpHeaderObject::NoneHeaderObject { tcp_type: _ } => "none".to_string(),
-                TcpHeaderObject::HttpHeaderObject {
-                    tcp_type: _,
-                    request: _,
-                    response: _,
-                } => "http".to_string(),
-            },
-            "kcp" => outbound
-                .stream_settings
+        base64_type: match stream.network.as_str() {
+            "tcp" if request.is_some() => "http".to_string(),
+            "kcp" => stream
                 .kcp_settings
                 .as_ref()
-                .unwrap()
-                .header
-                .kcp_type
-                .clone(),
-            "quic" => outbound
-                .stream_settings
-                .quic_settings
-                .as_ref()
-                .unwrap()
-                .security
-                .clone(),
+                .map(|i| i.header.kcp_type.clone())
+                .unwrap_or_else(|| "none".to_string()),
+            "quic" => quic
+                .and_then(|i| i.header.as_ref())
+                .map(|i| i.quic_type.clone())
+                .unwrap_or_else(|| "none".to_string()),
             _ => "none".to_owned(),
         },
-        host: match outbound.stream_settings.network.as_str() {
-            "tcp" => {
-                if let TcpHeaderObject::HttpHeaderObject {
-                    tcp_type,
-                    request,
-                    response,
-                } = &outbound
-                    .stream_settings
-                    .tcp_settings
-                    .as_ref()
-                    .unwrap()
-                    .header
-                {
-                    Some(request.as_ref().unwrap().path[0].clone())
-                } else {
-                    None
-                }
-            }
-            "ws" => {
-                let mut map: HashMap<String, String> = HashMap::new();
-                map.insert("Host".to_owned(), "".to_owned());
-                Some(
-                    outbound
-                        .stream_settings
-                        .ws_settings
-                        .as_ref()
-                        .unwrap()
-                        .headers
-                        .as_ref()
-                        .unwrap_or(&map)["Host"]
-                        .clone(),
-                )
-            }
-            "http" => Some(
-                outbound
-                    .stream_settings
-                    .http_settings
-                    .as_ref()
-                    .unwrap()
-                    .host[0]
-                    .clone(),
+        host: match stream.network.as_str() {
+            "tcp" => request
+                .flatten()
+                .and_then(|i| i.headers.get("Host"))
+                .map(|i| i.join(",")),
+            "ws" => Some(
+                ws.and_then(|i| i.headers.as_ref())
+                    .and_then(|i| i.get("Host"))
+                    .cloned()
+                    .unwrap_or_default(),
             ),
+            "http" => Some(http.map(|i| i.host.join(",")).unwrap_or_default()),
             "quic" => Some(
-                outbound
-                    .stream_settings
-                    .quic_settings
-                    .as_ref()
-                    .unwrap()
-                    .security
-                    .clone(),
+                quic.map(|i| i.security.clone())
+                    .unwrap_or_else(|| "none".to_string()),
             ),
             _ => Some("".to_owned()),
         },
-        path: match outbound.stream_settings.network.as_str() {
-            "ws" => Some(
-                outbound
-                    .stream_settings
-                    .ws_settings
-                    .as_ref()
-                    .unwrap()
-                    .path
-                    .as_ref()
-                    .unwrap()
-                    .clone(),
-            ),
-            "http" => Some(
-                outbound
-                    .stream_settings
-                    .http_settings
-                    .as_ref()
-                    .unwrap()
-                    .path
-                    .as_ref()
-                    .unwrap()
-                    .clone(),
-            ),
-            "quic" => Some(
-                outbound
-                    .stream_settings
-                    .quic_settings
-                    .as_ref()
-                    .unwrap()
-                    .key
-                    .clone(),
-            ),
+        path: match stream.network.as_str() {
+            "tcp" => request
+                .flatten()
+                .filter(|i| !i.path.is_empty())
+                .map(|i| i.path.join(",")),
+            "ws" => ws.and_then(|i| i.path.clone()),
+            "http" => http.and_then(|i| i.path.clone()),
+            "quic" => quic.map(|i| i.key.clone()),
             _ => None,
         },
-        tls: outbound.stream_settings.security.as_ref().unwrap().clone(),
-        sni: None,
+        tls: stream
+            .security
+            .clone()
+            .unwrap_or_else(|| "none".to_string()),
+        sni: stream
+            .tls_settings
+            .as_ref()
+            .and_then(|i| i.server_name.clone()),
     };
-    let raw_data = serde_json::to_string(&result).unwrap();
+    let raw_data =
+        serde_json::to_string(&result).map_err(|e| GenerateLinkError { msg: e.to_string() })?;
     let encoded_data = general_purpose::STANDARD.encode(raw_data);
-    format!("vmess://{}", encoded_data)
+    Ok(format!("vmess://{}", encoded_data))
+}
+
+/// 分享链接中用户信息与备注需要转义的字符。
+/// Characters escaped in the userinfo and remark of a share link.
+const LINK_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn url_error(msg: &str) -> ParseLinkError {
+    ParseLinkError {
+        msg: msg.to_string(),
+        code: super::error::ParseLinkErrorCode::UrlError,
+    }
+}
+
+/// 从分享链接中读取服务器地址、端口与备注。
+/// Read the server address, port and remark of a share link.
+fn get_server_from_url(url: &Url) -> Result<(String, i32, String), ParseLinkError> {
+    let address = match url.host() {
+        Some(Host::Ipv6(addr)) => addr.to_string(),
+        Some(host) => host.to_string(),
+        None => return Err(url_error("missing server address")),
+    };
+    let port = url.port().ok_or_else(|| url_error("missing server port"))?;
+    let name = percent_decode_str(url.fragment().unwrap_or(""))
+        .decode_utf8_lossy()
+        .to_string();
+    Ok((address, port as i32, name))
+}
+
+/// 拼接 `scheme://userinfo@host:port?query#name` 形式的分享链接。
+/// Assemble a `scheme://userinfo@host:port?query#name` share link.
+fn format_share_link(
+    scheme: &str,
+    userinfo: &str,
+    address: &str,
+    port: i32,
+    query: &[(&str, String)],
+    name: &str,
+) -> String {
+    let mut link = if address.contains(':') {
+        format!("{}://{}@[{}]:{}", scheme, userinfo, address, port)
+    } else {
+        format!("{}://{}@{}:{}", scheme, userinfo, address, port)
+    };
+    if !query.is_empty() {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        link = format!("{}?{}", link, query);
+    }
+    if !name.is_empty() {
+        link = format!("{}#{}", link, utf8_percent_encode(name, LINK_ENCODE_SET));
+    }
+    link
+}
+
+/// 根据 vless:// 与 trojan:// 链接共用的查询参数生成传输层配置。
+/// Build the stream settings from the query parameters shared by vless:// and trojan:// links.
+fn get_stream_settings_from_query(
+    query: &HashMap<String, String>,
+    default_security: &str,
+) -> Result<StreamSettingsObject, ParseLinkError> {
+    let get = |key: &str| query.get(key).filter(|i| !i.is_empty()).cloned();
+    let split =
+        |value: String| -> Vec<String> { value.split(',').map(|i| i.to_string()).collect() };
+
+    let network = match get("type").as_deref().unwrap_or("tcp") {
+        "h2" | "http" => "http".to_string(),
+        i @ ("tcp" | "kcp" | "ws" | "quic" | "grpc") => i.to_string(),
+        i => return Err(url_error(&format!("unsupported transport: {}", i))),
+    };
+    let security = get("security").unwrap_or_else(|| default_security.to_string());
+    let header_type = get("headerType").unwrap_or_else(|| "none".to_string());
+
+    let mut stream = StreamSettingsObject {
+        network: network.clone(),
+        security: Some(security.clone()),
+        tcp_settings: None,
+        kcp_settings: None,
+        ws_settings: None,
+        http_settings: None,
+        ds_settings: None,
+        quic_settings: None,
+        grpc_settings: None,
+        tls_settings: None,
+        reality_settings: None,
+        sockopt: Some(SockoptObject {
+            mark: 0,
+            tcp_fast_open: false,
+            tproxy: "off".to_string(),
+        }),
+    };
+    match network.as_str() {
+        "tcp" => {
+            stream.tcp_settings = Some(TcpObject {
+                header: if header_type == "http" {
+                    let mut headers = HashMap::new();
+                    if let Some(host) = get("host") {
+                        headers.insert("Host".to_string(), split(host));
+                    }
+                    TcpHeaderObject::HttpHeaderObject {
+                        tcp_type: "http".to_string(),
+                        request: Some(Box::new(HTTPRequestObject {
+                            version: "1.1".to_string(),
+                            method: "GET".to_string(),
+                            path: get("path").map(split).unwrap_or_default(),
+                            headers,
+                        })),
+                        response: None,
+                    }
+                } else {
+                    TcpHeaderObject::NoneHeaderObject {
+                        tcp_type: "none".to_string(),
+                    }
+                },
+            })
+        }
+        "kcp" => {
+            stream.kcp_settings = Some(KcpObject {
+                mtu: None,
+                tti: None,
+                uplink_capacity: None,
+                downlink_capacity: None,
+                congestion: None,
+                read_buffer_size: None,
+                write_buffer_size: None,
+                seed: get("seed"),
+                header: KcpHeaderObject {
+                    kcp_type: header_type,
+                },
+            })
+        }
+        "ws" => {
+            stream.ws_settings = Some(WebSocketObject {
+                path: get("path"),
+                headers: get("host").map(|host| HashMap::from([("Host".to_string(), host)])),
+            })
+        }
+        "http" => {
+            stream.http_settings = Some(HttpObject {
+                host: get("host").map(split).unwrap_or_default(),
+                path: get("path"),
+            })
+        }
+        "quic" => {
+            stream.quic_settings = Some(QUICObject {
+                security: get("quicSecurity").unwrap_or_else(|| "none".to_string()),
+                key: get("key").unwrap_or_default(),
+                header: Some(QUICHeaderObject {
+                    quic_type: header_type,
+                }),
+            })
+        }
+        _ => {
+            stream.grpc_settings = Some(GrpcObject {
+                service_name: get("serviceName").unwrap_or_default(),
+                multi_mode: match get("mode").as_deref() {
+                    Some("multi") => Some(true),
+                    Some("gun") => Some(false),
+                    _ => None,
+                },
+            })
+        }
+    }
+    match security.as_str() {
+        "none" => {}
+        "tls" => {
+            stream.tls_settings = Some(TlsObject {
+                server_name: get("sni"),
+                allow_insecure: get("allowInsecure").map(|i| i == "1" || i == "true"),
+                alpn: get("alpn").map(split),
+                fingerprint: get("fp"),
+            })
+        }
+        "reality" => {
+            stream.reality_settings = Some(RealityObject {
+                server_name: get("sni"),
+                fingerprint: get("fp"),
+                public_key: get("pbk").ok_or_else(|| url_error("reality link without pbk"))?,
+                short_id: get("sid"),
+                spider_x: get("spx"),
+            })
+        }
+        i => return Err(url_error(&format!("unsupported security: {}", i))),
+    }
+    Ok(stream)
+}
+
+/// 由协议设置与传输层查询参数组装出站配置，供各种导入格式复用。
+/// assemble an outbound from its protocol settings and transport query parameters,
+/// shared by the different import formats.
+fn get_outbound_from_query(
+    protocol: &str,
+    settings: OutboundConfigurationObject,
+    query: &HashMap<String, String>,
+) -> Result<OutboundObject, ParseLinkError> {
+    Ok(OutboundObject {
+        send_through: None,
+        protocol: protocol.to_string(),
+        settings,
+        tag: "PROXY".to_string(),
+        stream_settings: get_stream_settings_from_query(query, "none")?,
+        proxy_settings: None,
+        mux: MuxObject {
+            enabled: None,
+            concurrency: None,
+        },
+    })
+}
+
+/// 将传输层配置转换回分享链接的查询参数。
+/// Convert stream settings back into the query parameters of a share link.
+fn get_query_from_stream_settings(stream: &StreamSettingsObject) -> Vec<(&'static str, String)> {
+    let security = stream
+        .security
+        .clone()
+        .unwrap_or_else(|| "none".to_string());
+    let mut query = vec![("security", security)];
+    if let Some(tls) = &stream.tls_settings {
+        if let Some(i) = &tls.server_name {
+            query.push(("sni", i.clone()));
+        }
+        if let Some(i) = &tls.alpn {
+            query.push(("alpn", i.join(",")));
+        }
+        if let Some(i) = &tls.fingerprint {
+            query.push(("fp", i.clone()));
+        }
+        if let Some(i) = tls.allow_insecure {
+            query.push(("allowInsecure", if i { "1" } else { "0" }.to_string()));
+        }
+    }
+    if let Some(reality) = &stream.reality_settings {
+        if let Some(i) = &reality.server_name {
+            query.push(("sni", i.clone()));
+        }
+        if let Some(i) = &reality.fingerprint {
+            query.push(("fp", i.clone()));
+        }
+        query.push(("pbk", reality.public_key.clone()));
+        if let Some(i) = &reality.short_id {
+            query.push(("sid", i.clone()));
+        }
+        if let Some(i) = &reality.spider_x {
+            query.push(("spx", i.clone()));
+        }
+    }
+    query.push(("type", stream.network.clone()));
+    match stream.network.as_str() {
+        "tcp" => {
+            if let Some(TcpObject {
+                header:
+                    TcpHeaderObject::HttpHeaderObject {
+                        tcp_type, request, ..
+                    },
+            }) = &stream.tcp_settings
+            {
+                query.push(("headerType", tcp_type.clone()));
+                if let Some(request) = request {
+                    if let Some(host) = request.headers.get("Host") {
+                        query.push(("host", host.join(",")));
+                    }
+                    if !request.path.is_empty() {
+                        query.push(("path", request.path.join(",")));
+                    }
+                }
+            }
+        }
+        "kcp" => {
+            if let Some(kcp) = &stream.kcp_settings {
+                if kcp.header.kcp_type != "none" {
+                    query.push(("headerType", kcp.header.kcp_type.clone()));
+                }
+                if let Some(i) = &kcp.seed {
+                    query.push(("seed", i.clone()));
+                }
+            }
+        }
+        "ws" => {
+            if let Some(ws) = &stream.ws_settings {
+                if let Some(host) = ws.headers.as_ref().and_then(|i| i.get("Host")) {
+                    query.push(("host", host.clone()));
+                }
+                if let Some(i) = &ws.path {
+                    query.push(("path", i.clone()));
+                }
+            }
+        }
+        "http" => {
+            if let Some(http) = &stream.http_settings {
+                if !http.host.is_empty() {
+                    query.push(("host", http.host.join(",")));
+                }
+                if let Some(i) = &http.path {
+                    query.push(("path", i.clone()));
+                }
+            }
+        }
+        "quic" => {
+            if let Some(quic) = &stream.quic_settings {
+                if let Some(header) = &quic.header {
+                    if header.quic_type != "none" {
+                        query.push(("headerType", header.quic_type.clone()));
+                    }
+                }
+                query.push(("quicSecurity", quic.security.clone()));
+                if !quic.key.is_empty() {
+                    query.push(("key", quic.key.clone()));
+                }
+            }
+        }
+        "grpc" => {
+            if let Some(grpc) = &stream.grpc_settings {
+                query.push(("serviceName", grpc.service_name.clone()));
+                match grpc.multi_mode {
+                    Some(true) => query.push(("mode", "multi".to_string())),
+                    Some(false) => query.push(("mode", "gun".to_string())),
+                    None => {}
+                }
+            }
+        }
+        _ => {}
+    }
+    query
+}
+
+/// 解析 vless:// 分享链接，返回出站配置与链接备注。
+/// parse a vless:// share link into an outbound and its remark.
+fn parse_by_share_link_vless(link: &str) -> Result<(OutboundObject, String), ParseLinkError> {
+    let url = Url::parse(link.trim()).map_err(|e| url_error(&e.to_string()))?;
+    if url.scheme() != "vless" {
+        return Err(ParseLinkError {
+            msg: "not a vless link".to_string(),
+            code: super::error::ParseLinkErrorCode::LinkError,
+        });
+    }
+    let (address, port, name) = get_server_from_url(&url)?;
+    let id = percent_decode_str(url.username())
+        .decode_utf8_lossy()
+        .to_string();
+    if id.is_empty() {
+        return Err(url_error("missing user id"));
+    }
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let get = |key: &str| query.get(key).filter(|i| !i.is_empty()).cloned();
+    let outbound = OutboundObject {
+        send_through: None,
+        protocol: "vless".to_string(),
+        settings: OutboundConfigurationObject::Vless {
+            vnext: vec![VlessServerObject {
+                address,
+                port,
+                users: vec![VlessUserObject {
+                    id,
+                    encryption: get("encryption").unwrap_or_else(|| "none".to_string()),
+                    flow: get("flow"),
+                    level: None,
+                }],
+            }],
+        },
+        tag: "PROXY".to_string(),
+        stream_settings: get_stream_settings_from_query(&query, "none")?,
+        proxy_settings: None,
+        mux: MuxObject {
+            enabled: None,
+            concurrency: None,
+        },
+    };
+    Ok((outbound, name))
+}
+
+/// 由 VLESS 出站配置生成 vless:// 分享链接。
+/// generate a vless:// share link from a vless outbound.
+fn generate_share_link_vless(
+    outbound: &OutboundObject,
+    name: &str,
+) -> Result<String, GenerateLinkError> {
+    let vnext = match &outbound.settings {
+        OutboundConfigurationObject::Vless { vnext }
+            if !vnext.is_empty() && !vnext[0].users.is_empty() =>
+        {
+            &vnext[0]
+        }
+        _ => {
+            return Err(GenerateLinkError {
+                msg: "not a vless outbound".to_string(),
+            })
+        }
+    };
+    let user = &vnext.users[0];
+    let mut query = vec![("encryption", user.encryption.clone())];
+    if let Some(flow) = &user.flow {
+        query.push(("flow", flow.clone()));
+    }
+    query.extend(get_query_from_stream_settings(&outbound.stream_settings));
+    Ok(format_share_link(
+        "vless",
+        &utf8_percent_encode(&user.id, LINK_ENCODE_SET).to_string(),
+        &vnext.address,
+        vnext.port,
+        &query,
+        name,
+    ))
+}
+
+/// 解析 trojan:// 分享链接，返回出站配置与链接备注。
+/// parse a trojan:// share link into an outbound and its remark.
+fn parse_by_share_link_trojan(link: &str) -> Result<(OutboundObject, String), ParseLinkError> {
+    let url = Url::parse(link.trim()).map_err(|e| url_error(&e.to_string()))?;
+    if url.scheme() != "trojan" {
+        return Err(ParseLinkError {
+            msg: "not a trojan link".to_string(),
+            code: super::error::ParseLinkErrorCode::LinkError,
+        });
+    }
+    let (address, port, name) = get_server_from_url(&url)?;
+    let password = percent_decode_str(url.username())
+        .decode_utf8_lossy()
+        .to_string();
+    if password.is_empty() {
+        return Err(url_error("missing password"));
+    }
+    let mut query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    // 旧版客户端使用 peer 代替 sni。
+    // older clients put the server name in `peer` instead of `sni`.
+    if let Some(peer) = query.remove("peer") {
+        query.entry("sni".to_string()).or_insert(peer);
+    }
+    let outbound = OutboundObject {
+        send_through: None,
+        protocol: "trojan".to_string(),
+        settings: OutboundConfigurationObject::Trojan {
+            servers: vec![TrojanServerObject {
+                address,
+                port,
+                password,
+                email: None,
+                level: None,
+            }],
+        },
+        tag: "PROXY".to_string(),
+        stream_settings: get_stream_settings_from_query(&query, "tls")?,
+        proxy_settings: None,
+        mux: MuxObject {
+            enabled: None,
+            concurrency: None,
+        },
+    };
+    Ok((outbound, name))
+}
+
+/// 由 Trojan 出站配置生成 trojan:// 分享链接。
+/// generate a trojan:// share link from a trojan outbound.
+fn generate_share_link_trojan(
+    outbound: &OutboundObject,
+    name: &str,
+) -> Result<String, GenerateLinkError> {
+    let server = match &outbound.settings {
+        OutboundConfigurationObject::Trojan { servers } if !servers.is_empty() => &servers[0],
+        _ => {
+            return Err(GenerateLinkError {
+                msg: "not a trojan outbound".to_string(),
+            })
+        }
+    };
+    Ok(format_share_link(
+        "trojan",
+        &utf8_percent_encode(&server.password, LINK_ENCODE_SET).to_string(),
+        &server.address,
+        server.port,
+        &get_query_from_stream_settings(&outbound.stream_settings),
+        name,
+    ))
+}
+
+/// 宽松地解码 base64，同时兼容标准与 URL 安全字母表以及有无填充的情况。
+/// Decode base64 leniently, accepting both the standard and url-safe alphabets with or without padding.
+pub fn decode_base64_lenient(data: &str) -> Result<Vec<u8>, ParseLinkError> {
+    let normalized: String = data
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .filter(|i| !i.is_whitespace())
+        .map(|i| match i {
+            '-' => '+',
+            '_' => '/',
+            i => i,
+        })
+        .collect();
+    general_purpose::STANDARD_NO_PAD
+        .decode(normalized)
+        .map_err(|e| ParseLinkError {
+            msg: e.to_string(),
+            code: super::error::ParseLinkErrorCode::Base64Error,
+        })
+}
+
+/// 拆分 `host:port` 或 `[ipv6]:port`。
+/// Split `host:port` or `[ipv6]:port`.
+fn split_host_port(host_port: &str) -> Result<(String, i32), ParseLinkError> {
+    let (host, port) = host_port
+        .rsplit_once(':')
+        .ok_or_else(|| url_error("missing server port"))?;
+    let port = port.parse::<u16>().map_err(|e| url_error(&e.to_string()))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(url_error("missing server address"));
+    }
+    Ok((host.to_string(), port as i32))
+}
+
+/// 将 SIP003 插件参数转换为等价的传输层查询参数。
+/// Translate SIP003 plugin options into the equivalent transport query parameters.
+fn get_query_from_plugin(plugin: &str) -> Result<HashMap<String, String>, ParseLinkError> {
+    let mut parts = plugin.split(';');
+    let plugin_name = parts.next().unwrap_or_default();
+    let mut options = HashMap::new();
+    for i in parts {
+        match i.split_once('=') {
+            Some((key, value)) => options.insert(key.to_string(), value.to_string()),
+            None => options.insert(i.to_string(), String::new()),
+        };
+    }
+    let mut query = HashMap::new();
+    match plugin_name {
+        "obfs-local" | "simple-obfs" => {
+            if options.get("obfs").map(|i| i.as_str()) != Some("http") {
+                return Err(url_error("only obfs=http is supported by obfs-local"));
+            }
+            query.insert("type".to_string(), "tcp".to_string());
+            query.insert("headerType".to_string(), "http".to_string());
+            if let Some(host) = options.remove("obfs-host") {
+                query.insert("host".to_string(), host);
+            }
+            if let Some(uri) = options.remove("obfs-uri") {
+                query.insert("path".to_string(), uri);
+            }
+        }
+        "v2ray-plugin" => {
+            if !matches!(
+                options.get("mode").map(|i| i.as_str()),
+                None | Some("websocket")
+            ) {
+                return Err(url_error(
+                    "only websocket mode is supported by v2ray-plugin",
+                ));
+            }
+            query.insert("type".to_string(), "ws".to_string());
+            if let Some(host) = options.remove("host") {
+                if options.contains_key("tls") {
+                    query.insert("sni".to_string(), host.clone());
+                }
+                query.insert("host".to_string(), host);
+            }
+            if options.contains_key("tls") {
+                query.insert("security".to_string(), "tls".to_string());
+            }
+            if let Some(path) = options.remove("path") {
+                query.insert("path".to_string(), path);
+            }
+        }
+        i => return Err(url_error(&format!("unsupported plugin: {}", i))),
+    }
+    Ok(query)
+}
+
+/// 将传输层配置转换回 SIP003 插件参数，纯 TCP 时返回 None。
+/// Convert stream settings back into a SIP003 plugin string, or None for plain tcp.
+fn get_plugin_from_stream_settings(
+    stream: &StreamSettingsObject,
+) -> Result<Option<String>, GenerateLinkError> {
+    let unsupported = || GenerateLinkError {
+        msg: format!("transport {} has no shadowsocks plugin", stream.network),
+    };
+    match stream.network.as_str() {
+        "tcp" => match &stream.tcp_settings {
+            Some(TcpObject {
+                header:
+                    TcpHeaderObject::HttpHeaderObject {
+                        tcp_type, request, ..
+                    },
+            }) if tcp_type == "http" => {
+                let mut plugin = "obfs-local;obfs=http".to_string();
+                if let Some(request) = request {
+                    if let Some(host) = request.headers.get("Host") {
+                        plugin = format!("{};obfs-host={}", plugin, host.join(","));
+                    }
+                    if !request.path.is_empty() {
+                        plugin = format!("{};obfs-uri={}", plugin, request.path.join(","));
+                    }
+                }
+                Ok(Some(plugin))
+            }
+            Some(TcpObject {
+                header: TcpHeaderObject::NoneHeaderObject { tcp_type },
+            }) if tcp_type == "http" => Ok(Some("obfs-local;obfs=http".to_string())),
+            _ => Ok(None),
+        },
+        "ws" => {
+            let mut plugin = "v2ray-plugin".to_string();
+            if stream.security.as_deref() == Some("tls") {
+                plugin = format!("{};tls", plugin);
+            }
+            if let Some(ws) = &stream.ws_settings {
+                if let Some(host) = ws.headers.as_ref().and_then(|i| i.get("Host")) {
+                    plugin = format!("{};host={}", plugin, host);
+                }
+                if let Some(path) = &ws.path {
+                    plugin = format!("{};path={}", plugin, path);
+                }
+            }
+            Ok(Some(plugin))
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+/// 解析 ss:// 分享链接，支持 SIP002 与旧版整段 base64 两种格式。
+/// parse a ss:// share link in either the SIP002 or the legacy fully-base64 form.
+fn parse_by_share_link_shadowsocks(link: &str) -> Result<(OutboundObject, String), ParseLinkError> {
+    let link = link.trim();
+    let rest = match link.get(..5) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("ss://") => &link[5..],
+        _ => {
+            return Err(ParseLinkError {
+                msg: "not a shadowsocks link".to_string(),
+                code: super::error::ParseLinkErrorCode::LinkError,
+            })
+        }
+    };
+    let (rest, name) = match rest.split_once('#') {
+        Some((rest, fragment)) => (
+            rest,
+            percent_decode_str(fragment).decode_utf8_lossy().to_string(),
+        ),
+        None => (rest, String::new()),
+    };
+    let (main, query) = match rest.split_once('?') {
+        Some((main, query)) => (main, query),
+        None => (rest, ""),
+    };
+    let main = main.trim_end_matches('/');
+    let (user_info, host_port) = match main.rsplit_once('@') {
+        Some((user_info, host_port)) => {
+            let decoded = percent_decode_str(user_info)
+                .decode_utf8_lossy()
+                .to_string();
+            if decoded.contains(':') {
+                (decoded, host_port.to_string())
+            } else {
+                let decoded = decode_base64_lenient(&decoded)?;
+                (
+                    String::from_utf8_lossy(&decoded).to_string(),
+                    host_port.to_string(),
+                )
+            }
+        }
+        None => {
+            let decoded = decode_base64_lenient(main)?;
+            let decoded = String::from_utf8_lossy(&decoded).to_string();
+            let (user_info, host_port) = decoded
+                .rsplit_once('@')
+                .ok_or_else(|| url_error("missing server address"))?;
+            (user_info.to_string(), host_port.to_string())
+        }
+    };
+    let (method, password) = user_info
+        .split_once(':')
+        .ok_or_else(|| url_error("missing method or password"))?;
+    let (address, port) = split_host_port(&host_port)?;
+
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let query = match params.get("plugin").filter(|i| !i.is_empty()) {
+        Some(plugin) => get_query_from_plugin(plugin)?,
+        None => HashMap::new(),
+    };
+    let outbound = OutboundObject {
+        send_through: None,
+        protocol: "shadowsocks".to_string(),
+        settings: OutboundConfigurationObject::Shadowsocks {
+            servers: vec![ShadowsocksServerObject {
+                address,
+                port,
+                method: method.to_lowercase(),
+                password: password.to_string(),
+                uot: params.get("uot").map(|i| i == "1" || i == "true"),
+                email: None,
+                level: None,
+            }],
+        },
+        tag: "PROXY".to_string(),
+        stream_settings: get_stream_settings_from_query(&query, "none")?,
+        proxy_settings: None,
+        mux: MuxObject {
+            enabled: None,
+            concurrency: None,
+        },
+    };
+    Ok((outbound, name))
+}
+
+/// 由 Shadowsocks 出站配置生成 SIP002 格式的 ss:// 分享链接。
+/// generate a SIP002 ss:// share link from a shadowsocks outbound.
+fn generate_share_link_shadowsocks(
+    outbound: &OutboundObject,
+    name: &str,
+) -> Result<String, GenerateLinkError> {
+    let server = match &outbound.settings {
+        OutboundConfigurationObject::Shadowsocks { servers } if !servers.is_empty() => &servers[0],
+        _ => {
+            return Err(GenerateLinkError {
+                msg: "not a shadowsocks outbound".to_string(),
+            })
+        }
+    };
+    // SIP022 方法不允许对用户信息做 base64 编码。
+    // SIP022 methods must not base64 encode the userinfo.
+    let user_info = if server.method.starts_with("2022-") {
+        format!(
+            "{}:{}",
+            utf8_percent_encode(&server.method, LINK_ENCODE_SET),
+            utf8_percent_encode(&server.password, LINK_ENCODE_SET)
+        )
+    } else {
+        general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}", server.method, server.password))
+    };
+    let mut query = Vec::new();
+    if let Some(plugin) = get_plugin_from_stream_settings(&outbound.stream_settings)? {
+        query.push(("plugin", plugin));
+    }
+    if server.uot == Some(true) {
+        query.push(("uot", "1".to_string()));
+    }
+    Ok(format_share_link(
+        "ss",
+        &user_info,
+        &server.address,
+        server.port,
+        &query,
+        name,
+    ))
+}
+
+/// 按出站的协议生成对应的分享链接，`name` 作为链接备注。
+/// generate the share link matching the protocol of the outbound, with `name` as the remark.
+pub fn generate_share_link(
+    outbound: &OutboundObject,
+    name: &str,
+) -> Result<String, GenerateLinkError> {
+    match outbound.protocol() {
+        "vmess" => generate_share_link_base64(outbound, name),
+        "vless" => generate_share_link_vless(outbound, name),
+        "trojan" => generate_share_link_trojan(outbound, name),
+        "shadowsocks" => generate_share_link_shadowsocks(outbound, name),
+        i => Err(GenerateLinkError {
+            msg: format!("unsupported protocol: {}", i),
+        }),
+    }
+}
+
+/// 根据链接前缀解析任意受支持的分享链接，返回出站配置与链接备注。
+/// parse any supported share link by its scheme into an outbound and its remark.
+pub fn parse_share_link(link: &str) -> Result<(OutboundObject, String), ParseLinkError> {
+    let link = link.trim();
+    let scheme = link
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_lowercase())
+        .unwrap_or_default();
+    match scheme.as_str() {
+        "vmess" => parse_by_share_link_vmess(link),
+        "vless" => parse_by_share_link_vless(link),
+        "trojan" => parse_by_share_link_trojan(link),
+        "ss" => parse_by_share_link_shadowsocks(link),
+        _ => Err(ParseLinkError {
+            msg: format!("unsupported link: {}", scheme),
+            code: super::error::ParseLinkErrorCode::LinkError,
+        }),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::vmess::generate;
-
     use super::*;
+    use crate::config::get_default_config;
     #[test]
     fn test_generate_v2config() {
         let a = generate(
+            &get_default_config(),
+            10085,
             &serde_json::from_str(
                 r#"{
     "outbounds": [
@@ -721,18 +1955,407 @@ mod tests {
 "#,
             )
             .unwrap(),
+            &[],
         );
-        println!("{}", a);
+        let config = serde_json::from_str::<serde_json::Value>(&a).unwrap();
+
+        // API 入站在最前面，之后是本地的 socks 与 http 入站。
+        // the API inbound comes first, followed by the local socks and http inbounds.
+        let inbounds = config["inbounds"].as_array().unwrap();
+        assert_eq!(inbounds.len(), 3);
+        assert_eq!(inbounds[0]["tag"], "V2Neko_API_INBOUND");
+        assert_eq!(inbounds[0]["port"], 10085);
+        assert_eq!(inbounds[1]["protocol"], "socks");
+        assert_eq!(inbounds[1]["port"], 11451);
+        assert_eq!(inbounds[2]["protocol"], "http");
+        assert_eq!(inbounds[2]["port"], 11452);
+        assert!(inbounds.iter().all(|i| i["listen"] == "127.0.0.1"));
+
+        // 代理出站之后是直连与阻断出站。
+        // the proxy outbound is followed by the direct and block outbounds.
+        let outbounds = config["outbounds"].as_array().unwrap();
+        let tags = outbounds
+            .iter()
+            .map(|i| i["tag"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(tags, [PROXY_TAG, DIRECT_TAG, BLOCK_TAG]);
+        assert_eq!(outbounds[0]["protocol"], "vmess");
+        let server = &outbounds[0]["settings"]["vnext"][0];
+        assert_eq!(server["address"], "0kxedm1x8q8lksmj11.xingbayun.buzz");
+        assert_eq!(server["port"], 12003);
+        assert_eq!(server["users"][0]["security"], "aes-128-gcm");
+        assert_eq!(outbounds[1]["protocol"], "freedom");
+        assert_eq!(outbounds[2]["protocol"], "blackhole");
+
+        // 没有用户规则时只有 API 的路由。
+        // without user rules only the API is routed.
+        let rules = config["routing"]["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0]["inboundTag"][0], "V2Neko_API_INBOUND");
+        assert_eq!(rules[0]["outboundTag"], "V2Neko_API");
     }
+
+    #[test]
+    fn test_generate_routing() {
+        let (outbound, _) =
+            parse_share_link("trojan://password@example.com:443?security=tls#name").unwrap();
+        let outbounds = Outbounds::new(vec![outbound]);
+        let mut direct = RuleObject::new(DIRECT_TAG);
+        direct.domain = vec!["geosite:cn".to_string()];
+        direct.port = "80,443".to_string();
+        let mut block = RuleObject::new(BLOCK_TAG);
+        block.protocol = vec!["bittorrent".to_string()];
+
+        let config = serde_json::from_str::<serde_json::Value>(&generate(
+            &get_default_config(),
+            10085,
+            &outbounds,
+            &[direct.clone(), block],
+        ))
+        .unwrap();
+        // 代理出站在最前面，作为默认出站。
+        // the proxy outbound comes first as the default outbound.
+        let tags = config["outbounds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["tag"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(tags, [PROXY_TAG, DIRECT_TAG, BLOCK_TAG]);
+        assert_eq!(config["outbounds"][1]["protocol"], "freedom");
+        assert_eq!(config["outbounds"][2]["protocol"], "blackhole");
+        assert_eq!(config["inbounds"][0]["tag"], "V2Neko_API_INBOUND");
+        assert_eq!(config["inbounds"][0]["port"], 10085);
+
+        let routing = &config["routing"];
+        assert_eq!(routing["domainStrategy"], "AsIs");
+        assert_eq!(routing["domainMatcher"], "mph");
+        let rules = routing["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0]["outboundTag"], "V2Neko_API");
+        assert_eq!(
+            rules[1],
+            serde_json::json!({
+                "type": "field",
+                "domain": ["geosite:cn"],
+                "port": "80,443",
+                "outboundTag": "DIRECT"
+            })
+        );
+        assert_eq!(rules[2]["protocol"][0], "bittorrent");
+        assert_eq!(rules[2]["outboundTag"], "BLOCK");
+
+        // IP 规则需要把域名解析为 IP 再匹配。
+        // IP rules need domains to be resolved and matched again.
+        direct.ip = vec!["geoip:cn".to_string()];
+        let config = serde_json::from_str::<ConfigJson>(&generate(
+            &get_default_config(),
+            10085,
+            &outbounds,
+            &[direct],
+        ))
+        .unwrap();
+        assert_eq!(config.routing.domain_strategy, "IPIfNonMatch");
+        assert!(matches!(
+            config.outbounds[2].settings,
+            OutboundConfigurationObject::Blackhole { .. }
+        ));
+    }
+
+    #[test]
+    fn test_get_mode_rules() {
+        let mut block = RuleObject::new(BLOCK_TAG);
+        block.protocol = vec!["bittorrent".to_string()];
+        let rules = [block.clone()];
+
+        assert!(get_mode_rules(RoutingMode::Global, &rules).is_empty());
+        assert_eq!(get_mode_rules(RoutingMode::Rule, &rules), rules);
+
+        let direct = get_mode_rules(RoutingMode::Direct, &rules);
+        assert_eq!(direct.len(), 1);
+        assert_eq!(direct[0].network, "tcp,udp");
+        assert_eq!(direct[0].outbound_tag, DIRECT_TAG);
+
+        // 用户规则优先于绕过规则。
+        // the user rules take precedence over the bypass rules.
+        let bypass = get_mode_rules(RoutingMode::BypassChina, &rules);
+        assert_eq!(bypass.len(), 3);
+        assert_eq!(bypass[0], block);
+        assert_eq!(bypass[1].domain, ["geosite:private", "geosite:cn"]);
+        assert_eq!(bypass[2].ip, ["geoip:private", "geoip:cn"]);
+        assert!(bypass[1..].iter().all(|i| i.outbound_tag == DIRECT_TAG));
+        assert_eq!(get_routing_object(&bypass).domain_strategy, "IPIfNonMatch");
+    }
+
+    #[test]
+    fn test_choose_api_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let used = listener.local_addr().unwrap().port() as i32;
+        let port = choose_api_port(used);
+        assert_ne!(port, used);
+        assert!(port > 0);
+        assert!(choose_api_port(-1) > 0);
+        drop(listener);
+        assert_eq!(choose_api_port(used), used);
+    }
+
     #[test]
     fn test_parse_link_base64() {
         let link = "vmess://ewogICJ2IjogIjIiLAogICJwcyI6ICIyIiwKICAiYWRkIjogIjIwLjI0LjczLjE2NCIsCiAgInBvcnQiOiA4MCwKICAiaWQiOiAiYzdjMWM5ODUtOTQyMS00ZDBmLWZhMTktMGVmZGE4MDM0M2FmIiwKICAiYWlkIjogMCwKICAibmV0IjogIndzIiwKICAidHlwZSI6ICJub25lIiwKICAiaG9zdCI6ICIiLAogICJwYXRoIjogIi8iLAogICJ0bHMiOiAibm9uZSIKfQ==";
-        let a = parse_by_share_link_base64(link).unwrap();
-        let json = serde_json::from_str::<ConfigJson>(a.as_str()).unwrap();
-        // assert_eq!(a, "{\n  \"v\": \"2\",\n  \"ps\": \"2\",\n  \"add\": \"20.24.73.164\",\n  \"port\": 80,\n  \"id\": \"c7c1c985-9421-4d0f-fa19-0efda80343af\",\n  \"aid\": 0,\n  \"net\": \"ws\",\n  \"type\": \"none\",\n  \"host\": \"\",\n  \"path\": \"/\",\n  \"tls\": \"none\"\n}")
-        println!("{}", a);
-        let outbound = &json.outbound[0];
-        let sss = generate_share_link_base64(outbound, "a");
-        assert_eq!(link.to_owned(), sss)
+        let (outbound, name) = parse_by_share_link_vmess(link).unwrap();
+        assert_eq!(name, "2");
+        assert_eq!(outbound.server(), Some(("20.24.73.164".to_string(), 80)));
+
+        // 原链接是格式化过的 JSON，所以比较解码后的内容而不是链接本身。
+        // the original link holds pretty printed JSON, so the decoded contents are compared instead of the links.
+        let decode = |link: &str| {
+            let data = general_purpose::STANDARD
+                .decode(link.trim_start_matches("vmess://"))
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&data).unwrap()
+        };
+        let generated = generate_share_link_base64(&outbound, &name).unwrap();
+        assert_eq!(decode(&generated), decode(link));
+    }
+
+    #[test]
+    fn test_parse_link_base64_tls() {
+        let encode = |json: serde_json::Value| {
+            format!(
+                "vmess://{}",
+                general_purpose::URL_SAFE_NO_PAD.encode(json.to_string())
+            )
+        };
+        let server_name = |link: &str| {
+            let (outbound, _) = parse_by_share_link_vmess(link).unwrap();
+            assert_eq!(outbound.stream_settings.security.as_deref(), Some("tls"));
+            outbound.tls_server_name()
+        };
+        let mut json = serde_json::json!({
+            "v": "2",
+            "ps": "cdn",
+            "add": "104.16.0.1",
+            "port": 443,
+            "id": "c7c1c985-9421-4d0f-fa19-0efda80343af",
+            "aid": 0,
+            "net": "ws",
+            "type": "none",
+            "host": "cdn.example.com",
+            "path": "/ws",
+            "tls": "tls",
+            "sni": "sni.example.com"
+        });
+        // 没有填充、URL 安全的链接也能解析，sni 优先于伪装域名。
+        // unpadded URL safe links parse as well, and sni takes precedence over the host.
+        assert_eq!(
+            server_name(&encode(json.clone())).as_deref(),
+            Some("sni.example.com")
+        );
+        json["sni"] = "".into();
+        assert_eq!(
+            server_name(&encode(json.clone())).as_deref(),
+            Some("cdn.example.com")
+        );
+
+        let (outbound, name) = parse_by_share_link_vmess(&encode(json)).unwrap();
+        let generated = generate_share_link_base64(&outbound, &name).unwrap();
+        let (parsed, _) = parse_by_share_link_vmess(&generated).unwrap();
+        assert_eq!(parsed.tls_server_name().as_deref(), Some("cdn.example.com"));
+    }
+
+    #[test]
+    fn test_generate_share_link() {
+        // 每种协议的链接都能按协议生成并解析回同样的出站。
+        // the link of every protocol is generated by its protocol and parses back into the same outbound.
+        let links = [
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@example.com:443?encryption=none&security=tls&type=ws&path=%2F#vless",
+            "trojan://password@example.com:443?security=tls&type=tcp#trojan",
+            "ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888#ss",
+        ];
+        for link in links {
+            let (outbound, name) = parse_share_link(link).unwrap();
+            assert_eq!(generate_share_link(&outbound, &name).unwrap(), link);
+        }
+
+        // vmess 链接中缺少的传输层配置不会导致 panic。
+        // missing transport settings in a vmess outbound do not panic.
+        let data = r#"{"v":"2","ps":"vmess","add":"example.com","port":443,"id":"c7c1c985-9421-4d0f-fa19-0efda80343af","aid":0,"net":"tcp","type":"http","tls":"tls"}"#;
+        let link = format!("vmess://{}", general_purpose::STANDARD.encode(data));
+        let (outbound, name) = parse_share_link(&link).unwrap();
+        let generated = generate_share_link(&outbound, &name).unwrap();
+        let (again, _) = parse_share_link(&generated).unwrap();
+        assert_eq!(
+            serde_json::to_value(&again).unwrap(),
+            serde_json::to_value(&outbound).unwrap()
+        );
+
+        assert!(generate_share_link(&get_builtin_outbounds()[0], "direct").is_err());
+    }
+
+    #[test]
+    fn test_vless_link_round_trip() {
+        let links = [
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@example.com:443?encryption=none&flow=xtls-rprx-vision&security=reality&sni=www.microsoft.com&fp=chrome&pbk=Z84J2IelR9ch3k8VtlVhhs5ycBUlXA7wHBWcBrjqnAw&sid=6ba85179e30d4fc2&spx=%2F&type=tcp#%E9%A6%99%E6%B8%AF%2001",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@example.com:443?encryption=none&security=tls&sni=example.com&alpn=h2%2Chttp%2F1.1&fp=firefox&allowInsecure=1&type=ws&host=cdn.example.com&path=%2Fws%3Fed%3D2048#ws",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@[2001:db8::1]:8443?encryption=none&security=reality&sni=www.apple.com&pbk=Z84J2IelR9ch3k8VtlVhhs5ycBUlXA7wHBWcBrjqnAw&type=grpc&serviceName=grpc-svc&mode=multi#grpc",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@1.2.3.4:80?encryption=none&security=none&type=tcp&headerType=http&host=a.com%2Cb.com&path=%2Fa%2C%2Fb#tcp-http",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@1.2.3.4:1080?encryption=none&security=none&type=kcp&headerType=wechat-video&seed=neko#kcp",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@1.2.3.4:1443?encryption=none&security=tls&sni=example.com&type=quic&headerType=srtp&quicSecurity=aes-128-gcm&key=k#quic",
+            "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@1.2.3.4:2443?encryption=none&security=tls&type=http&host=a.com&path=%2Fh2#h2",
+        ];
+        for link in links {
+            let (outbound, name) = parse_by_share_link_vless(link).unwrap();
+            assert_eq!(generate_share_link_vless(&outbound, &name).unwrap(), link);
+        }
+    }
+
+    #[test]
+    fn test_parse_link_vless() {
+        let link = "vless://c7c1c985-9421-4d0f-fa19-0efda80343af@example.com:443?security=reality&pbk=key&sni=www.microsoft.com&flow=xtls-rprx-vision&type=grpc&serviceName=svc#name";
+        let (outbound, name) = parse_by_share_link_vless(link).unwrap();
+        assert_eq!(name, "name");
+        assert_eq!(outbound.protocol, "vless");
+        let vnext = match &outbound.settings {
+            OutboundConfigurationObject::Vless { vnext } => vnext,
+            _ => panic!("not a vless outbound"),
+        };
+        assert_eq!(vnext[0].address, "example.com");
+        assert_eq!(vnext[0].port, 443);
+        assert_eq!(vnext[0].users[0].encryption, "none");
+        assert_eq!(vnext[0].users[0].flow.as_deref(), Some("xtls-rprx-vision"));
+        let stream = &outbound.stream_settings;
+        assert_eq!(stream.network, "grpc");
+        assert_eq!(stream.reality_settings.as_ref().unwrap().public_key, "key");
+        assert_eq!(stream.grpc_settings.as_ref().unwrap().service_name, "svc");
+
+        assert!(parse_by_share_link_vless("vless://example.com:443").is_err());
+        assert!(parse_by_share_link_vless("vless://id@example.com:443?security=reality").is_err());
+        assert!(parse_by_share_link_vless("vless://id@example.com:443?type=foo").is_err());
+    }
+    #[test]
+    fn test_trojan_link_round_trip() {
+        let links = [
+            "trojan://p%40ssw0rd@example.com:443?security=tls&sni=example.com&type=tcp#trojan",
+            "trojan://password@example.com:443?security=tls&sni=cdn.example.com&alpn=h2&fp=chrome&allowInsecure=0&type=ws&host=cdn.example.com&path=%2Ftrojan#%E6%97%A5%E6%9C%AC",
+            "trojan://password@[2001:db8::1]:443?security=tls&type=grpc&serviceName=trojan-grpc&mode=gun#grpc",
+        ];
+        for link in links {
+            let (outbound, name) = parse_by_share_link_trojan(link).unwrap();
+            assert_eq!(generate_share_link_trojan(&outbound, &name).unwrap(), link);
+        }
+    }
+
+    #[test]
+    fn test_parse_link_trojan() {
+        let (outbound, name) = parse_by_share_link_trojan(
+            "trojan://p%40ss@example.com:443?peer=sni.example.com#a%20b",
+        )
+        .unwrap();
+        assert_eq!(name, "a b");
+        assert_eq!(outbound.protocol, "trojan");
+        match &outbound.settings {
+            OutboundConfigurationObject::Trojan { servers } => {
+                assert_eq!(servers[0].password, "p@ss");
+                assert_eq!(servers[0].port, 443);
+            }
+            _ => panic!("not a trojan outbound"),
+        }
+        let stream = &outbound.stream_settings;
+        assert_eq!(stream.network, "tcp");
+        assert_eq!(stream.security.as_deref(), Some("tls"));
+        assert_eq!(
+            stream.tls_settings.as_ref().unwrap().server_name.as_deref(),
+            Some("sni.example.com")
+        );
+        assert!(generate_share_link_vless(&outbound, &name).is_err());
+        assert!(parse_by_share_link_trojan("trojan://example.com:443").is_err());
+
+        let config = generate(
+            &get_default_config(),
+            10085,
+            &Outbounds {
+                outbounds: vec![outbound],
+            },
+            &[],
+        );
+        let json = serde_json::from_str::<ConfigJson>(&config).unwrap();
+        assert!(matches!(
+            json.outbounds[0].settings,
+            OutboundConfigurationObject::Trojan { .. }
+        ));
+    }
+    #[test]
+    fn test_shadowsocks_link_round_trip() {
+        let links = [
+            "ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888#Example1",
+            "ss://2022-blake3-aes-256-gcm:YctPZ6U7xPPcU%2Bgp3u%2B0tx%2FtRizJN9K8y%2BuKlW2qjlI%3D@192.168.100.1:8888#Example3",
+            "ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.com#Example2",
+            "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTp0ZXN0@[2001:db8::1]:443?plugin=v2ray-plugin%3Btls%3Bhost%3Dexample.com%3Bpath%3D%2Fws&uot=1#v2ray",
+        ];
+        for link in links {
+            let (outbound, name) = parse_by_share_link_shadowsocks(link).unwrap();
+            assert_eq!(
+                generate_share_link_shadowsocks(&outbound, &name).unwrap(),
+                link
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_link_shadowsocks_legacy() {
+        let (outbound, name) = parse_by_share_link_shadowsocks(
+            "ss://YmYtY2ZiOnRlc3RAMTkyLjE2OC4xMDAuMTo4ODg4#example-server",
+        )
+        .unwrap();
+        assert_eq!(name, "example-server");
+        match &outbound.settings {
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                assert_eq!(servers[0].method, "bf-cfb");
+                assert_eq!(servers[0].password, "test");
+                assert_eq!(servers[0].address, "192.168.100.1");
+                assert_eq!(servers[0].port, 8888);
+            }
+            _ => panic!("not a shadowsocks outbound"),
+        }
+        assert_eq!(
+            generate_share_link_shadowsocks(&outbound, &name).unwrap(),
+            "ss://YmYtY2ZiOnRlc3Q@192.168.100.1:8888#example-server"
+        );
+
+        // 带填充的标准 base64 用户信息与末尾斜杠。
+        // padded standard base64 userinfo with a trailing slash.
+        let (outbound, _) = parse_by_share_link_shadowsocks(
+            "ss://YWVzLTI1Ni1nY206cGFzcy93b3JkPw==@example.com:8388/?plugin=v2ray-plugin%3Bpath%3D%2F",
+        )
+        .unwrap();
+        assert_eq!(outbound.stream_settings.network, "ws");
+        match &outbound.settings {
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                assert_eq!(servers[0].password, "pass/word?")
+            }
+            _ => panic!("not a shadowsocks outbound"),
+        }
+
+        let config = generate(
+            &get_default_config(),
+            10085,
+            &Outbounds {
+                outbounds: vec![outbound],
+            },
+            &[],
+        );
+        let json = serde_json::from_str::<ConfigJson>(&config).unwrap();
+        assert!(matches!(
+            json.outbounds[0].settings,
+            OutboundConfigurationObject::Shadowsocks { .. }
+        ));
+
+        assert!(parse_by_share_link_shadowsocks(
+            "ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1:8888?plugin=obfs-local%3Bobfs%3Dtls"
+        )
+        .is_err());
+        assert!(
+            parse_by_share_link_shadowsocks("ss://YWVzLTEyOC1nY206dGVzdA@192.168.100.1").is_err()
+        );
     }
 }
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{
+    get_outbound_from_query, get_query_from_plugin, url_error, ImportEntry,
+    OutboundConfigurationObject, OutboundObject, ShadowsocksServerObject, TrojanServerObject,
+    UserObject, VlessServerObject, VlessUserObject, VmessServerObject,
+};
+use crate::vmess::error::{ParseLinkError, ParseLinkErrorCode};
+
+/// Clash 配置中的一个代理条目，只包含能映射到 Xray 出站的字段。
+/// a proxy entry of a Clash profile, limited to the fields that map onto an Xray outbound.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct ClashProxy {
+    name: String,
+    #[serde(rename = "type")]
+    proxy_type: String,
+    server: String,
+    #[serde(deserialize_with = "deserialize_port")]
+    port: i32,
+    uuid: Option<String>,
+    #[serde(rename = "alterId")]
+    alter_id: Option<i32>,
+    cipher: Option<String>,
+    password: Option<String>,
+    flow: Option<String>,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    skip_cert_verify: bool,
+    servername: Option<String>,
+    sni: Option<String>,
+    client_fingerprint: Option<String>,
+    alpn: Option<Vec<String>>,
+    network: Option<String>,
+    ws_opts: Option<WsOpts>,
+    grpc_opts: Option<GrpcOpts>,
+    h2_opts: Option<H2Opts>,
+    http_opts: Option<HttpOpts>,
+    reality_opts: Option<RealityOpts>,
+    plugin: Option<String>,
+    plugin_opts: Option<PluginOpts>,
+    udp_over_tcp: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct WsOpts {
+    path: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    max_early_data: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct GrpcOpts {
+    grpc_service_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct H2Opts {
+    host: Option<Vec<String>>,
+    path: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HttpOpts {
+    path: Option<Vec<String>>,
+    headers: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct RealityOpts {
+    public_key: String,
+    short_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PluginOpts {
+    mode: Option<String>,
+    host: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    tls: bool,
+}
+
+#[derive(Deserialize)]
+struct ClashProfile {
+    #[serde(alias = "Proxy")]
+    proxies: Vec<serde_yaml::Value>,
+}
+
+/// 端口既可能是数字也可能是字符串。
+/// the port may be written either as a number or as a string.
+fn deserialize_port<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(i32),
+        Text(String),
+    }
+    match Port::deserialize(deserializer)? {
+        Port::Number(i) => Ok(i),
+        Port::Text(i) => i.trim().parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// 解析 Clash / Clash.Meta 配置中的 `proxies:` 部分，逐条转换为出站配置。
+/// parse the `proxies:` section of a Clash / Clash.Meta profile into outbounds, entry by entry.
+pub fn parse_clash_proxies(content: &str) -> Result<Vec<ImportEntry>, ParseLinkError> {
+    let profile = serde_yaml::from_str::<ClashProfile>(content).map_err(|e| ParseLinkError {
+        msg: e.to_string(),
+        code: ParseLinkErrorCode::YamlError,
+    })?;
+    Ok(profile
+        .proxies
+        .into_iter()
+        .map(|value| {
+            let name = value
+                .get("name")
+                .and_then(|i| i.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let proxy = serde_yaml::from_value::<ClashProxy>(value)
+                .map_err(|e| (name.clone(), e.to_string()))?;
+            get_outbound(&proxy)
+                .map(|outbound| (outbound, proxy.name.clone()))
+                .map_err(|e| (proxy.name.clone(), e.msg))
+        })
+        .collect())
+}
+
+/// 将 Clash 的传输层与 TLS 选项转换为分享链接的查询参数，以复用链接的解析逻辑。
+/// translate the Clash transport and tls options into share link query parameters,
+/// so that the link parsing logic can be reused.
+fn get_query(proxy: &ClashProxy) -> Result<HashMap<String, String>, ParseLinkError> {
+    let mut query = HashMap::new();
+    match proxy.network.as_deref().unwrap_or("tcp") {
+        "tcp" => {}
+        "ws" => {
+            query.insert("type".to_string(), "ws".to_string());
+            if let Some(opts) = &proxy.ws_opts {
+                let mut path = opts.path.clone().unwrap_or_else(|| "/".to_string());
+                if let Some(max_early_data) = opts.max_early_data.filter(|i| *i > 0) {
+                    if !path.contains("ed=") {
+                        path = format!("{}?ed={}", path, max_early_data);
+                    }
+                }
+                query.insert("path".to_string(), path);
+                if let Some(host) = opts.headers.as_ref().and_then(|i| i.get("Host")) {
+                    query.insert("host".to_string(), host.clone());
+                }
+            }
+        }
+        "grpc" => {
+            query.insert("type".to_string(), "grpc".to_string());
+            if let Some(name) = proxy
+                .grpc_opts
+                .as_ref()
+                .and_then(|i| i.grpc_service_name.clone())
+            {
+                query.insert("serviceName".to_string(), name);
+            }
+        }
+        "h2" => {
+            query.insert("type".to_string(), "http".to_string());
+            if let Some(opts) = &proxy.h2_opts {
+                if let Some(host) = &opts.host {
+                    query.insert("host".to_string(), host.join(","));
+                }
+                if let Some(path) = &opts.path {
+                    query.insert("path".to_string(), path.clone());
+                }
+            }
+        }
+        "http" => {
+            query.insert("type".to_string(), "tcp".to_string());
+            query.insert("headerType".to_string(), "http".to_string());
+            if let Some(opts) = &proxy.http_opts {
+                if let Some(path) = &opts.path {
+                    query.insert("path".to_string(), path.join(","));
+                }
+                if let Some(host) = opts.headers.as_ref().and_then(|i| i.get("Host")) {
+                    query.insert("host".to_string(), host.join(","));
+                }
+            }
+        }
+        i => return Err(url_error(&format!("unsupported network: {}", i))),
+    }
+
+    let security = if proxy.reality_opts.is_some() {
+        "reality"
+    } else if proxy.tls || proxy.proxy_type == "trojan" {
+        "tls"
+    } else {
+        "none"
+    };
+    query.insert("security".to_string(), security.to_string());
+    if let Some(sni) = proxy.servername.clone().or_else(|| proxy.sni.clone()) {
+        query.insert("sni".to_string(), sni);
+    }
+    if let Some(fp) = &proxy.client_fingerprint {
+        query.insert("fp".to_string(), fp.clone());
+    }
+    if security == "tls" {
+        if let Some(alpn) = &proxy.alpn {
+            query.insert("alpn".to_string(), alpn.join(","));
+        }
+        if proxy.skip_cert_verify {
+            query.insert("allowInsecure".to_string(), "1".to_string());
+        }
+    }
+    if let Some(opts) = &proxy.reality_opts {
+        query.insert("pbk".to_string(), opts.public_key.clone());
+        if let Some(sid) = &opts.short_id {
+            query.insert("sid".to_string(), sid.clone());
+        }
+    }
+    Ok(query)
+}
+
+/// 将 Shadowsocks 的插件选项转换为 SIP003 插件参数字符串。
+/// translate the Shadowsocks plugin options into a SIP003 plugin string.
+fn get_plugin(proxy: &ClashProxy) -> Option<String> {
+    let plugin = proxy.plugin.as_deref()?;
+    let opts = proxy.plugin_opts.as_ref();
+    let mut result = match plugin {
+        "obfs" => format!(
+            "obfs-local;obfs={}",
+            opts.and_then(|i| i.mode.clone()).unwrap_or_default()
+        ),
+        "v2ray-plugin" => {
+            let mut result = format!(
+                "v2ray-plugin;mode={}",
+                opts.and_then(|i| i.mode.clone())
+                    .unwrap_or_else(|| "websocket".to_string())
+            );
+            if opts.map(|i| i.tls).unwrap_or(false) {
+                result = format!("{};tls", result);
+            }
+            if let Some(path) = opts.and_then(|i| i.path.clone()) {
+                result = format!("{};path={}", result, path);
+            }
+            result
+        }
+        i => i.to_string(),
+    };
+    if let Some(host) = opts.and_then(|i| i.host.clone()) {
+        let key = if plugin == "obfs" {
+            "obfs-host"
+        } else {
+            "host"
+        };
+        result = format!("{};{}={}", result, key, host);
+    }
+    Some(result)
+}
+
+fn get_outbound(proxy: &ClashProxy) -> Result<OutboundObject, ParseLinkError> {
+    let missing = |field: &str| url_error(&format!("missing {}", field));
+    let (protocol, settings, query) = match proxy.proxy_type.as_str() {
+        "vmess" => (
+            "vmess",
+            OutboundConfigurationObject::Vmess {
+                vnext: vec![VmessServerObject {
+                    address: proxy.server.clone(),
+                    port: proxy.port,
+                    users: vec![UserObject {
+                        id: proxy.uuid.clone().ok_or_else(|| missing("uuid"))?,
+                        alter_id: Some(proxy.alter_id.unwrap_or(0)),
+                        level: None,
+                        security: proxy.cipher.clone().unwrap_or_else(|| "auto".to_string()),
+                    }],
+                }],
+            },
+            get_query(proxy)?,
+        ),
+        "vless" => (
+            "vless",
+            OutboundConfigurationObject::Vless {
+                vnext: vec![VlessServerObject {
+                    address: proxy.server.clone(),
+                    port: proxy.port,
+                    users: vec![VlessUserObject {
+                        id: proxy.uuid.clone().ok_or_else(|| missing("uuid"))?,
+                        encryption: "none".to_string(),
+                        flow: proxy.flow.clone().filter(|i| !i.is_empty()),
+                        level: None,
+                    }],
+                }],
+            },
+            get_query(proxy)?,
+        ),
+        "trojan" => (
+            "trojan",
+            OutboundConfigurationObject::Trojan {
+                servers: vec![TrojanServerObject {
+                    address: proxy.server.clone(),
+                    port: proxy.port,
+                    password: proxy.password.clone().ok_or_else(|| missing("password"))?,
+                    email: None,
+                    level: None,
+                }],
+            },
+            get_query(proxy)?,
+        ),
+        "ss" => (
+            "shadowsocks",
+            OutboundConfigurationObject::Shadowsocks {
+                servers: vec![ShadowsocksServerObject {
+                    address: proxy.server.clone(),
+                    port: proxy.port,
+                    method: proxy.cipher.clone().ok_or_else(|| missing("cipher"))?,
+                    password: proxy.password.clone().ok_or_else(|| missing("password"))?,
+                    uot: proxy.udp_over_tcp,
+                    email: None,
+                    level: None,
+                }],
+            },
+            match get_plugin(proxy) {
+                Some(plugin) => get_query_from_plugin(&plugin)?,
+                None => HashMap::new(),
+            },
+        ),
+        i => return Err(url_error(&format!("unsupported proxy type: {}", i))),
+    };
+    get_outbound_from_query(protocol, settings, &query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = r#"
+port: 7890
+mode: rule
+proxies:
+  - name: "vmess-ws"
+    type: vmess
+    server: vmess.example.com
+    port: 443
+    uuid: c7c1c985-9421-4d0f-fa19-0efda80343af
+    alterId: 0
+    cipher: auto
+    tls: true
+    servername: cdn.example.com
+    network: ws
+    ws-opts:
+      path: /ws
+      headers:
+        Host: cdn.example.com
+      max-early-data: 2048
+  - name: "vless-reality"
+    type: vless
+    server: 1.2.3.4
+    port: "8443"
+    uuid: c7c1c985-9421-4d0f-fa19-0efda80343af
+    flow: xtls-rprx-vision
+    tls: true
+    servername: www.microsoft.com
+    client-fingerprint: chrome
+    network: grpc
+    grpc-opts:
+      grpc-service-name: grpc
+    reality-opts:
+      public-key: Z84J2IelR9ch3k8VtlVhhs5ycBUlXA7wHBWcBrjqnAw
+      short-id: 6ba85179e30d4fc2
+  - name: "vless-h2"
+    type: vless
+    server: h2.example.com
+    port: 443
+    uuid: c7c1c985-9421-4d0f-fa19-0efda80343af
+    tls: true
+    network: h2
+    h2-opts:
+      host: [h2.example.com]
+      path: /h2
+  - name: "trojan"
+    type: trojan
+    server: trojan.example.com
+    port: 443
+    password: secret
+    sni: trojan.example.com
+    skip-cert-verify: true
+    alpn: [h2, http/1.1]
+  - name: "ss-obfs"
+    type: ss
+    server: ss.example.com
+    port: 8388
+    cipher: aes-128-gcm
+    password: test
+    plugin: obfs
+    plugin-opts:
+      mode: http
+      host: bing.com
+  - name: "ss-v2ray"
+    type: ss
+    server: ss.example.com
+    port: 443
+    cipher: chacha20-ietf-poly1305
+    password: test
+    udp-over-tcp: true
+    plugin: v2ray-plugin
+    plugin-opts:
+      mode: websocket
+      tls: true
+      host: ws.example.com
+      path: /ray
+  - name: "vmess-http"
+    type: vmess
+    server: vmess.example.com
+    port: 80
+    uuid: c7c1c985-9421-4d0f-fa19-0efda80343af
+    cipher: aes-128-gcm
+    network: http
+    http-opts:
+      path: [/a, /b]
+      headers:
+        Host: [a.com]
+  - name: "hy2"
+    type: hysteria2
+    server: hy2.example.com
+    port: 443
+    password: secret
+  - name: "ss-obfs-tls"
+    type: ss
+    server: ss.example.com
+    port: 8388
+    cipher: aes-128-gcm
+    password: test
+    plugin: obfs
+    plugin-opts:
+      mode: tls
+  - name: "broken"
+    type: vmess
+    server: broken.example.com
+"#;
+
+    #[test]
+    fn test_parse_clash_proxies() {
+        let entries = parse_clash_proxies(PROFILE).unwrap();
+        assert_eq!(entries.len(), 10);
+        let (ok, skipped): (Vec<_>, Vec<_>) = entries.into_iter().partition(|i| i.is_ok());
+        let ok: Vec<(OutboundObject, String)> = ok.into_iter().map(|i| i.unwrap()).collect();
+        let skipped: Vec<(String, String)> =
+            skipped.into_iter().map(|i| i.err().unwrap()).collect();
+
+        let names: Vec<&str> = ok.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "vmess-ws",
+                "vless-reality",
+                "vless-h2",
+                "trojan",
+                "ss-obfs",
+                "ss-v2ray",
+                "vmess-http"
+            ]
+        );
+        let skipped_names: Vec<&str> = skipped.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(skipped_names, ["hy2", "ss-obfs-tls", "broken"]);
+        assert!(skipped[0].1.contains("hysteria2"));
+        assert!(skipped[1].1.contains("obfs"));
+        assert!(skipped[2].1.contains("port"));
+
+        let vmess = &ok[0].0;
+        assert_eq!(vmess.protocol, "vmess");
+        assert_eq!(vmess.stream_settings.network, "ws");
+        assert_eq!(vmess.stream_settings.security.as_deref(), Some("tls"));
+        let ws = vmess.stream_settings.ws_settings.as_ref().unwrap();
+        assert_eq!(ws.path.as_deref(), Some("/ws?ed=2048"));
+        assert_eq!(ws.headers.as_ref().unwrap()["Host"], "cdn.example.com");
+
+        let vless = &ok[1].0;
+        match &vless.settings {
+            OutboundConfigurationObject::Vless { vnext } => {
+                assert_eq!(vnext[0].port, 8443);
+                assert_eq!(vnext[0].users[0].flow.as_deref(), Some("xtls-rprx-vision"));
+            }
+            _ => panic!("not a vless outbound"),
+        }
+        let reality = vless.stream_settings.reality_settings.as_ref().unwrap();
+        assert_eq!(reality.short_id.as_deref(), Some("6ba85179e30d4fc2"));
+        assert_eq!(reality.fingerprint.as_deref(), Some("chrome"));
+        assert_eq!(
+            vless
+                .stream_settings
+                .grpc_settings
+                .as_ref()
+                .unwrap()
+                .service_name,
+            "grpc"
+        );
+
+        let h2 = ok[2].0.stream_settings.http_settings.as_ref().unwrap();
+        assert_eq!(h2.host, ["h2.example.com"]);
+        assert_eq!(h2.path.as_deref(), Some("/h2"));
+
+        let trojan = ok[3].0.stream_settings.tls_settings.as_ref().unwrap();
+        assert_eq!(trojan.allow_insecure, Some(true));
+        assert_eq!(trojan.alpn.as_ref().unwrap(), &["h2", "http/1.1"]);
+
+        assert_eq!(ok[4].0.stream_settings.network, "tcp");
+        assert!(ok[4].0.stream_settings.tcp_settings.is_some());
+        let ss = &ok[5].0;
+        assert_eq!(ss.protocol, "shadowsocks");
+        assert_eq!(ss.stream_settings.network, "ws");
+        assert_eq!(ss.stream_settings.security.as_deref(), Some("tls"));
+        match &ss.settings {
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                assert_eq!(servers[0].uot, Some(true))
+            }
+            _ => panic!("not a shadowsocks outbound"),
+        }
+    }
+
+    #[test]
+    fn test_parse_clash_proxies_invalid() {
+        assert!(parse_clash_proxies("port: 7890").is_err());
+        assert!(parse_clash_proxies(": :").is_err());
+    }
+}
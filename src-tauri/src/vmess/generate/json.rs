@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{
+    get_outbound_from_query, get_query_from_plugin, url_error, ImportEntry,
+    OutboundConfigurationObject, OutboundObject, ShadowsocksServerObject, TrojanServerObject,
+    UserObject, VlessServerObject, VlessUserObject, VmessServerObject,
+};
+use crate::vmess::error::{ParseLinkError, ParseLinkErrorCode};
+
+/// 可以导入的代理协议，其余出站（freedom、blackhole、direct 等）都会被跳过。
+/// proxy protocols that can be imported, every other outbound (freedom, blackhole, direct...) is skipped.
+const PROXY_PROTOCOLS: [&str; 4] = ["vmess", "vless", "trojan", "shadowsocks"];
+
+#[derive(Deserialize, Debug)]
+struct SingBoxOutbound {
+    #[serde(rename = "type")]
+    outbound_type: String,
+    server: Option<String>,
+    server_port: Option<i32>,
+    uuid: Option<String>,
+    security: Option<String>,
+    alter_id: Option<i32>,
+    flow: Option<String>,
+    password: Option<String>,
+    method: Option<String>,
+    plugin: Option<String>,
+    plugin_opts: Option<String>,
+    udp_over_tcp: Option<serde_json::Value>,
+    tls: Option<SingBoxTls>,
+    transport: Option<SingBoxTransport>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SingBoxTls {
+    #[serde(default)]
+    enabled: bool,
+    server_name: Option<String>,
+    #[serde(default)]
+    insecure: bool,
+    alpn: Option<serde_json::Value>,
+    utls: Option<SingBoxUtls>,
+    reality: Option<SingBoxReality>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SingBoxUtls {
+    #[serde(default)]
+    enabled: bool,
+    fingerprint: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SingBoxReality {
+    #[serde(default)]
+    enabled: bool,
+    public_key: Option<String>,
+    short_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SingBoxTransport {
+    #[serde(rename = "type")]
+    transport_type: String,
+    host: Option<serde_json::Value>,
+    path: Option<String>,
+    headers: Option<HashMap<String, serde_json::Value>>,
+    service_name: Option<String>,
+    max_early_data: Option<i32>,
+}
+
+/// sing-box 中不少字段既可以是字符串也可以是字符串数组。
+/// many sing-box fields may be either a string or a list of strings.
+fn get_strings(value: &serde_json::Value) -> Vec<String> {
+    match value {
+        serde_json::Value::String(i) => vec![i.clone()],
+        serde_json::Value::Array(i) => i
+            .iter()
+            .filter_map(|j| j.as_str().map(|k| k.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// 解析 Xray/V2Ray 或 sing-box 的 JSON 配置，逐条转换其中的代理出站。
+/// 名称取自出站的 tag，缺省时使用 `协议-地址:端口`。
+/// parse a Xray/V2Ray or sing-box JSON config and convert every proxy outbound in it.
+/// The name is taken from the outbound tag, falling back to `protocol-address:port`.
+pub fn parse_json_outbounds(content: &str) -> Result<Vec<ImportEntry>, ParseLinkError> {
+    let value = serde_json::from_str::<serde_json::Value>(content).map_err(|e| ParseLinkError {
+        msg: e.to_string(),
+        code: ParseLinkErrorCode::JsonEror,
+    })?;
+    let outbounds = match &value {
+        serde_json::Value::Array(i) => i.clone(),
+        _ => match value.get("outbounds").and_then(|i| i.as_array()) {
+            Some(i) => i.clone(),
+            None => return Err(url_error("no outbounds found")),
+        },
+    };
+    Ok(outbounds
+        .into_iter()
+        .map(|value| {
+            let tag = value
+                .get("tag")
+                .and_then(|i| i.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let result = if value.get("protocol").is_some() {
+                get_xray_outbound(value)
+            } else if value.get("type").is_some() {
+                get_sing_box_outbound(value)
+            } else {
+                Err(url_error("unknown outbound format"))
+            };
+            match result {
+                Ok(outbound) => {
+                    let name = if tag.is_empty() {
+                        let (address, port) = outbound.server().unwrap_or_default();
+                        format!("{}-{}:{}", outbound.protocol, address, port)
+                    } else {
+                        tag
+                    };
+                    Ok((outbound, name))
+                }
+                Err(e) => Err((tag, e.msg)),
+            }
+        })
+        .collect())
+}
+
+fn get_xray_outbound(value: serde_json::Value) -> Result<OutboundObject, ParseLinkError> {
+    let protocol = value
+        .get("protocol")
+        .and_then(|i| i.as_str())
+        .unwrap_or_default();
+    if !PROXY_PROTOCOLS.contains(&protocol) {
+        return Err(url_error(&format!("not a proxy outbound: {}", protocol)));
+    }
+    let mut outbound =
+        serde_json::from_value::<OutboundObject>(value).map_err(|e| url_error(&e.to_string()))?;
+    // 导入的代理单独使用，不保留原配置中的 tag 与链式代理。
+    // imported proxies stand alone, so drop the original tag and proxy chaining.
+    outbound.tag = "PROXY".to_string();
+    outbound.proxy_settings = None;
+    Ok(outbound)
+}
+
+fn get_sing_box_outbound(value: serde_json::Value) -> Result<OutboundObject, ParseLinkError> {
+    let outbound =
+        serde_json::from_value::<SingBoxOutbound>(value).map_err(|e| url_error(&e.to_string()))?;
+    let missing = |field: &str| url_error(&format!("missing {}", field));
+    let protocol = outbound.outbound_type.as_str();
+    if !PROXY_PROTOCOLS.contains(&protocol) {
+        return Err(url_error(&format!("not a proxy outbound: {}", protocol)));
+    }
+    let address = outbound.server.clone().ok_or_else(|| missing("server"))?;
+    let port = outbound.server_port.ok_or_else(|| missing("server_port"))?;
+    let settings = match protocol {
+        "vmess" => OutboundConfigurationObject::Vmess {
+            vnext: vec![VmessServerObject {
+                address,
+                port,
+                users: vec![UserObject {
+                    id: outbound.uuid.clone().ok_or_else(|| missing("uuid"))?,
+                    alter_id: Some(outbound.alter_id.unwrap_or(0)),
+                    level: None,
+                    security: outbound
+                        .security
+                        .clone()
+                        .unwrap_or_else(|| "auto".to_string()),
+                }],
+            }],
+        },
+        "vless" => OutboundConfigurationObject::Vless {
+            vnext: vec![VlessServerObject {
+                address,
+                port,
+                users: vec![VlessUserObject {
+                    id: outbound.uuid.clone().ok_or_else(|| missing("uuid"))?,
+                    encryption: "none".to_string(),
+                    flow: outbound.flow.clone().filter(|i| !i.is_empty()),
+                    level: None,
+                }],
+            }],
+        },
+        "trojan" => OutboundConfigurationObject::Trojan {
+            servers: vec![TrojanServerObject {
+                address,
+                port,
+                password: outbound
+                    .password
+                    .clone()
+                    .ok_or_else(|| missing("password"))?,
+                email: None,
+                level: None,
+            }],
+        },
+        _ => OutboundConfigurationObject::Shadowsocks {
+            servers: vec![ShadowsocksServerObject {
+                address,
+                port,
+                method: outbound.method.clone().ok_or_else(|| missing("method"))?,
+                password: outbound
+                    .password
+                    .clone()
+                    .ok_or_else(|| missing("password"))?,
+                uot: match &outbound.udp_over_tcp {
+                    Some(serde_json::Value::Bool(i)) => Some(*i),
+                    Some(i) => i.get("enabled").and_then(|j| j.as_bool()),
+                    None => None,
+                },
+                email: None,
+                level: None,
+            }],
+        },
+    };
+    let query = match (protocol, &outbound.plugin) {
+        ("shadowsocks", Some(plugin)) => get_query_from_plugin(&format!(
+            "{};{}",
+            plugin,
+            outbound.plugin_opts.clone().unwrap_or_default()
+        ))?,
+        _ => get_sing_box_query(&outbound)?,
+    };
+    get_outbound_from_query(protocol, settings, &query)
+}
+
+/// 将 sing-box 的 tls 与 transport 配置转换为分享链接的查询参数。
+/// translate the sing-box tls and transport options into share link query parameters.
+fn get_sing_box_query(
+    outbound: &SingBoxOutbound,
+) -> Result<HashMap<String, String>, ParseLinkError> {
+    let mut query = HashMap::new();
+    if let Some(transport) = &outbound.transport {
+        match transport.transport_type.as_str() {
+            "ws" => {
+                query.insert("type".to_string(), "ws".to_string());
+                let mut path = transport.path.clone().unwrap_or_else(|| "/".to_string());
+                if let Some(max_early_data) = transport.max_early_data.filter(|i| *i > 0) {
+                    if !path.contains("ed=") {
+                        path = format!("{}?ed={}", path, max_early_data);
+                    }
+                }
+                query.insert("path".to_string(), path);
+                if let Some(host) = transport
+                    .headers
+                    .as_ref()
+                    .and_then(|i| i.get("Host"))
+                    .and_then(|i| get_strings(i).into_iter().next())
+                {
+                    query.insert("host".to_string(), host);
+                }
+            }
+            "grpc" => {
+                query.insert("type".to_string(), "grpc".to_string());
+                if let Some(name) = &transport.service_name {
+                    query.insert("serviceName".to_string(), name.clone());
+                }
+            }
+            "http" => {
+                query.insert("type".to_string(), "http".to_string());
+                if let Some(host) = &transport.host {
+                    query.insert("host".to_string(), get_strings(host).join(","));
+                }
+                if let Some(path) = &transport.path {
+                    query.insert("path".to_string(), path.clone());
+                }
+            }
+            "quic" => {
+                query.insert("type".to_string(), "quic".to_string());
+            }
+            i => return Err(url_error(&format!("unsupported transport: {}", i))),
+        }
+    }
+    if let Some(tls) = outbound.tls.as_ref().filter(|i| i.enabled) {
+        if let Some(sni) = &tls.server_name {
+            query.insert("sni".to_string(), sni.clone());
+        }
+        if let Some(fp) = tls
+            .utls
+            .as_ref()
+            .filter(|i| i.enabled)
+            .and_then(|i| i.fingerprint.clone())
+        {
+            query.insert("fp".to_string(), fp);
+        }
+        match tls.reality.as_ref().filter(|i| i.enabled) {
+            Some(reality) => {
+                query.insert("security".to_string(), "reality".to_string());
+                if let Some(pbk) = &reality.public_key {
+                    query.insert("pbk".to_string(), pbk.clone());
+                }
+                if let Some(sid) = &reality.short_id {
+                    query.insert("sid".to_string(), sid.clone());
+                }
+            }
+            None => {
+                query.insert("security".to_string(), "tls".to_string());
+                if let Some(alpn) = &tls.alpn {
+                    query.insert("alpn".to_string(), get_strings(alpn).join(","));
+                }
+                if tls.insecure {
+                    query.insert("allowInsecure".to_string(), "1".to_string());
+                }
+            }
+        }
+    }
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Split = (Vec<(OutboundObject, String)>, Vec<(String, String)>);
+
+    fn split(entries: Vec<ImportEntry>) -> Split {
+        let (ok, skipped): (Vec<_>, Vec<_>) = entries.into_iter().partition(|i| i.is_ok());
+        (
+            ok.into_iter().map(|i| i.unwrap()).collect(),
+            skipped.into_iter().map(|i| i.err().unwrap()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_xray_outbounds() {
+        let content = r#"{
+    "log": { "loglevel": "warning" },
+    "outbounds": [
+        {
+            "tag": "hk-vmess",
+            "protocol": "vmess",
+            "settings": {
+                "vnext": [{
+                    "address": "vmess.example.com",
+                    "port": 443,
+                    "users": [{ "id": "c7c1c985-9421-4d0f-fa19-0efda80343af", "alterId": 0 }]
+                }]
+            },
+            "streamSettings": {
+                "network": "ws",
+                "security": "tls",
+                "tlsSettings": { "serverName": "vmess.example.com" },
+                "wsSettings": { "path": "/ws" }
+            },
+            "proxySettings": { "tag": "chain" }
+        },
+        {
+            "protocol": "vless",
+            "settings": {
+                "vnext": [{
+                    "address": "1.2.3.4",
+                    "port": 8443,
+                    "users": [{ "id": "c7c1c985-9421-4d0f-fa19-0efda80343af", "encryption": "none", "flow": "xtls-rprx-vision" }]
+                }]
+            },
+            "streamSettings": {
+                "security": "reality",
+                "realitySettings": { "serverName": "www.apple.com", "publicKey": "key", "shortId": "ab" }
+            }
+        },
+        {
+            "tag": "ss",
+            "protocol": "shadowsocks",
+            "settings": {
+                "servers": [{ "address": "ss.example.com", "port": 8388, "method": "aes-128-gcm", "password": "test" }]
+            }
+        },
+        { "tag": "direct", "protocol": "freedom", "settings": {} },
+        { "tag": "block", "protocol": "blackhole" }
+    ]
+}"#;
+        let (ok, skipped) = split(parse_json_outbounds(content).unwrap());
+        let names: Vec<&str> = ok.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, ["hk-vmess", "vless-1.2.3.4:8443", "ss"]);
+        let skipped_names: Vec<&str> = skipped.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(skipped_names, ["direct", "block"]);
+
+        let vmess = &ok[0].0;
+        assert_eq!(vmess.tag, "PROXY");
+        assert!(vmess.proxy_settings.is_none());
+        match &vmess.settings {
+            OutboundConfigurationObject::Vmess { vnext } => {
+                assert_eq!(vnext[0].users[0].security, "auto")
+            }
+            _ => panic!("not a vmess outbound"),
+        }
+        assert_eq!(
+            vmess
+                .stream_settings
+                .tls_settings
+                .as_ref()
+                .unwrap()
+                .server_name
+                .as_deref(),
+            Some("vmess.example.com")
+        );
+        let vless = &ok[1].0;
+        assert!(matches!(
+            vless.settings,
+            OutboundConfigurationObject::Vless { .. }
+        ));
+        assert_eq!(vless.stream_settings.network, "tcp");
+        assert_eq!(
+            vless.credential().as_deref(),
+            Some("c7c1c985-9421-4d0f-fa19-0efda80343af")
+        );
+        assert!(matches!(
+            ok[2].0.settings,
+            OutboundConfigurationObject::Shadowsocks { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_sing_box_outbounds() {
+        let content = r#"{
+    "outbounds": [
+        {
+            "type": "vless",
+            "tag": "vless-ws",
+            "server": "vless.example.com",
+            "server_port": 443,
+            "uuid": "c7c1c985-9421-4d0f-fa19-0efda80343af",
+            "tls": {
+                "enabled": true,
+                "server_name": "cdn.example.com",
+                "alpn": "h2",
+                "utls": { "enabled": true, "fingerprint": "chrome" }
+            },
+            "transport": { "type": "ws", "path": "/ws", "headers": { "Host": "cdn.example.com" } }
+        },
+        {
+            "type": "vless",
+            "tag": "vless-reality",
+            "server": "1.2.3.4",
+            "server_port": 443,
+            "uuid": "c7c1c985-9421-4d0f-fa19-0efda80343af",
+            "flow": "xtls-rprx-vision",
+            "tls": {
+                "enabled": true,
+                "server_name": "www.apple.com",
+                "reality": { "enabled": true, "public_key": "key", "short_id": "ab" }
+            }
+        },
+        {
+            "type": "trojan",
+            "tag": "trojan-grpc",
+            "server": "trojan.example.com",
+            "server_port": 443,
+            "password": "secret",
+            "tls": { "enabled": true, "insecure": true },
+            "transport": { "type": "grpc", "service_name": "svc" }
+        },
+        {
+            "type": "shadowsocks",
+            "tag": "ss-obfs",
+            "server": "ss.example.com",
+            "server_port": 8388,
+            "method": "aes-128-gcm",
+            "password": "test",
+            "plugin": "obfs-local",
+            "plugin_opts": "obfs=http;obfs-host=bing.com",
+            "udp_over_tcp": { "enabled": true, "version": 2 }
+        },
+        { "type": "vmess", "tag": "vmess-upgrade", "server": "a.com", "server_port": 80, "uuid": "id", "transport": { "type": "httpupgrade" } },
+        { "type": "hysteria2", "tag": "hy2", "server": "hy2.example.com", "server_port": 443 },
+        { "type": "direct", "tag": "direct" },
+        { "type": "selector", "tag": "select", "outbounds": ["vless-ws"] }
+    ]
+}"#;
+        let (ok, skipped) = split(parse_json_outbounds(content).unwrap());
+        let names: Vec<&str> = ok.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["vless-ws", "vless-reality", "trojan-grpc", "ss-obfs"]
+        );
+        let skipped_names: Vec<&str> = skipped.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(skipped_names, ["vmess-upgrade", "hy2", "direct", "select"]);
+        assert!(skipped[0].1.contains("httpupgrade"));
+
+        let ws = &ok[0].0.stream_settings;
+        assert_eq!(ws.network, "ws");
+        let tls = ws.tls_settings.as_ref().unwrap();
+        assert_eq!(tls.fingerprint.as_deref(), Some("chrome"));
+        assert_eq!(tls.alpn.as_ref().unwrap(), &["h2"]);
+        assert_eq!(
+            ws.ws_settings.as_ref().unwrap().headers.as_ref().unwrap()["Host"],
+            "cdn.example.com"
+        );
+        let reality = ok[1].0.stream_settings.reality_settings.as_ref().unwrap();
+        assert_eq!(reality.public_key, "key");
+        let grpc = &ok[2].0.stream_settings;
+        assert_eq!(grpc.grpc_settings.as_ref().unwrap().service_name, "svc");
+        assert_eq!(
+            grpc.tls_settings.as_ref().unwrap().allow_insecure,
+            Some(true)
+        );
+        match &ok[3].0.settings {
+            OutboundConfigurationObject::Shadowsocks { servers } => {
+                assert_eq!(servers[0].uot, Some(true))
+            }
+            _ => panic!("not a shadowsocks outbound"),
+        }
+    }
+
+    #[test]
+    fn test_parse_json_outbounds_invalid() {
+        assert!(parse_json_outbounds("{").is_err());
+        assert!(parse_json_outbounds(r#"{"log": {}}"#).is_err());
+    }
+}
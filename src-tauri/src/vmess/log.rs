@@ -0,0 +1,233 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// 核心每输出一行日志发送一次的事件。
+/// the event sent for every log line printed by the core.
+pub const CORE_LOG_EVENT: &str = "core-log";
+
+/// 默认保留的日志行数。
+/// the number of log lines kept by default.
+const LOG_CAPACITY: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+impl LogLine {
+    /// 从 Xray 的日志格式 `2023/01/01 12:00:00 [Warning] message` 中读取级别。
+    /// 没有级别的行在 stdout 上视为 info，在 stderr 上视为 error。
+    /// read the level from the Xray log format `2023/01/01 12:00:00 [Warning] message`.
+    /// Lines without a level count as info on stdout and as error on stderr.
+    pub fn parse(stream: LogStream, message: String) -> LogLine {
+        let level = match message.split_whitespace().nth(2) {
+            Some("[Debug]") => Some(LogLevel::Debug),
+            Some("[Info]") => Some(LogLevel::Info),
+            Some("[Warning]") => Some(LogLevel::Warning),
+            Some("[Error]") => Some(LogLevel::Error),
+            _ => None,
+        };
+        let level = level.unwrap_or(match stream {
+            LogStream::Stdout => LogLevel::Info,
+            LogStream::Stderr => LogLevel::Error,
+        });
+        LogLine {
+            stream,
+            level,
+            message,
+        }
+    }
+}
+
+pub type LogListener = Arc<dyn Fn(&LogLine) + Send + Sync>;
+
+/// 有界的日志缓冲区，核心重启后仍然保留之前的日志。
+/// a bounded buffer of log lines, which keeps the earlier lines across core restarts.
+#[derive(Clone)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<LogLine>>>,
+    capacity: usize,
+    listener: Option<LogListener>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        LogBuffer::new(LOG_CAPACITY)
+    }
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> LogBuffer {
+        LogBuffer {
+            lines: Arc::new(Mutex::new(VecDeque::new())),
+            capacity,
+            listener: None,
+        }
+    }
+
+    /// 设置收到新行时的回调。
+    /// set the callback invoked for every new line.
+    pub fn set_listener(&mut self, listener: LogListener) {
+        self.listener = Some(listener);
+    }
+
+    pub fn push(&self, line: LogLine) {
+        if let Some(i) = &self.listener {
+            i(&line);
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// 最后 `count` 行不低于 `level` 的日志，按时间顺序排列。
+    /// the last `count` lines at or above `level`, oldest first.
+    pub fn tail(&self, count: usize, level: Option<LogLevel>) -> Vec<LogLine> {
+        let lines = self.lines.lock().unwrap();
+        let mut result = lines
+            .iter()
+            .rev()
+            .filter(|i| level.map(|j| i.level >= j).unwrap_or(true))
+            .take(count)
+            .cloned()
+            .collect::<Vec<LogLine>>();
+        result.reverse();
+        result
+    }
+
+    /// 在后台线程中逐行读取输出，写入缓冲区并把每一行交给 `on_line`，读到结尾时结束。
+    /// 无效的 UTF-8 按替换字符解码，读取会继续进行，否则管道不再被读取，核心写日志时会被阻塞。
+    /// read the output line by line in a background thread, pushing every line into the buffer and handing it
+    /// to `on_line`, finishing at the end of the stream. Invalid UTF-8 is decoded with replacement characters
+    /// and reading goes on, otherwise the pipe would no longer be drained and the core would block writing logs.
+    pub fn read<R, F>(&self, stream: LogStream, output: R, on_line: F) -> JoinHandle<()>
+    where
+        R: Read + Send + 'static,
+        F: Fn(&str) + Send + 'static,
+    {
+        let logs = self.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(output);
+            let mut buffer = Vec::new();
+            loop {
+                buffer.clear();
+                match reader.read_until(b'\n', &mut buffer) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+                on_line(&line);
+                logs.push(LogLine::parse(stream, line));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let line = LogLine::parse(
+            LogStream::Stdout,
+            "2023/01/01 12:00:00 [Warning] core: Xray 1.7.2 started".to_string(),
+        );
+        assert_eq!(line.level, LogLevel::Warning);
+        assert_eq!(
+            LogLine::parse(
+                LogStream::Stdout,
+                "Xray 1.7.2 (Xray, Penetrates Everything.)".to_string()
+            )
+            .level,
+            LogLevel::Info
+        );
+        assert_eq!(
+            LogLine::parse(LogStream::Stderr, "panic: bad config".to_string()).level,
+            LogLevel::Error
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        let logs = LogBuffer::new(3);
+        let received = Arc::new(Mutex::new(0));
+        let mut listened = logs.clone();
+        {
+            let received = received.clone();
+            listened.set_listener(Arc::new(move |_| *received.lock().unwrap() += 1));
+        }
+        let output = "2023/01/01 12:00:00 [Info] first\n\
+            2023/01/01 12:00:01 [Error] second\n\
+            2023/01/01 12:00:02 [Debug] third\n\
+            2023/01/01 12:00:03 [Warning] fourth\n";
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        {
+            let lines = lines.clone();
+            listened
+                .read(LogStream::Stdout, output.as_bytes(), move |i| {
+                    lines.lock().unwrap().push(i.to_string())
+                })
+                .join()
+                .unwrap();
+        }
+        assert_eq!(lines.lock().unwrap().len(), 4);
+        assert_eq!(*received.lock().unwrap(), 4);
+
+        // 缓冲区只保留最后三行。
+        // the buffer only keeps the last three lines.
+        let tail = logs.tail(10, None);
+        assert_eq!(tail.len(), 3);
+        assert!(tail[0].message.ends_with("second"));
+        assert!(tail[2].message.ends_with("fourth"));
+
+        let warnings = logs.tail(10, Some(LogLevel::Warning));
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].message.ends_with("second"));
+        assert_eq!(logs.tail(1, None)[0].level, LogLevel::Warning);
+    }
+
+    #[test]
+    fn test_read_invalid_utf8() {
+        let logs = LogBuffer::new(10);
+        // 第二行不是有效的 UTF-8，之后的行仍然被读取。
+        // the second line is not valid UTF-8 and the lines after it are still read.
+        let output: &[u8] = b"first\r\nbad \xff\xfe line\nlast";
+        logs.read(LogStream::Stderr, output, |_| {}).join().unwrap();
+        let messages = logs
+            .tail(10, None)
+            .into_iter()
+            .map(|i| i.message)
+            .collect::<Vec<String>>();
+        assert_eq!(messages, ["first", "bad \u{fffd}\u{fffd} line", "last"]);
+    }
+}
@@ -1,4 +1,10 @@
 pub mod core;
-mod generate;
-mod error;
-mod traffic;
\ No newline at end of file
+pub mod discovery;
+pub mod generate;
+pub mod error;
+pub mod log;
+pub mod shutdown;
+pub mod supervisor;
+#[cfg(test)]
+pub mod testing;
+pub mod traffic;
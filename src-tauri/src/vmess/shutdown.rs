@@ -0,0 +1,217 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Child,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::files;
+
+/// 停止核心时等待其自行退出的时间，超时后强制结束。
+/// how long a stopping core is given to exit on its own before it is killed.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 退出应用时等待正在进行的核心操作（例如切换代理）结束的时间，超时后按 PID 文件结束核心。
+/// how long quitting the app waits for a running core operation such as a switch to finish,
+/// after which the core is stopped through the pid file.
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 配置目录中记录核心进程的文件名。
+/// the name of the file in the config directory recording the core process.
+const PID_FILE: &str = "core.pid";
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 先请求子进程退出，超时后强制结束，最后回收进程。
+/// ask the child to exit first, kill it after the timeout, and reap it in the end.
+pub fn terminate(child: &mut Child, timeout: Duration) {
+    #[cfg(unix)]
+    {
+        if let Ok(None) = child.try_wait() {
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+            }
+            let start = Instant::now();
+            while start.elapsed() < timeout {
+                if !matches!(child.try_wait(), Ok(None)) {
+                    break;
+                }
+                thread::sleep(WAIT_INTERVAL);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = timeout;
+    // 进程可能已经退出，只需回收即可。
+    // the process may have exited already, in which case it only needs reaping.
+    child.kill().ok();
+    child.wait().ok();
+}
+
+/// 进程仍然存在时返回它的命令行，僵尸进程视为已经退出。
+/// the command line of the process while it exists, zombies count as exited.
+#[cfg(unix)]
+fn get_command(pid: u32) -> Option<String> {
+    let output = std::process::Command::new("ps")
+        .args(["-o", "stat=", "-o", "command=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (stat, command) = output.trim().split_once(char::is_whitespace)?;
+    if stat.starts_with('Z') {
+        return None;
+    }
+    Some(command.trim().to_string())
+}
+
+/// 结束一个不是本进程子进程的核心，先发送 SIGTERM，超时后发送 SIGKILL。
+/// stop a core that is not a child of this process, sending SIGTERM first and SIGKILL after the timeout.
+#[cfg(unix)]
+fn terminate_pid(pid: u32, timeout: Duration) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+    let start = Instant::now();
+    while get_command(pid).is_some() {
+        if start.elapsed() > timeout {
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            }
+            return;
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// 记录本应用启动的核心的进程号与配置文件，应用异常退出后再次启动时据此清理残留的核心。
+/// records the pid and config file of the core started by this app, so that a core left behind after the app
+/// exited abnormally can be cleaned up on the next start.
+#[derive(Debug, Clone)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn new(path: &Path) -> PidFile {
+        PidFile {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// 配置目录中的 PID 文件。
+    /// the pid file in the config directory.
+    pub fn open() -> io::Result<PidFile> {
+        files::get_path(PID_FILE).map(|i| PidFile::new(&i))
+    }
+
+    pub fn write(&self, pid: u32, config_path: &Path) -> io::Result<()> {
+        files::write_atomic(&self.path, &format!("{}\n{}\n", pid, config_path.display()))
+    }
+
+    pub fn remove(&self) {
+        fs::remove_file(&self.path).ok();
+    }
+
+    fn read(&self) -> Option<(u32, String)> {
+        let data = fs::read_to_string(&self.path).ok()?;
+        let mut lines = data.lines();
+        let pid = lines.next()?.trim().parse().ok()?;
+        let config_path = lines.next()?.trim().to_string();
+        Some((pid, config_path))
+    }
+
+    /// 结束记录中仍在运行的核心并删除记录与它的配置文件，返回被结束的进程号。只有命令行中带有记录的配置文件的
+    /// 进程才会被结束，以免误杀复用了该进程号的其他进程。
+    /// stop the recorded core if it is still running and remove the record together with its config file,
+    /// returning the pid that was stopped. Only a process whose command line contains the recorded config file
+    /// is stopped, so that another process reusing the pid is left alone.
+    pub fn cleanup_stale(&self) -> Option<u32> {
+        let (pid, config_path) = self.read()?;
+        self.remove();
+        #[cfg(unix)]
+        let stopped = match get_command(pid) {
+            Some(i) if i.contains(&config_path) => {
+                terminate_pid(pid, STOP_TIMEOUT);
+                Some(pid)
+            }
+            _ => None,
+        };
+        #[cfg(not(unix))]
+        let stopped = None;
+        // 配置文件中带有代理的凭据，不能留在磁盘上。
+        // the config file holds the credentials of the proxy and must not stay on disk.
+        fs::remove_file(&config_path).ok();
+        stopped
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    #[test]
+    fn test_terminate() {
+        // 正常响应 SIGTERM 的进程会很快退出。
+        // a process honouring SIGTERM exits quickly.
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let start = Instant::now();
+        terminate(&mut child, Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(get_command(child.id()).is_none());
+
+        // 忽略 SIGTERM 的进程在超时后被强制结束。
+        // a process ignoring SIGTERM is killed after the timeout.
+        let mut child = Command::new("sh")
+            .args(["-c", "trap '' TERM; exec sleep 30"])
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        terminate(&mut child, Duration::from_millis(300));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(get_command(child.id()).is_none());
+    }
+
+    #[test]
+    fn test_cleanup_stale() {
+        let dir = crate::vmess::testing::get_temp_dir();
+        let config_path = dir.join("connection.json");
+        let pid_file = PidFile::new(&dir.join("core.pid"));
+        assert!(pid_file.cleanup_stale().is_none());
+
+        // 在后台启动一个不属于本进程的“核心”，模拟上次运行残留的核心。
+        // start a "core" in the background that is not our child, like one left behind by the last run.
+        let output = Command::new("sh")
+            .args([
+                "-c",
+                &format!(
+                    "sh -c 'sleep 30; true' -config {} >/dev/null 2>&1 & echo $!",
+                    config_path.display()
+                ),
+            ])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap();
+        let pid: u32 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap();
+        assert!(get_command(pid).is_some());
+
+        // 命令行不匹配时不会结束进程。
+        // the process is left alone when its command line does not match.
+        pid_file.write(pid, &dir.join("other.json")).unwrap();
+        assert!(pid_file.cleanup_stale().is_none());
+        assert!(get_command(pid).is_some());
+
+        pid_file.write(pid, &config_path).unwrap();
+        fs::write(&config_path, "{}").unwrap();
+        assert_eq!(pid_file.cleanup_stale(), Some(pid));
+        assert!(get_command(pid).is_none());
+        assert!(!dir.join("core.pid").exists());
+        assert!(!config_path.exists());
+    }
+}
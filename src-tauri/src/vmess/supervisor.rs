@@ -0,0 +1,335 @@
+use std::{
+    collections::VecDeque,
+    process::Child,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::log::{LogBuffer, LogStream};
+
+/// 核心状态变化时发送的事件。
+/// the event sent whenever the state of the core changes.
+pub const CORE_STATE_EVENT: &str = "core-state";
+
+/// 保留的 stderr 行数，作为崩溃原因。
+/// the number of stderr lines kept as the crash reason.
+const STDERR_LINES: usize = 20;
+
+/// 检查子进程是否退出的间隔。
+/// the interval of checking whether the child has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoreState {
+    Stopped,
+    Starting,
+    Running,
+    Crashed,
+}
+
+/// 核心的状态，以及崩溃时 stderr 的最后几行与当前时间窗口内的重启次数。
+/// the state of the core, with the last stderr lines on a crash and the number of restarts in the current window.
+#[derive(Debug, Serialize, Clone)]
+pub struct CoreStateChanged {
+    pub state: CoreState,
+    pub reason: Option<String>,
+    pub restarts: usize,
+}
+
+impl CoreStateChanged {
+    pub fn new(state: CoreState) -> CoreStateChanged {
+        CoreStateChanged {
+            state,
+            reason: None,
+            restarts: 0,
+        }
+    }
+}
+
+pub type StateListener = Arc<dyn Fn(CoreStateChanged) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct SupervisorOptions {
+    /// 第一次重启前的等待时间，之后每次翻倍
+    /// the delay before the first restart, doubled on every following one
+    pub initial_backoff: Duration,
+    /// 重启等待时间的上限
+    /// the upper bound of the restart delay
+    pub max_backoff: Duration,
+    /// 时间窗口内允许的最多重启次数，超过后视为崩溃循环并停止重启
+    /// the most restarts allowed within the window, beyond which the core is considered crash looping and left stopped
+    pub max_restarts: usize,
+    /// 统计重启次数的时间窗口
+    /// the window the restarts are counted in
+    pub window: Duration,
+}
+
+impl Default for SupervisorOptions {
+    fn default() -> Self {
+        SupervisorOptions {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SupervisorOptions {
+    /// 第 `restarts` 次重启前的等待时间。
+    /// the delay before restart number `restarts`.
+    fn backoff(&self, restarts: usize) -> Duration {
+        let factor = 2u32.saturating_pow(restarts.saturating_sub(1) as u32);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// 核心进程与监视线程共享的状态。
+/// the state shared between the core and its watching thread.
+#[derive(Default)]
+pub struct Process {
+    pub child: Option<Child>,
+    /// 核心被主动停止，监视线程应当退出
+    /// the core was stopped on purpose and the watching thread should exit
+    pub stopped: bool,
+    /// 核心的 stdout 与 stderr 都写入这里
+    /// both stdout and stderr of the core are written here
+    pub logs: LogBuffer,
+    stderr: VecDeque<String>,
+    stderr_reader: Option<JoinHandle<()>>,
+}
+
+impl Process {
+    pub fn new(logs: LogBuffer) -> Process {
+        Process {
+            logs,
+            ..Process::default()
+        }
+    }
+
+    /// 开始管理一个新启动的子进程，在后台把它的输出写入日志，stderr 的最后几行另外收集到 `shared` 中。
+    /// `shared` 必须是持有 `self` 的锁。
+    /// take over a freshly spawned child, writing its output to the logs in the background and additionally
+    /// collecting the last stderr lines into `shared`. `shared` must be the mutex holding `self`.
+    pub fn attach(&mut self, shared: &Arc<Mutex<Process>>, mut child: Child) {
+        self.stderr.clear();
+        if let Some(i) = child.stdout.take() {
+            self.logs.read(LogStream::Stdout, i, |_| {});
+        }
+        self.stderr_reader = child.stderr.take().map(|i| {
+            let shared = shared.clone();
+            self.logs.read(LogStream::Stderr, i, move |line| {
+                let mut process = shared.lock().unwrap();
+                if process.stderr.len() == STDERR_LINES {
+                    process.stderr.pop_front();
+                }
+                process.stderr.push_back(line.to_string());
+            })
+        });
+        self.child = Some(child);
+    }
+
+    /// 取出收集到的 stderr 作为退出原因。
+    /// take the collected stderr as the exit reason.
+    fn take_reason(&mut self) -> Option<String> {
+        if self.stderr.is_empty() {
+            return None;
+        }
+        Some(self.stderr.drain(..).collect::<Vec<String>>().join("\n"))
+    }
+}
+
+/// 在后台线程中监视核心进程：意外退出时以指数退避重启，同一时间窗口内重启过多时停止并报告崩溃。
+/// watch the core process in a background thread: restart it with exponential backoff when it exits unexpectedly,
+/// giving up and reporting the crash when it restarts too often within the window.
+pub fn watch<F>(
+    process: Arc<Mutex<Process>>,
+    options: SupervisorOptions,
+    listener: StateListener,
+    spawn: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> std::io::Result<Child> + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let mut guard = process.lock().unwrap();
+            if guard.stopped {
+                return;
+            }
+            let status = match guard.child.as_mut().map(|i| i.try_wait()) {
+                Some(Ok(Some(i))) => i,
+                Some(Ok(None)) => continue,
+                Some(Err(_)) | None => return,
+            };
+            guard.child = None;
+            // 等待 stderr 读完，确保最后几行都被收集。
+            // wait for stderr to be drained so that the last lines are collected.
+            let reader = guard.stderr_reader.take();
+            drop(guard);
+            if let Some(i) = reader {
+                i.join().ok();
+            }
+            let mut reason = process
+                .lock()
+                .unwrap()
+                .take_reason()
+                .unwrap_or_else(|| format!("core exited with {}", status));
+
+            loop {
+                let now = Instant::now();
+                while matches!(restarts.front(), Some(i) if now.duration_since(*i) > options.window)
+                {
+                    restarts.pop_front();
+                }
+                if restarts.len() >= options.max_restarts {
+                    listener(CoreStateChanged {
+                        state: CoreState::Crashed,
+                        reason: Some(format!("crash loop, giving up: {}", reason)),
+                        restarts: restarts.len(),
+                    });
+                    return;
+                }
+                restarts.push_back(now);
+                listener(CoreStateChanged {
+                    state: CoreState::Crashed,
+                    reason: Some(reason.clone()),
+                    restarts: restarts.len(),
+                });
+                thread::sleep(options.backoff(restarts.len()));
+
+                let mut guard = process.lock().unwrap();
+                if guard.stopped {
+                    return;
+                }
+                listener(CoreStateChanged {
+                    state: CoreState::Starting,
+                    reason: None,
+                    restarts: restarts.len(),
+                });
+                match spawn() {
+                    Ok(i) => {
+                        guard.attach(&process, i);
+                        drop(guard);
+                        listener(CoreStateChanged {
+                            state: CoreState::Running,
+                            reason: None,
+                            restarts: restarts.len(),
+                        });
+                        break;
+                    }
+                    Err(e) => {
+                        drop(guard);
+                        reason = format!("failed to start core: {}", e);
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let options = SupervisorOptions::default();
+        assert_eq!(options.backoff(1), Duration::from_secs(1));
+        assert_eq!(options.backoff(2), Duration::from_secs(2));
+        assert_eq!(options.backoff(4), Duration::from_secs(8));
+        assert_eq!(options.backoff(6), Duration::from_secs(30));
+        assert_eq!(options.backoff(100), Duration::from_secs(30));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch_crash_loop() {
+        use std::process::{Command, Stdio};
+
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let listener: StateListener = {
+            let states = states.clone();
+            Arc::new(move |i: CoreStateChanged| states.lock().unwrap().push(i))
+        };
+        let spawn = || {
+            Command::new("sh")
+                .args([
+                    "-c",
+                    "echo first >&2; echo 'failed to load config' >&2; exit 1",
+                ])
+                .stderr(Stdio::piped())
+                .spawn()
+        };
+        let process = Arc::new(Mutex::new(Process::default()));
+        process.lock().unwrap().attach(&process, spawn().unwrap());
+        let options = SupervisorOptions {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+        };
+        watch(process, options, listener, spawn).join().unwrap();
+
+        let states = states.lock().unwrap();
+        let kinds = states.iter().map(|i| i.state).collect::<Vec<CoreState>>();
+        assert_eq!(
+            kinds,
+            vec![
+                CoreState::Crashed,
+                CoreState::Starting,
+                CoreState::Running,
+                CoreState::Crashed,
+                CoreState::Starting,
+                CoreState::Running,
+                CoreState::Crashed,
+            ]
+        );
+        assert_eq!(
+            states[0].reason.as_deref(),
+            Some("first\nfailed to load config")
+        );
+        assert_eq!(states[3].restarts, 2);
+        assert!(states[6]
+            .reason
+            .as_deref()
+            .unwrap()
+            .starts_with("crash loop"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch_stopped() {
+        use std::process::Command;
+
+        let listener: StateListener = Arc::new(|_| panic!("no state expected"));
+        let process = Arc::new(Mutex::new(Process::default()));
+        process
+            .lock()
+            .unwrap()
+            .attach(&process, Command::new("sleep").arg("30").spawn().unwrap());
+        let handle = watch(
+            process.clone(),
+            SupervisorOptions::default(),
+            listener,
+            || Command::new("sleep").arg("30").spawn(),
+        );
+        {
+            let mut guard = process.lock().unwrap();
+            guard.stopped = true;
+            let mut child = guard.child.take().unwrap();
+            child.kill().ok();
+            child.wait().ok();
+        }
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// 新建一个测试专用的临时目录。
+/// create a fresh temporary directory for a test.
+pub fn get_temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("v2neko-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// 在 `dir` 中写出名为 `name` 的可执行 sh 脚本代替真实的核心，返回它的路径，测试不依赖系统中安装的核心。
+/// write an executable sh script named `name` into `dir` to stand in for the real core and return its path,
+/// so that the tests do not depend on an installed core.
+#[cfg(unix)]
+pub fn write_fake_core(dir: &Path, name: &str, script: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
@@ -0,0 +1,290 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::{async_runtime::JoinHandle, AppHandle, Manager};
+use tokio::process::Command;
+
+use super::generate::PROXY_TAG;
+use crate::{depositor, error::AppError, state::AppState};
+
+/// 每秒发送一次的流量事件。
+/// the traffic event sent every second.
+pub const TRAFFIC_UPDATED_EVENT: &str = "traffic-updated";
+
+/// 核心统计中的一项，例如 `outbound>>>PROXY>>>traffic>>>uplink`。
+/// one counter of the core stats, e.g. `outbound>>>PROXY>>>traffic>>>uplink`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub name: String,
+    pub value: i64,
+}
+
+/// 每秒的速率（字节每秒）与代理累计的流量（字节）。
+/// the rate of the last second in bytes per second and the accumulated traffic of the proxy in bytes.
+#[derive(Debug, Serialize, Clone)]
+pub struct TrafficUpdated {
+    pub proxy_id: String,
+    pub upload_rate: i64,
+    pub download_rate: i64,
+    pub upload_total: i64,
+    pub download_total: i64,
+}
+
+#[derive(serde::Deserialize)]
+struct StatsResponse {
+    #[serde(default)]
+    stat: Vec<StatObject>,
+}
+
+#[derive(serde::Deserialize)]
+struct StatObject {
+    name: String,
+    /// protojson 把 int64 输出为字符串，值为 0 时省略该字段。
+    /// protojson prints int64 as a string and omits the field when it is 0.
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+/// 解析 `xray api statsquery` 输出的 JSON。
+/// parse the JSON printed by `xray api statsquery`.
+pub fn parse_stats(output: &str) -> Result<Vec<Stat>, String> {
+    let response = serde_json::from_str::<StatsResponse>(output).map_err(|e| e.to_string())?;
+    Ok(response
+        .stat
+        .into_iter()
+        .map(|i| Stat {
+            name: i.name,
+            value: match &i.value {
+                serde_json::Value::Number(j) => j.as_i64().unwrap_or(0),
+                serde_json::Value::String(j) => j.parse().unwrap_or(0),
+                _ => 0,
+            },
+        })
+        .collect())
+}
+
+/// 通过核心的 API 查询名称匹配 `pattern` 的统计，`reset` 为真时查询后清零。
+/// query the stats whose name matches `pattern` through the core API, resetting them afterwards when `reset` is set.
+pub async fn query_stats(
+    core_path: &str,
+    server: &str,
+    pattern: &str,
+    reset: bool,
+) -> Result<Vec<Stat>, String> {
+    let mut command = Command::new(core_path);
+    command.args([
+        "api",
+        "statsquery",
+        &format!("--server={}", server),
+        "-pattern",
+        pattern,
+    ]);
+    if reset {
+        command.arg("-reset");
+    }
+    let output = command.output().await.map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    parse_stats(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// 汇总某个出站的上传与下载字节数。
+/// sum up the upload and download bytes of an outbound.
+fn get_outbound_traffic(stats: &[Stat], tag: &str) -> (i64, i64) {
+    let uplink = format!("outbound>>>{}>>>traffic>>>uplink", tag);
+    let downlink = format!("outbound>>>{}>>>traffic>>>downlink", tag);
+    stats.iter().fold((0, 0), |(up, down), i| {
+        if i.name == uplink {
+            (up + i.value, down)
+        } else if i.name == downlink {
+            (up, down + i.value)
+        } else {
+            (up, down)
+        }
+    })
+}
+
+fn get_rate(bytes: i64, elapsed: Duration) -> i64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as i64
+}
+
+/// 读取并清零代理出站的统计，把读到的流量累加到 `proxy_id` 上，返回这次读到的上传与下载字节数。
+/// read and reset the stats of the proxy outbound, add the traffic read to `proxy_id` and return the
+/// upload and download bytes of this read.
+async fn collect(
+    state: &AppState,
+    core_path: &str,
+    server: &str,
+    proxy_id: &str,
+) -> Result<(i64, i64), AppError> {
+    let pattern = format!("outbound>>>{}>>>", PROXY_TAG);
+    let stats = query_stats(core_path, server, &pattern, true)
+        .await
+        .map_err(|e| AppError::Io(format!("failed to query stats: {}", e)))?;
+    let (upload, download) = get_outbound_traffic(&stats, PROXY_TAG);
+    depositor::add_proxy_traffic(&*state.database()?, proxy_id, upload, download)?;
+    Ok((upload, download))
+}
+
+/// 某个代理的流量监视。
+/// the traffic monitor of one proxy.
+pub struct Monitor {
+    proxy_id: String,
+    core_path: String,
+    server: String,
+    handle: JoinHandle<()>,
+}
+
+impl Monitor {
+    /// 最后读取一次统计并算到该代理上，然后停止监视。切换代理前调用，这样新的代理不会被算上之前的流量。
+    /// read the stats a last time, adding them to this proxy, then stop monitoring. Called before switching
+    /// proxies so that the traffic so far is not billed to the new proxy.
+    pub async fn finish(self, state: &AppState) {
+        collect(state, &self.core_path, &self.server, &self.proxy_id)
+            .await
+            .ok();
+        self.handle.abort();
+    }
+
+    /// 不再读取统计，立即停止监视。
+    /// stop monitoring immediately without reading the stats.
+    pub fn abort(self) {
+        self.handle.abort();
+    }
+}
+
+/// 每秒读取并清零当前代理出站的统计，累加到数据库并推送速率。核心暂时不可用时跳过该次读取。
+/// 数据库使用应用状态中的连接。
+/// every second read and reset the stats of the current proxy outbound, add them to the database and emit the rate.
+/// Reads are skipped while the core is unavailable. The database is reached through the connection of the app state.
+pub fn spawn_monitor(
+    app: AppHandle,
+    core_path: String,
+    server: String,
+    proxy_id: String,
+) -> Monitor {
+    let handle = {
+        let core_path = core_path.clone();
+        let server = server.clone();
+        let proxy_id = proxy_id.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut last = Instant::now();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let state = app.state::<AppState>();
+                let (upload, download) = match collect(&state, &core_path, &server, &proxy_id).await
+                {
+                    Ok(i) => i,
+                    Err(_) => continue,
+                };
+                let elapsed = last.elapsed();
+                last = Instant::now();
+                let proxy = match state
+                    .database()
+                    .and_then(|i| depositor::get_proxy_by_id(&i, &proxy_id))
+                {
+                    Ok(Some(i)) => i,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+                app.emit_all(
+                    TRAFFIC_UPDATED_EVENT,
+                    TrafficUpdated {
+                        proxy_id: proxy_id.clone(),
+                        upload_rate: get_rate(upload, elapsed),
+                        download_rate: get_rate(download, elapsed),
+                        upload_total: proxy.proxy_upload,
+                        download_total: proxy.proxy_download,
+                    },
+                )
+                .ok();
+            }
+        })
+    };
+    Monitor {
+        proxy_id,
+        core_path,
+        server,
+        handle,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::vmess::testing::{get_temp_dir, write_fake_core};
+
+    const OUTPUT: &str = r#"{
+    "stat": [
+        {
+            "name": "outbound>>>PROXY>>>traffic>>>uplink",
+            "value": "1024"
+        },
+        {
+            "name": "outbound>>>PROXY>>>traffic>>>downlink",
+            "value": 4096
+        },
+        {
+            "name": "outbound>>>direct>>>traffic>>>downlink",
+            "value": "7"
+        },
+        {
+            "name": "inbound>>>SOCK5_IN>>>traffic>>>uplink"
+        }
+    ]
+}"#;
+
+    #[test]
+    fn test_parse_stats() {
+        let stats = parse_stats(OUTPUT).unwrap();
+        assert_eq!(stats.len(), 4);
+        assert_eq!(stats[0].value, 1024);
+        assert_eq!(stats[1].value, 4096);
+        assert_eq!(stats[3].value, 0);
+        assert_eq!(get_outbound_traffic(&stats, "PROXY"), (1024, 4096));
+        assert_eq!(get_outbound_traffic(&stats, "direct"), (0, 7));
+
+        // 没有任何统计时核心只输出空对象。
+        // the core prints an empty object when there are no stats at all.
+        assert!(parse_stats("{}").unwrap().is_empty());
+        assert!(parse_stats("failed to dial").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_query_stats() {
+        let dir = get_temp_dir();
+        let args = dir.join("args");
+        let path = write_fake_core(
+            &dir,
+            "xray",
+            &format!(
+                "echo \"$@\" > \"{}\"\ncat <<'EOF'\n{}\nEOF\n",
+                args.display(),
+                OUTPUT
+            ),
+        );
+
+        let stats = query_stats(
+            &path.to_string_lossy(),
+            "127.0.0.1:10085",
+            "outbound>>>PROXY>>>",
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(get_outbound_traffic(&stats, "PROXY"), (1024, 4096));
+        assert_eq!(
+            std::fs::read_to_string(args).unwrap().trim(),
+            "api statsquery --server=127.0.0.1:10085 -pattern outbound>>>PROXY>>> -reset"
+        );
+
+        assert!(
+            query_stats("/nonexistent/xray", "127.0.0.1:10085", "", false)
+                .await
+                .is_err()
+        );
+    }
+}